@@ -0,0 +1,15 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // the gRPC API is generated with its feature only
+    #[cfg(feature = "grpc")]
+    {
+        // protoc is not required to be installed
+        let mut config = prost_build::Config::new();
+        config.protoc_executable(protoc_bin_vendored::protoc_bin_path()?);
+        tonic_build::configure().compile_protos_with_config(
+            config,
+            &["proto/toybank.proto"],
+            &["proto"],
+        )?;
+    }
+    Ok(())
+}
@@ -1,19 +1,28 @@
 use crate::{
     common::*,
-    libcsv::{validate_accounts_internal, AccountState, ExecError, TxRequest},
+    libcsv::{
+        apply_request, sorted_accounts, validate_accounts_internal, DumpOptions, ExecError,
+        Outcome, TxRequest,
+    },
+    pipeline::{ParallelCsvSource, ParallelOptions},
+    routing::{Router, ShardRouter},
+    snapshot::Snapshot,
+    source::{CsvSource, Position, TxSource},
 };
-use crossbeam::sync::WaitGroup;
-use crossbeam_channel::{bounded, unbounded, Sender, TryRecvError};
+use crossbeam_channel::{bounded, unbounded, Sender, TrySendError};
 use serde::{Deserialize, Serialize};
 use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
     default::Default,
     io::{Error as IoError, ErrorKind::Other as AnotherError},
     path::Path,
     sync::{Arc, Mutex},
     thread,
+    time::{Duration, Instant},
 };
 
-const MSG_QUEUE_LENGTH: usize = 8;
+pub const MSG_QUEUE_LENGTH: usize = 8;
 
 pub fn index_by_client(c: Client, concurrency: usize) -> usize {
     let index = c.0 as u32;
@@ -23,12 +32,12 @@ pub fn index_by_client(c: Client, concurrency: usize) -> usize {
 
 pub fn sharded_validate_accounts(
     rd: impl std::io::Read,
-    ledgers: &Vec<Arc<Mutex<dyn Ledger + Send>>>,
-    index: impl Fn(Client, usize) -> usize,
+    ledgers: &[Arc<Mutex<dyn Ledger + Send>>],
+    index: impl ShardRouter,
 ) -> Result<(), ExecError> {
     let concurrency = ledgers.len();
     validate_accounts_internal(rd, |c| {
-        ledgers[index(c, concurrency)]
+        ledgers[index.route(c, concurrency)]
             .lock()
             .unwrap()
             .get_account(c)
@@ -37,33 +46,134 @@ pub fn sharded_validate_accounts(
 
 pub fn sharded_dump_accounts(
     wr: impl std::io::Write,
-    ledgers: &Vec<Arc<Mutex<dyn Ledger + Send>>>,
-    index: impl Fn(Client, usize) -> usize,
+    ledgers: &[Arc<Mutex<dyn Ledger + Send>>],
+    index: impl ShardRouter,
+) -> Result<(), ExecError> {
+    sharded_dump_accounts_with(wr, ledgers, index, &Default::default())
+}
+
+/// Dumps accounts of all shards,
+///   with sorted output the shards are merged into one stream ordered by client
+pub fn sharded_dump_accounts_with(
+    wr: impl std::io::Write,
+    ledgers: &[Arc<Mutex<dyn Ledger + Send>>],
+    index: impl ShardRouter,
+    opts: &DumpOptions,
 ) -> Result<(), ExecError> {
     let mut wrr = csv::WriterBuilder::new().delimiter(b',').from_writer(wr);
-    let index = |c| index(c, ledgers.len());
+    let index = |c| index.route(c, ledgers.len());
+    // the header is written with the first account, nothing is written without accounts
+    let mut header = Some(opts.header());
+    let mut write = |client, state: &Account| -> Result<(), csv::Error> {
+        if let Some(header) = header.take() {
+            wrr.write_record(header)?;
+        }
+        wrr.write_record(opts.record(client, state))
+    };
+    if opts.sorted {
+        let shards = ledgers
+            .iter()
+            .enumerate()
+            .map(|(i, l)| {
+                sorted_accounts(
+                    l.lock()
+                        .unwrap()
+                        .accounts()
+                        .filter(|pair| !matches!(pair, Ok((client, _)) if index(*client) != i)),
+                )
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for (client, state) in merge_sorted(shards) {
+            write(client, &state)?;
+        }
+    } else {
+        for (i, l) in ledgers.iter().enumerate() {
+            for pair in l.lock().unwrap().accounts() {
+                match pair {
+                    Ok((client, _)) if index(client) != i => Ok(()),
+                    Ok((client, state)) => write(client, &state),
+                    Err(e) => Err(e.into()),
+                }?;
+            }
+        }
+    }
+    wrr.flush()?;
+    Ok(())
+}
+
+/// Collects the state of all shards, records are taken from the shard owning the client
+pub fn sharded_snapshot(
+    ledgers: &[Arc<Mutex<dyn Ledger + Send>>],
+    index: impl ShardRouter,
+) -> Result<Snapshot, ExecError> {
+    let index = |c| index.route(c, ledgers.len());
+    let mut snapshot = Snapshot::default();
     for (i, l) in ledgers.iter().enumerate() {
-        for pair in l.lock().unwrap().accounts() {
-            match pair {
-                Ok((client, _)) if index(client) != i => Ok(()),
-                Ok((client, state)) => wrr.serialize(AccountState {
-                    client,
-                    available: state.available,
-                    total: state.total,
-                    held: state.held,
-                    locked: state.locked,
-                }),
-                Err(e) => Err(e.into()),
-            }?;
+        let l = l.lock().unwrap();
+        for pair in l.accounts() {
+            match pair? {
+                (client, _) if index(client) != i => (),
+                pair => snapshot.accounts.push(pair),
+            }
         }
+        for pair in l.transactions() {
+            match pair? {
+                (_, tx) if index(tx.client) != i => (),
+                pair => snapshot.transactions.push(pair),
+            }
+        }
+    }
+    snapshot.sort();
+    Ok(snapshot)
+}
+
+/// Puts snapshot records into the shards owning their clients
+pub fn sharded_restore(
+    snapshot: &Snapshot,
+    ledgers: &[Arc<Mutex<dyn Ledger + Send>>],
+    index: impl ShardRouter,
+) -> Result<(), ExecError> {
+    let index = |c| index.route(c, ledgers.len());
+    for (client, account) in &snapshot.accounts {
+        ledgers[index(*client)]
+            .lock()
+            .unwrap()
+            .put_account(*client, *account)?;
+    }
+    for (tx_id, tx) in &snapshot.transactions {
+        ledgers[index(tx.client)]
+            .lock()
+            .unwrap()
+            .put_transaction(*tx_id, *tx)?;
     }
     Ok(())
 }
 
+/// Merges per shard sorted accounts into one sorted stream
+fn merge_sorted(shards: Vec<Vec<(Client, Account)>>) -> impl Iterator<Item = (Client, Account)> {
+    let mut shards: Vec<_> = shards
+        .into_iter()
+        .map(|v| v.into_iter().peekable())
+        .collect();
+    let mut heap: BinaryHeap<_> = shards
+        .iter_mut()
+        .enumerate()
+        .filter_map(|(i, it)| it.peek().map(|(c, _)| Reverse((c.0, i))))
+        .collect();
+    std::iter::from_fn(move || {
+        let Reverse((_, i)) = heap.pop()?;
+        let next = shards[i].next();
+        if let Some((c, _)) = shards[i].peek() {
+            heap.push(Reverse((c.0, i)));
+        }
+        next
+    })
+}
+
 pub fn sharded_execute_csv_file(
     path: impl AsRef<Path>,
-    ledgers: &Vec<Arc<Mutex<dyn Ledger + Send>>>,
-    index: impl Fn(Client, usize) -> usize,
+    ledgers: &[Arc<Mutex<dyn Ledger + Send>>],
+    index: impl ShardRouter,
 ) -> Result<(), ExecError> {
     let mut f = std::fs::File::open(path)?;
     sharded_execute_csv(&mut f, ledgers, index)
@@ -71,78 +181,358 @@ pub fn sharded_execute_csv_file(
 
 pub fn sharded_execute_csv(
     rd: impl std::io::Read,
-    ledgers: &Vec<Arc<Mutex<dyn Ledger + Send>>>,
-    index: impl Fn(Client, usize) -> usize,
+    ledgers: &[Arc<Mutex<dyn Ledger + Send>>],
+    index: impl ShardRouter,
+) -> Result<(), ExecError> {
+    sharded_execute_source(CsvSource::new(rd), ledgers, index)
+}
+
+/// Executes csv with shards, the csv is parsed by the pool of threads
+pub fn sharded_execute_csv_parallel(
+    rd: impl std::io::Read + Send,
+    ledgers: &[Arc<Mutex<dyn Ledger + Send>>],
+    index: impl ShardRouter,
+    opts: &ParallelOptions,
+) -> Result<(), ExecError> {
+    thread::scope(|s| {
+        let src = ParallelCsvSource::scoped(s, &Default::default(), rd, opts)?;
+        sharded_execute_source(src, ledgers, index)
+    })
+}
+
+pub fn sharded_execute_source(
+    src: impl TxSource,
+    ledgers: &[Arc<Mutex<dyn Ledger + Send>>],
+    index: impl ShardRouter,
 ) -> Result<(), ExecError> {
-    let mut ch: Vec<Sender<TxRequest>> = Vec::new();
-    let wg = WaitGroup::new();
+    sharded_execute_source_with(src, ledgers, index, &Default::default()).map(|_| ())
+}
+
+/// How the sharded execution handles failed rows
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ErrorMode {
+    /// Stop on the first failure and return it as the error
+    #[default]
+    FailFast,
+    /// Process the whole input and collect failures into the report,
+    ///   a shard failed with IO error skips all its following rows
+    Collect,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ShardedOptions {
+    pub errors: ErrorMode,
+    /// Record the outcome of every applied row in the report
+    pub outcomes: bool,
+}
+
+/// The row which could not be processed
+#[derive(Debug)]
+pub struct RowError {
+    /// Shard the row was routed to, `None` when the row was not dispatched
+    pub shard: Option<usize>,
+    /// Position of the row, `None` when the input has not been parsed
+    pub position: Option<Position>,
+    pub error: ExecError,
+}
+
+impl std::fmt::Display for RowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(shard) = self.shard {
+            write!(f, "shard {shard}: ")?;
+        }
+        if let Some(position) = self.position {
+            write!(f, "{position}: ")?;
+        }
+        write!(f, "{}", self.error)
+    }
+}
+
+/// Load statistics of the shard
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ShardStats {
+    /// Rows routed to the shard
+    pub rows: usize,
+    /// Times the shard queue was full and the dispatcher had to wait
+    pub queue_full_waits: usize,
+    /// Time the dispatcher spent waiting for the shard queue
+    pub queue_wait: Duration,
+    /// Time the shard ledger was locked by the worker
+    pub lock_hold: Duration,
+    /// Lifetime of the worker
+    pub elapsed: Duration,
+}
+
+impl ShardStats {
+    /// Rows per second of the worker lifetime
+    pub fn throughput(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.rows as f64 / secs,
+            _ => 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ShardReport {
+    pub shard: usize,
+    pub applied: usize,
+    pub rejected: usize,
+    pub ignored: usize,
+    /// Rows drained without processing after the shard failed
+    pub skipped: usize,
+    pub failed: Vec<RowError>,
+    pub stats: ShardStats,
+    /// Outcomes of rows in the order of applying, when requested
+    pub outcomes: Vec<(Position, Outcome)>,
+}
+
+#[derive(Debug, Default)]
+pub struct ExecReport {
+    pub shards: Vec<ShardReport>,
+    /// Rows failed before they were dispatched to shards
+    pub input_errors: Vec<RowError>,
+}
+
+impl ExecReport {
+    pub fn is_ok(&self) -> bool {
+        self.input_errors.is_empty() && self.shards.iter().all(|s| s.failed.is_empty())
+    }
+    pub fn errors(&self) -> impl Iterator<Item = &RowError> {
+        self.input_errors
+            .iter()
+            .chain(self.shards.iter().flat_map(|s| s.failed.iter()))
+    }
+    /// Ratio of rows of the most loaded shard to the mean rows per shard,
+    ///   1.0 is the perfectly balanced load
+    pub fn skew(&self) -> f64 {
+        let rows = self.shards.iter().map(|s| s.stats.rows);
+        let total: usize = rows.clone().sum();
+        match (total, rows.max()) {
+            (0, _) | (_, None) => 1.0,
+            (total, Some(max)) => max as f64 * self.shards.len() as f64 / total as f64,
+        }
+    }
+    /// Shards processed more than `factor` times the mean rows per shard
+    pub fn hot_shards(&self, factor: f64) -> Vec<usize> {
+        let total: usize = self.shards.iter().map(|s| s.stats.rows).sum();
+        let mean = total as f64 / self.shards.len().max(1) as f64;
+        self.shards
+            .iter()
+            .filter(|s| total > 0 && s.stats.rows as f64 > mean * factor)
+            .map(|s| s.shard)
+            .collect()
+    }
+    pub fn stats_table(&self) -> StatsTable<'_> {
+        StatsTable(self)
+    }
+}
+
+/// Load statistics of shards, printable as the table
+pub struct StatsTable<'a>(&'a ExecReport);
+
+impl std::fmt::Display for StatsTable<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:>5} {:>10} {:>10} {:>12} {:>12} {:>12} {:>12}",
+            "shard", "rows", "full-waits", "queue-wait", "lock-hold", "elapsed", "rows/sec"
+        )?;
+        for s in &self.0.shards {
+            let st = &s.stats;
+            writeln!(
+                f,
+                "{:>5} {:>10} {:>10} {:>12.3?} {:>12.3?} {:>12.3?} {:>12.0}",
+                s.shard,
+                st.rows,
+                st.queue_full_waits,
+                st.queue_wait,
+                st.lock_hold,
+                st.elapsed,
+                st.throughput()
+            )?;
+        }
+        writeln!(f, "skew {:.2}", self.0.skew())?;
+        for shard in self.0.hot_shards(HOT_SHARD_FACTOR) {
+            writeln!(f, "shard {shard} is hot")?;
+        }
+        Ok(())
+    }
+}
+
+/// The shard is hot when it processes 1.5 times more rows than the mean
+pub const HOT_SHARD_FACTOR: f64 = 1.5;
+
+impl std::fmt::Display for ExecReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for s in &self.shards {
+            writeln!(
+                f,
+                "shard {}: applied {}, rejected {}, ignored {}, failed {}, skipped {}",
+                s.shard,
+                s.applied,
+                s.rejected,
+                s.ignored,
+                s.failed.len(),
+                s.skipped
+            )?;
+        }
+        for e in self.errors() {
+            writeln!(f, "{e}")?;
+        }
+        Ok(())
+    }
+}
+
+fn is_fatal(e: &ExecError) -> bool {
+    match e {
+        ExecError::IOError(_) => true,
+        ExecError::CSVError(e) => matches!(e.kind(), csv::ErrorKind::Io(_)),
+        _ => false,
+    }
+}
+
+pub fn sharded_execute_source_with(
+    src: impl TxSource,
+    ledgers: &[Arc<Mutex<dyn Ledger + Send>>],
+    index: impl ShardRouter,
+    opts: &ShardedOptions,
+) -> Result<ExecReport, ExecError> {
+    let collect = opts.errors == ErrorMode::Collect;
+    let record = opts.outcomes;
+    let mut ch: Vec<Sender<(Position, TxRequest)>> = Vec::new();
+    let mut workers = Vec::new();
     let (res_s, res_r) = unbounded::<ExecError>();
-    for ledger in ledgers {
+    for (shard, ledger) in ledgers.iter().enumerate() {
         let res_s = res_s.clone();
-        let (msg_s, msg_r) = bounded(MSG_QUEUE_LENGTH);
+        let (msg_s, msg_r) = bounded::<(Position, TxRequest)>(MSG_QUEUE_LENGTH);
         ch.push(msg_s);
-        let wg = wg.clone();
         let ledger = ledger.clone();
-        thread::spawn(move || {
-            let mut l = ledger.lock().unwrap();
-            loop {
-                use TxType::*;
-                let res = match msg_r.recv() {
-                    Ok(tx) => match tx.tx_type {
-                        Deposit => l.deposit(tx.client, tx.tx_id, tx.amount.unwrap()),
-                        Withdrawal => l.withdrawal(tx.client, tx.tx_id, tx.amount.unwrap()),
-                        Dispute => l.dispute(tx.client, tx.tx_id),
-                        Resolve => l.resolve(tx.client, tx.tx_id),
-                        Chargeback => l.chargeback(tx.client, tx.tx_id),
-                    },
-                    Err(_) => Err(TxError::Empty),
-                };
-                match res {
-                    Ok(_) | Err(TxError::Rejected(_)) | Err(TxError::Ignored(_)) => (), // ignore
-                    Err(TxError::Empty) => break,
-                    Err(e) => {
-                        let _ = res_s.try_send(e.into());
-                        break;
+        workers.push(thread::spawn(move || {
+            let mut report = ShardReport {
+                shard,
+                ..Default::default()
+            };
+            let started = Instant::now();
+            // the ledger is locked for the batch of already queued rows only
+            'worker: while let Ok(first) = msg_r.recv() {
+                let batch: Vec<_> = std::iter::once(first)
+                    .chain(msg_r.try_iter().take(MSG_QUEUE_LENGTH - 1))
+                    .collect();
+                report.stats.rows += batch.len();
+                let mut l = ledger.lock().unwrap();
+                // waiting for the lock is not holding it
+                let locked = Instant::now();
+                for (position, tx) in batch {
+                    if !report.failed.is_empty() {
+                        // the shard state is unknown after the failure
+                        report.skipped += 1;
+                        continue;
+                    }
+                    let outcome = Outcome::of(apply_request(&mut *l, &tx));
+                    match &outcome {
+                        Ok(Outcome::Applied) => report.applied += 1,
+                        Ok(Outcome::Rejected(_)) => report.rejected += 1,
+                        Ok(Outcome::Ignored(_)) => report.ignored += 1,
+                        Err(_) => (),
+                    }
+                    match outcome {
+                        Ok(outcome) if record => report.outcomes.push((position, outcome)),
+                        Ok(_) => (),
+                        Err(e) if collect => report.failed.push(RowError {
+                            shard: Some(shard),
+                            position: Some(position),
+                            error: e.into(),
+                        }),
+                        Err(e) => {
+                            let _ = res_s.try_send(e.into());
+                            break 'worker;
+                        }
                     }
                 }
+                drop(l);
+                report.stats.lock_hold += locked.elapsed();
             }
-            drop(wg);
-        });
+            report.stats.elapsed = started.elapsed();
+            report
+        }));
     }
     let concurrency = ledgers.len();
-    let mut rdr = csv::ReaderBuilder::new()
-        .delimiter(b',')
-        .comment(Some(b'#'))
-        .flexible(true)
-        .trim(csv::Trim::All)
-        .from_reader(rd);
-    for result in rdr.deserialize() {
-        let r: TxRequest = result?;
-        use TxType::*;
-        let wkr = index(r.client, concurrency);
-        match (r.tx_type, r.amount) {
-            (Deposit | Withdrawal, None) => Err(ExecError::StringError("tx has no amount".into())),
-            _ => Ok(r),
+    let mut report = ExecReport::default();
+    let mut waits = vec![(0usize, Duration::ZERO); concurrency];
+    let dispatch = || -> Result<(), ExecError> {
+        for result in src {
+            use TxType::*;
+            let input_error = |position, error| RowError {
+                shard: None,
+                position,
+                error,
+            };
+            let (position, r) = match result {
+                Ok(x) => x,
+                Err(e) if collect && !is_fatal(&e) => {
+                    report.input_errors.push(input_error(None, e));
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if let (Deposit | Withdrawal, None) = (r.tx_type, r.amount) {
+                let e = ExecError::StringError("tx has no amount".into());
+                match collect {
+                    true => report.input_errors.push(input_error(Some(position), e)),
+                    false => return Err(e),
+                }
+                continue;
+            }
+            if let Ok(err) = res_r.try_recv() {
+                return Err(err);
+            }
+            let shard = index.route(r.client, concurrency);
+            match ch[shard].try_send((position, r)) {
+                Err(TrySendError::Full(msg)) => {
+                    let started = Instant::now();
+                    let sent = ch[shard].send(msg);
+                    waits[shard].0 += 1;
+                    waits[shard].1 += started.elapsed();
+                    sent.map_err(|e| ExecError::StringError(e.to_string()))
+                }
+                sent => sent.map_err(|e| ExecError::StringError(e.to_string())),
+            }?;
         }
-        .and_then(|x| match res_r.try_recv() {
-            Err(TryRecvError::Empty) => ch[wkr]
-                .send(x)
-                .map_err(|e| ExecError::StringError(e.to_string())),
-            Ok(err) => Err(err),
-            Err(err) => Err(ExecError::StringError(err.to_string())),
-        })?;
-    }
+        Ok(())
+    };
+    let dispatched = dispatch();
     drop(ch); // close all channels
-    wg.wait();
+    for (shard, w) in workers.into_iter().enumerate() {
+        match w.join() {
+            Ok(mut r) => {
+                (r.stats.queue_full_waits, r.stats.queue_wait) = waits[shard];
+                report.shards.push(r)
+            }
+            Err(_) if collect => report.shards.push(ShardReport {
+                shard,
+                failed: vec![RowError {
+                    shard: Some(shard),
+                    position: None,
+                    error: ExecError::StringError("worker panicked".into()),
+                }],
+                ..Default::default()
+            }),
+            Err(_) => {
+                return Err(ExecError::StringError(format!(
+                    "shard {shard} worker panicked"
+                )))
+            }
+        }
+    }
     match res_r.try_recv() {
-        Err(TryRecvError::Empty) => Ok(()),
         Ok(err) => Err(err),
-        Err(err) => Err(ExecError::StringError(err.to_string())),
+        Err(_) => dispatched.map(|_| report),
     }
 }
 
 #[derive(Clone, Debug)]
-pub struct SledLedger(sled::Db, Policy);
+pub struct SledLedger(pub(crate) sled::Db, pub(crate) Policy, Arc<dyn Clock>);
 
 impl Default for SledLedger {
     fn default() -> Self {
@@ -156,7 +546,7 @@ impl SledLedger {
         sled::Config::default()
             .path(path)
             .open()
-            .map(|db| SledLedger(db, policy))
+            .map(|db| SledLedger(db, policy, Arc::new(SystemClock)))
     }
     pub fn new_empty(path: Option<String>, policy: Policy) -> sled::Result<SledLedger> {
         match path {
@@ -166,12 +556,16 @@ impl SledLedger {
             }),
             None => sled::Config::default().temporary(true).open(),
         }
-        .map(|db| SledLedger(db, policy))
+        .map(|db| SledLedger(db, policy, Arc::new(SystemClock)))
     }
     #[allow(dead_code)]
     pub fn new() -> sled::Result<SledLedger> {
         Self::new_empty(None, Default::default())
     }
+    /// The ledger taking the current time of the time rules from the clock
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self(self.0, self.1, clock)
+    }
     #[allow(dead_code)]
     pub fn sharding(&self, n: usize) -> Vec<Arc<Mutex<dyn Ledger + Send>>> {
         (0..n)
@@ -180,6 +574,42 @@ impl SledLedger {
     }
 }
 
+const ROUTER_KEY: &str = "0'router";
+
+impl SledLedger {
+    /// The router the ledger has been sharded with
+    pub fn router(&self) -> Result<Option<Router>, IoError> {
+        match self.0.get(ROUTER_KEY) {
+            Err(e) => Err(std::io::Error::new(AnotherError, e)),
+            Ok(None) => Ok(None),
+            Ok(Some(v)) => serde_json::from_slice(&v)
+                .map(Some)
+                .map_err(|e| std::io::Error::new(AnotherError, e)),
+        }
+    }
+    pub fn put_router(&self, router: &Router) -> Result<(), IoError> {
+        self.0
+            .insert(ROUTER_KEY, serde_json::to_vec(router).unwrap())
+            .map_err(|e| std::io::Error::new(AnotherError, e))?;
+        Ok(())
+    }
+    /// Returns the router stored with the ledger,
+    ///   the ledger sharded first time stores the requested (or default) router
+    pub fn open_router(&self, requested: Option<Router>) -> Result<Router, ExecError> {
+        match (self.router()?, requested) {
+            (Some(stored), Some(r)) if stored != r => Err(ExecError::StringError(format!(
+                "ledger is sharded with {stored:?} router"
+            ))),
+            (Some(stored), _) => Ok(stored),
+            (None, r) => {
+                let r = r.unwrap_or_default();
+                self.put_router(&r)?;
+                Ok(r)
+            }
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 struct Rec<K, V> {
     k: K,
@@ -192,6 +622,9 @@ impl Ledger for SledLedger {
     fn policy(&self) -> Policy {
         self.1
     }
+    fn now(&self) -> Timestamp {
+        self.2.now()
+    }
     fn get_account(&self, client: Client) -> Result<Option<Account>, IoError> {
         get::<AccRec>(&self.0.get(format!("1'{:?}", client))).map(|x| x.map(|r| r.v))
     }
@@ -228,7 +661,105 @@ impl Ledger for SledLedger {
     fn transactions<'q>(
         &'q self,
     ) -> Box<dyn Iterator<Item = IterResult<(TxId, Transaction)>> + 'q> {
-        Box::new(self.0.range("2'0"..).map(|v| decode(&v)))
+        Box::new(self.0.range("2'0".."3").map(|v| decode(&v)))
+    }
+    fn put_account_transaction(
+        &mut self,
+        client: Client,
+        account: Account,
+        tx_id: TxId,
+        tx: Transaction,
+    ) -> Result<(), IoError> {
+        // both records are written or none of them
+        self.0
+            .apply_batch(operation_batch(client, account, tx_id, tx))
+            .map_err(|e| std::io::Error::new(AnotherError, e))
+    }
+    fn put_account_transaction_recorded(
+        &mut self,
+        client: Client,
+        account: Account,
+        tx_id: TxId,
+        tx: Transaction,
+        key: &str,
+        recorded: Recorded,
+    ) -> Result<(), IoError> {
+        // the recorded outcome is written with the operation, so a retry never applies it twice
+        let mut batch = operation_batch(client, account, tx_id, tx);
+        batch.insert(
+            format!("3'{key}").as_bytes(),
+            bson::to_vec(&recorded).unwrap(),
+        );
+        self.0
+            .apply_batch(batch)
+            .map_err(|e| std::io::Error::new(AnotherError, e))
+    }
+    fn remove_account(&mut self, client: Client) -> Result<(), IoError> {
+        self.0
+            .remove(format!("1'{:?}", client))
+            .map_err(|e| std::io::Error::new(AnotherError, e))?;
+        Ok(())
+    }
+    fn remove_transaction(&mut self, tx_id: TxId) -> Result<(), IoError> {
+        self.0
+            .remove(format!("2'{:?}", tx_id))
+            .map_err(|e| std::io::Error::new(AnotherError, e))?;
+        Ok(())
+    }
+    fn get_recorded(&self, key: &str) -> Result<Option<Recorded>, IoError> {
+        get::<Recorded>(&self.0.get(format!("3'{key}")))
+    }
+    fn put_recorded(&mut self, key: &str, recorded: Recorded) -> Result<(), IoError> {
+        self.0
+            .insert(format!("3'{key}"), bson::to_vec(&recorded).unwrap())
+            .map_err(|e| std::io::Error::new(AnotherError, e))?;
+        Ok(())
+    }
+    fn recorded<'q>(&'q self) -> Box<dyn Iterator<Item = IterResult<(String, Recorded)>> + 'q> {
+        Box::new(self.0.scan_prefix("3'").map(|v| {
+            let (k, v) = v.map_err(|e| std::io::Error::new(AnotherError, e))?;
+            let key = String::from_utf8_lossy(&k[2..]).into_owned();
+            match bson::from_slice(&v) {
+                Ok(recorded) => Ok((key, recorded)),
+                Err(e) => Err(std::io::Error::new(AnotherError, e)),
+            }
+        }))
+    }
+}
+
+/// The records of the account and the transaction changed by one operation
+fn operation_batch(client: Client, account: Account, tx_id: TxId, tx: Transaction) -> sled::Batch {
+    let mut batch = sled::Batch::default();
+    batch.insert(
+        format!("2'{:?}", tx_id).as_bytes(),
+        bson::to_vec(&TxRec { k: tx_id, v: tx }).unwrap(),
+    );
+    batch.insert(
+        format!("1'{:?}", client).as_bytes(),
+        bson::to_vec(&AccRec {
+            k: client,
+            v: account,
+        })
+        .unwrap(),
+    );
+    batch
+}
+
+impl SledLedger {
+    /// Decodes the raw value of the stored account
+    pub fn decode_account(value: &[u8]) -> Result<(Client, Account), IoError> {
+        decode_rec(value)
+    }
+    /// Decodes the raw value of the stored transaction
+    pub fn decode_transaction(value: &[u8]) -> Result<(TxId, Transaction), IoError> {
+        decode_rec(value)
+    }
+}
+
+fn decode_rec<'a, A: Deserialize<'a>, B: Deserialize<'a>>(value: &'a [u8]) -> IterResult<(A, B)> {
+    match bson::from_slice::<'a, Rec<A, B>>(value) {
+        Ok(r) => Ok((r.k, r.v)),
+        Err(e) => Err(std::io::Error::new(AnotherError, e)),
     }
 }
 
@@ -236,10 +767,7 @@ fn decode<'a, A: Deserialize<'a>, B: Deserialize<'a>>(
     v: &'a sled::Result<(sled::IVec, sled::IVec)>,
 ) -> IterResult<(A, B)> {
     match v {
-        Ok((_, b)) => match bson::from_slice::<'a, Rec<A, B>>(b) {
-            Ok(r) => Ok((r.k, r.v)),
-            Err(e) => Err(std::io::Error::new(AnotherError, e)),
-        },
+        Ok((_, b)) => decode_rec(b),
         Err(e) => Err(std::io::Error::new(AnotherError, e.clone())),
     }
 }
@@ -277,7 +805,7 @@ fn test_concurrent_csv_processing_1() -> Result<(), ExecError> {
 
 #[test]
 fn test_concurrent_csv_processing_2() -> Result<(), ExecError> {
-    let sharding = (0..3)
+    let sharding: Vec<_> = (0..3)
         .map(|_| {
             Arc::new(Mutex::new(crate::basic::HashLedger::default()))
                 as Arc<Mutex<dyn Ledger + Send>>
@@ -296,3 +824,200 @@ fn test_concurrent_csv_processing_2() -> Result<(), ExecError> {
     sharded_dump_accounts(std::io::stdout(), &sharding, index_by_client)?;
     Ok(())
 }
+
+#[test]
+fn test_parallel_csv_processing() -> Result<(), ExecError> {
+    let sharding = SledLedger::new().unwrap().sharding(3);
+    let opts = ParallelOptions {
+        parsers: 2,
+        chunk_size: 64,
+    };
+    sharded_execute_csv_parallel(
+        crate::basic::TRANSACTIONS.as_bytes(),
+        &sharding,
+        index_by_client,
+        &opts,
+    )?;
+    sharded_validate_accounts(
+        std::io::Cursor::new(crate::basic::ACCOUNTS.as_bytes()),
+        &sharding,
+        index_by_client,
+    )
+}
+
+#[test]
+fn test_sorted_sharded_dump() -> Result<(), ExecError> {
+    let sharding: Vec<_> = (0..3)
+        .map(|_| {
+            Arc::new(Mutex::new(crate::basic::HashLedger::default()))
+                as Arc<Mutex<dyn Ledger + Send>>
+        })
+        .collect();
+    let transactions = (1..=20u16)
+        .rev()
+        .map(|c| format!("deposit, {c}, {c}, 1.5\n"))
+        .collect::<String>();
+    let mut out = Vec::new();
+    let opts = DumpOptions {
+        sorted: true,
+        scale: Some(0),
+        columns: "client,total"
+            .split(',')
+            .map(|c| c.parse().unwrap())
+            .collect(),
+        ..Default::default()
+    };
+    sharded_dump_accounts_with(&mut out, &sharding, index_by_client, &opts)?;
+    assert!(out.is_empty());
+    sharded_execute_csv(
+        std::io::Cursor::new(format!("type, client, tx, amount\n{transactions}")),
+        &sharding,
+        index_by_client,
+    )?;
+    sharded_dump_accounts_with(&mut out, &sharding, index_by_client, &opts)?;
+    let expected = (1..=20).map(|c| format!("{c},2\n")).collect::<String>();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        format!("client,total\n{expected}")
+    );
+    Ok(())
+}
+
+#[test]
+fn test_stored_router() -> Result<(), ExecError> {
+    let ledger = SledLedger::new().unwrap();
+    let router = Router::Jump.pin(Client(1), 2);
+    assert_eq!(ledger.open_router(Some(router.clone()))?, router);
+    assert_eq!(ledger.open_router(None)?, router);
+    assert!(ledger.open_router(Some(Router::Modulo)).is_err());
+    let sharding = ledger.sharding(3);
+    sharded_execute_csv(
+        std::io::Cursor::new(crate::basic::TRANSACTIONS.as_bytes()),
+        &sharding,
+        &router,
+    )?;
+    sharded_validate_accounts(
+        std::io::Cursor::new(crate::basic::ACCOUNTS.as_bytes()),
+        &sharding,
+        &router,
+    )
+}
+
+#[cfg(test)]
+struct FailingLedger(crate::basic::HashLedger, Client);
+
+#[cfg(test)]
+impl Ledger for FailingLedger {
+    fn policy(&self) -> Policy {
+        self.0.policy()
+    }
+    fn get_account(&self, client: Client) -> Result<Option<Account>, IoError> {
+        self.0.get_account(client)
+    }
+    fn put_account(&mut self, client: Client, account: Account) -> Result<(), IoError> {
+        match client == self.1 {
+            true => Err(IoError::new(AnotherError, "storage failure")),
+            false => self.0.put_account(client, account),
+        }
+    }
+    fn accounts<'q>(&'q self) -> Box<dyn Iterator<Item = IterResult<(Client, Account)>> + 'q> {
+        self.0.accounts()
+    }
+    fn get_transaction(&self, tx_id: TxId) -> Result<Option<Transaction>, IoError> {
+        self.0.get_transaction(tx_id)
+    }
+    fn put_transaction(&mut self, tx_id: TxId, tx: Transaction) -> Result<(), IoError> {
+        self.0.put_transaction(tx_id, tx)
+    }
+    fn transactions<'q>(
+        &'q self,
+    ) -> Box<dyn Iterator<Item = IterResult<(TxId, Transaction)>> + 'q> {
+        self.0.transactions()
+    }
+}
+
+#[test]
+fn test_collect_errors() -> Result<(), ExecError> {
+    let broken = Client(5);
+    let sharding: Vec<_> = (0..3)
+        .map(|_| {
+            Arc::new(Mutex::new(FailingLedger(Default::default(), broken)))
+                as Arc<Mutex<dyn Ledger + Send>>
+        })
+        .collect();
+    let input = || {
+        let rows = (1..=40u32)
+            .map(|tx| format!("deposit, {}, {tx}, 1.0\n", (tx - 1) % 20 + 1))
+            .collect::<String>();
+        CsvSource::new(std::io::Cursor::new(format!(
+            "type, client, tx, amount\n{rows}"
+        )))
+    };
+    let report = sharded_execute_source_with(
+        input(),
+        &sharding,
+        index_by_client,
+        &ShardedOptions {
+            errors: ErrorMode::Collect,
+            ..Default::default()
+        },
+    )?;
+    assert!(!report.is_ok());
+    let errors: Vec<_> = report.errors().collect();
+    assert_eq!(errors.len(), 1);
+    let shard = index_by_client(broken, 3);
+    assert_eq!(errors[0].shard, Some(shard));
+    assert_eq!(errors[0].position.map(|p| p.line), Some(6));
+    // the failed shard skips all its rows after the failure
+    let routed = |c: u16| (index_by_client(Client(c), 3) == shard) as usize;
+    let before = (1..5).map(routed).sum::<usize>();
+    assert_eq!(report.shards[shard].applied, before);
+    let total = 2 * (1..=20).map(routed).sum::<usize>();
+    assert_eq!(report.shards[shard].skipped, total - before - 1);
+    for s in report.shards.iter().filter(|s| s.shard != shard) {
+        assert_eq!(
+            s.applied,
+            (1..=20)
+                .map(|c| 2 * (index_by_client(Client(c), 3) == s.shard) as usize)
+                .sum::<usize>()
+        );
+        assert_eq!(s.skipped, 0);
+    }
+
+    let failed = sharded_execute_source(input(), &sharding, index_by_client);
+    assert!(matches!(
+        failed,
+        Err(ExecError::TxError(TxError::IOError(_)))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_hot_shard_stats() -> Result<(), ExecError> {
+    let sharding: Vec<_> = (0..4)
+        .map(|_| {
+            Arc::new(Mutex::new(crate::basic::HashLedger::new())) as Arc<Mutex<dyn Ledger + Send>>
+        })
+        .collect();
+    // client 1 is hot, it has as many rows as all other clients
+    let rows = (1..=200u32)
+        .map(|tx| match tx % 2 {
+            0 => format!("deposit, 1, {tx}, 1.0\n"),
+            _ => format!("deposit, {}, {tx}, 1.0\n", tx % 40 + 2),
+        })
+        .collect::<String>();
+    let src = CsvSource::new(std::io::Cursor::new(format!(
+        "type, client, tx, amount\n{rows}"
+    )));
+    let report = sharded_execute_source_with(src, &sharding, Router::Modulo, &Default::default())?;
+    assert_eq!(
+        report.shards.iter().map(|s| s.stats.rows).sum::<usize>(),
+        200
+    );
+    assert_eq!(report.shards[1].stats.rows, report.shards[1].applied);
+    assert!(report.shards[1].stats.rows > 100);
+    assert_eq!(report.hot_shards(HOT_SHARD_FACTOR), vec![1]);
+    assert!(report.skew() > 2.0);
+    assert!(report.stats_table().to_string().contains("shard 1 is hot"));
+    Ok(())
+}
@@ -0,0 +1,286 @@
+use crate::{
+    advanced::MSG_QUEUE_LENGTH,
+    common::{Ledger, TxError, TxType},
+    dialect::Dialect,
+    libcsv::{apply_request, ExecError, TxRequest},
+    pipeline::{chunk_reader, parse_chunk, source_item, Chunker, CHUNK_SIZE},
+    routing::ShardRouter,
+    source::SourceItem,
+};
+use futures::{Stream, StreamExt};
+use std::sync::{Arc, Mutex};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::mpsc,
+};
+
+/// Async source of transaction requests read as csv records,
+///   a quoted field can span lines
+pub fn async_csv_source<R: AsyncRead + Unpin>(
+    rd: R,
+    dialect: &Dialect,
+) -> Result<impl Stream<Item = SourceItem> + Unpin, ExecError> {
+    let state = AsyncCsv {
+        rd: Some(rd),
+        chunker: Chunker::new(dialect)?,
+        builder: chunk_reader(dialect)?,
+        dialect: dialect.clone(),
+        items: Vec::new().into_iter(),
+        base: 0,
+    };
+    Ok(Box::pin(futures::stream::unfold(
+        state,
+        |mut st| async move {
+            loop {
+                if let Some(item) = st.items.next() {
+                    return Some((item, st));
+                }
+                let rd = st.rd.as_mut()?;
+                st.chunker.buf().reserve(CHUNK_SIZE);
+                let eof = match rd.read_buf(st.chunker.buf()).await {
+                    Ok(n) => n == 0,
+                    // the stream ends after the IO error
+                    Err(e) => {
+                        st.rd = None;
+                        return Some((Err(e.into()), st));
+                    }
+                };
+                if eof {
+                    st.rd = None;
+                }
+                match st.chunker.take(eof) {
+                    Ok(None) => (),
+                    Ok(Some(chunk)) => {
+                        let (parsed, count) = parse_chunk(&st.dialect, &st.builder, &chunk);
+                        let base = st.base;
+                        st.base += count;
+                        st.items = parsed
+                            .into_iter()
+                            .map(|p| source_item(p, base))
+                            .collect::<Vec<_>>()
+                            .into_iter();
+                    }
+                    Err(e) => {
+                        st.rd = None;
+                        return Some((Err(e), st));
+                    }
+                }
+            }
+        },
+    )))
+}
+
+struct AsyncCsv<R> {
+    rd: Option<R>,
+    chunker: Chunker,
+    builder: csv::ReaderBuilder,
+    dialect: Dialect,
+    items: std::vec::IntoIter<SourceItem>,
+    base: u64,
+}
+
+/// Options of `async_sharded_execute_with`
+#[derive(Clone, Copy, Debug)]
+pub struct AsyncOptions {
+    /// Requests queued for every shard before the source is read further
+    pub queue_length: usize,
+    /// Queued requests applied under one lock of the shard
+    pub batch_size: usize,
+}
+
+impl Default for AsyncOptions {
+    fn default() -> Self {
+        Self {
+            queue_length: MSG_QUEUE_LENGTH,
+            batch_size: MSG_QUEUE_LENGTH,
+        }
+    }
+}
+
+/// Async counterpart of `advanced::sharded_execute_source`
+///
+/// Every shard is served by a task receiving requests from a bounded channel,
+///   so a slow shard stops the source from being read further.
+/// The requests are applied in batches of already queued ones, the shard is locked
+///   for the batch only, in the blocking pool. The requests of a client are always routed
+///   to the same shard and applied in the source order.
+pub async fn async_sharded_execute<L>(
+    src: impl Stream<Item = SourceItem> + Unpin,
+    ledgers: &[Arc<Mutex<L>>],
+    index: impl ShardRouter,
+) -> Result<(), ExecError>
+where
+    L: Ledger + Send + ?Sized + 'static,
+{
+    async_sharded_execute_with(src, ledgers, index, &Default::default()).await
+}
+
+pub async fn async_sharded_execute_with<L>(
+    mut src: impl Stream<Item = SourceItem> + Unpin,
+    ledgers: &[Arc<Mutex<L>>],
+    index: impl ShardRouter,
+    opts: &AsyncOptions,
+) -> Result<(), ExecError>
+where
+    L: Ledger + Send + ?Sized + 'static,
+{
+    if opts.queue_length == 0 || opts.batch_size == 0 {
+        return Err(ExecError::StringError(
+            "queue length and batch size must be positive".into(),
+        ));
+    }
+    let batch_size = opts.batch_size;
+    let (err_s, mut err_r) = mpsc::unbounded_channel::<ExecError>();
+    let mut ch = Vec::new();
+    let mut workers = Vec::new();
+    for ledger in ledgers {
+        let (msg_s, mut msg_r) = mpsc::channel::<TxRequest>(opts.queue_length);
+        ch.push(msg_s);
+        let ledger = ledger.clone();
+        let err_s = err_s.clone();
+        workers.push(tokio::spawn(async move {
+            let mut batch = Vec::with_capacity(batch_size);
+            while msg_r.recv_many(&mut batch, batch_size).await > 0 {
+                let ledger = ledger.clone();
+                let requests = std::mem::take(&mut batch);
+                let res = tokio::task::spawn_blocking(move || {
+                    let mut l = ledger.lock().unwrap();
+                    for tx in &requests {
+                        match apply_request(&mut *l, tx) {
+                            Ok(_) | Err(TxError::Rejected(_)) | Err(TxError::Ignored(_)) => (),
+                            Err(e) => return Err(ExecError::from(e)),
+                        }
+                    }
+                    Ok(())
+                })
+                .await
+                .unwrap_or_else(|e| Err(ExecError::StringError(e.to_string())));
+                if let Err(e) = res {
+                    let _ = err_s.send(e);
+                    break;
+                }
+            }
+        }));
+    }
+    drop(err_s);
+    let concurrency = ledgers.len();
+    let res = async {
+        while let Some(result) = src.next().await {
+            let (_, r) = result?;
+            use TxType::*;
+            if let (Deposit | Withdrawal, None) = (r.tx_type, r.amount) {
+                return Err(ExecError::StringError("tx has no amount".into()));
+            }
+            if let Ok(err) = err_r.try_recv() {
+                return Err(err);
+            }
+            ch[index.route(r.client, concurrency)]
+                .send(r)
+                .await
+                .map_err(|e| ExecError::StringError(e.to_string()))?;
+        }
+        Ok(())
+    }
+    .await;
+    drop(ch); // close all channels
+    for w in workers {
+        w.await.map_err(|e| ExecError::StringError(e.to_string()))?;
+    }
+    match err_r.try_recv() {
+        Ok(err) => Err(err),
+        Err(_) => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        advanced::{index_by_client, sharded_validate_accounts, SledLedger},
+        basic::HashLedger,
+    };
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_async_csv_processing() -> Result<(), ExecError> {
+        let sharding: Vec<_> = (0..3)
+            .map(|_| Arc::new(Mutex::new(HashLedger::new())))
+            .collect();
+        let src = async_csv_source(crate::basic::TRANSACTIONS.as_bytes(), &Default::default())?;
+        async_sharded_execute(src, &sharding, index_by_client).await?;
+        let sharding: Vec<_> = sharding
+            .into_iter()
+            .map(|l| l as Arc<Mutex<dyn Ledger + Send>>)
+            .collect();
+        sharded_validate_accounts(
+            std::io::Cursor::new(crate::basic::ACCOUNTS.as_bytes()),
+            &sharding,
+            index_by_client,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_async_dyn_ledgers() -> Result<(), ExecError> {
+        let sharding = SledLedger::new().unwrap().sharding(2);
+        let src = async_csv_source(crate::basic::TRANSACTIONS.as_bytes(), &Default::default())?;
+        async_sharded_execute(src, &sharding, index_by_client).await?;
+        sharded_validate_accounts(
+            std::io::Cursor::new(crate::basic::ACCOUNTS.as_bytes()),
+            &sharding,
+            index_by_client,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_async_source_positions() -> Result<(), ExecError> {
+        let src = async_csv_source(crate::basic::TRANSACTIONS.as_bytes(), &Default::default())?;
+        let positions: Vec<_> = src.map(|r| r.map(|(p, _)| p.line)).collect().await;
+        let positions = positions.into_iter().collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(positions.len(), 14);
+        assert_eq!(positions[..2], [3, 5]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_source_quoted_newlines() -> Result<(), ExecError> {
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,1\n\
+            \"deposit\n\",2,2,2\n\
+            # a comment\n\
+            deposit,\"3\",3,3";
+        let expected: Vec<_> = crate::source::CsvSource::new(std::io::Cursor::new(input))
+            .map(|r| r.map(|(p, r)| (p, r.client)).map_err(|e| e.to_string()))
+            .collect();
+        let src = async_csv_source(input.as_bytes(), &Default::default())?;
+        let positions: Vec<_> = src
+            .map(|r| r.map(|(p, r)| (p, r.client)).map_err(|e| e.to_string()))
+            .collect()
+            .await;
+        assert_eq!(positions.len(), 3);
+        assert_eq!(positions, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_async_options() -> Result<(), ExecError> {
+        let sharding: Vec<_> = (0..2)
+            .map(|_| Arc::new(Mutex::new(HashLedger::new())))
+            .collect();
+        let opts = AsyncOptions {
+            queue_length: 1,
+            batch_size: 64,
+        };
+        let src = async_csv_source(crate::basic::TRANSACTIONS.as_bytes(), &Default::default())?;
+        async_sharded_execute_with(src, &sharding, index_by_client, &opts).await?;
+        let src = async_csv_source(crate::basic::TRANSACTIONS.as_bytes(), &Default::default())?;
+        let opts = AsyncOptions {
+            batch_size: 0,
+            ..opts
+        };
+        assert!(
+            async_sharded_execute_with(src, &sharding, index_by_client, &opts)
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+}
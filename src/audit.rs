@@ -0,0 +1,572 @@
+//! Tamper-evident audit log of ledger writes
+//!
+//! `AuditLedger` appends an entry for every written or removed account and transaction
+//!   with the values before and after, the actor, and the request with its source and line.
+//! The log is JSON lines, every entry holds the SHA-256 of the previous entry and its own,
+//!   so a changed, removed or inserted entry breaks the chain:
+//! ```text
+//! {"seq":1,"actor":"ops","source":"in.csv","line":2,"request":{...},
+//!  "change":{"record":"transaction","tx":1,"before":null,"after":{...}},"prev":"000...","hash":"9f2..."}
+//! ```
+//! `verify` replays the chain, the `before` value of every entry must be the `after` value
+//!   of the previous entry of the same record.
+use crate::{
+    common::*,
+    libcsv::{apply_request, ExecError, TxRequest},
+    snapshot::hex,
+    source::TxSource,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// Hash preceding the first entry
+pub const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Change of one record, `None` is the absent record
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum Change {
+    Account {
+        client: Client,
+        before: Option<Account>,
+        after: Option<Account>,
+    },
+    Transaction {
+        tx: TxId,
+        before: Option<Transaction>,
+        after: Option<Transaction>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub actor: String,
+    pub source: Option<String>,
+    pub line: Option<u64>,
+    pub request: Option<TxRequest>,
+    pub change: Change,
+    pub prev: String,
+    pub hash: String,
+}
+
+impl AuditEntry {
+    /// SHA-256 of the entry without its hash
+    pub fn digest(&self) -> String {
+        let unsigned = AuditEntry {
+            hash: String::new(),
+            ..self.clone()
+        };
+        hex(&Sha256::digest(serde_json::to_vec(&unsigned).unwrap()))
+    }
+}
+
+/// Who and what the following writes originate from
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Origin {
+    pub actor: String,
+    pub source: Option<String>,
+    pub line: Option<u64>,
+    pub request: Option<TxRequest>,
+}
+
+/// Appending end of the chain
+pub struct AuditLog {
+    wr: Box<dyn Write + Send>,
+    next_seq: u64,
+    last: String,
+}
+
+impl AuditLog {
+    /// The new chain
+    pub fn new(wr: impl Write + Send + 'static) -> Self {
+        Self {
+            wr: Box::new(wr),
+            next_seq: 1,
+            last: GENESIS.into(),
+        }
+    }
+
+    /// Continues the chain of the file after verifying it, creates the file when it does not exist
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, ExecError> {
+        let path = path.as_ref();
+        let (next_seq, last) = match std::fs::File::open(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (1, GENESIS.into()),
+            f => {
+                let replay = verify(std::io::BufReader::new(f?))?;
+                (replay.entries + 1, replay.last)
+            }
+        };
+        let f = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self {
+            wr: Box::new(f),
+            next_seq,
+            last,
+        })
+    }
+
+    /// Hash of the last entry, publishing it anchors the chain
+    pub fn last_hash(&self) -> &str {
+        &self.last
+    }
+
+    pub fn append(&mut self, origin: &Origin, change: Change) -> std::io::Result<()> {
+        let mut entry = AuditEntry {
+            seq: self.next_seq,
+            actor: origin.actor.clone(),
+            source: origin.source.clone(),
+            line: origin.line,
+            request: origin.request.clone(),
+            change,
+            prev: self.last.clone(),
+            hash: String::new(),
+        };
+        entry.hash = entry.digest();
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        self.wr.write_all(&line)?;
+        self.wr.flush()?;
+        self.next_seq += 1;
+        self.last = entry.hash;
+        Ok(())
+    }
+}
+
+/// Ledger appending its writes to the audit log
+///
+/// The writes are logged ahead, so a failed append fails the write before it is done.
+/// A failed write is followed by the entries of its records as they are after it,
+///   so the chain stays true to the ledger unless the process crashes in between.
+/// The log is shared by the shards of a ledger.
+pub struct AuditLedger<L> {
+    inner: L,
+    log: Arc<Mutex<AuditLog>>,
+    origin: Origin,
+}
+
+impl<L: Ledger> AuditLedger<L> {
+    pub fn new(inner: L, log: Arc<Mutex<AuditLog>>, actor: impl Into<String>) -> Self {
+        Self {
+            inner,
+            log,
+            origin: Origin {
+                actor: actor.into(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Sets the request and its position the following writes originate from
+    pub fn begin(&mut self, source: Option<&str>, line: Option<u64>, request: &TxRequest) {
+        self.origin.source = source.map(Into::into);
+        self.origin.line = line;
+        self.origin.request = Some(request.clone());
+    }
+
+    /// Following writes do not originate from a request
+    pub fn end(&mut self) {
+        self.origin.source = None;
+        self.origin.line = None;
+        self.origin.request = None;
+    }
+
+    pub fn into_inner(self) -> L {
+        self.inner
+    }
+
+    fn append(&self, changes: impl IntoIterator<Item = Change>) -> Result<(), IoError> {
+        let mut log = self.log.lock().unwrap();
+        for change in changes {
+            log.append(&self.origin, change)?;
+        }
+        Ok(())
+    }
+
+    /// Appends the changes, then writes them to the inner ledger
+    fn logged(
+        &mut self,
+        changes: Vec<Change>,
+        write: impl FnOnce(&mut L) -> Result<(), IoError>,
+    ) -> Result<(), IoError> {
+        self.append(changes.clone())?;
+        let Err(e) = write(&mut self.inner) else {
+            return Ok(());
+        };
+        // the failed write may be partial, the records are logged as they are,
+        // the write has failed anyway when they can not be
+        let _ = changes
+            .into_iter()
+            .map(|change| self.reverted(change))
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|changes| self.append(changes));
+        Err(e)
+    }
+
+    /// The change from the logged value to the stored one
+    fn reverted(&self, change: Change) -> Result<Change, IoError> {
+        Ok(match change {
+            Change::Account { client, after, .. } => Change::Account {
+                client,
+                before: after,
+                after: self.inner.get_account(client)?,
+            },
+            Change::Transaction { tx, after, .. } => Change::Transaction {
+                tx,
+                before: after,
+                after: self.inner.get_transaction(tx)?,
+            },
+        })
+    }
+
+    fn put_operation(
+        &mut self,
+        client: Client,
+        account: Account,
+        tx_id: TxId,
+        tx: Transaction,
+        recorded: Option<(&str, Recorded)>,
+    ) -> Result<(), IoError> {
+        let changes = vec![
+            Change::Transaction {
+                tx: tx_id,
+                before: self.inner.get_transaction(tx_id)?,
+                after: Some(tx),
+            },
+            Change::Account {
+                client,
+                before: self.inner.get_account(client)?,
+                after: Some(account),
+            },
+        ];
+        self.logged(changes, |inner| {
+            put_operation(inner, client, account, tx_id, tx, recorded)
+        })
+    }
+}
+
+type IoError = std::io::Error;
+
+impl<L: Ledger> Ledger for AuditLedger<L> {
+    fn policy(&self) -> Policy {
+        self.inner.policy()
+    }
+    fn now(&self) -> Timestamp {
+        self.inner.now()
+    }
+    fn get_account(&self, client: Client) -> Result<Option<Account>, IoError> {
+        self.inner.get_account(client)
+    }
+    fn put_account(&mut self, client: Client, account: Account) -> Result<(), IoError> {
+        let change = Change::Account {
+            client,
+            before: self.inner.get_account(client)?,
+            after: Some(account),
+        };
+        self.logged(vec![change], |inner| inner.put_account(client, account))
+    }
+    fn accounts<'q>(&'q self) -> Box<dyn Iterator<Item = IterResult<(Client, Account)>> + 'q> {
+        self.inner.accounts()
+    }
+    fn get_transaction(&self, tx_id: TxId) -> Result<Option<Transaction>, IoError> {
+        self.inner.get_transaction(tx_id)
+    }
+    fn put_transaction(&mut self, tx_id: TxId, tx: Transaction) -> Result<(), IoError> {
+        let change = Change::Transaction {
+            tx: tx_id,
+            before: self.inner.get_transaction(tx_id)?,
+            after: Some(tx),
+        };
+        self.logged(vec![change], |inner| inner.put_transaction(tx_id, tx))
+    }
+    fn transactions<'q>(
+        &'q self,
+    ) -> Box<dyn Iterator<Item = IterResult<(TxId, Transaction)>> + 'q> {
+        self.inner.transactions()
+    }
+    fn put_account_transaction(
+        &mut self,
+        client: Client,
+        account: Account,
+        tx_id: TxId,
+        tx: Transaction,
+    ) -> Result<(), IoError> {
+        self.put_operation(client, account, tx_id, tx, None)
+    }
+    fn put_account_transaction_recorded(
+        &mut self,
+        client: Client,
+        account: Account,
+        tx_id: TxId,
+        tx: Transaction,
+        key: &str,
+        recorded: Recorded,
+    ) -> Result<(), IoError> {
+        self.put_operation(client, account, tx_id, tx, Some((key, recorded)))
+    }
+    fn remove_account(&mut self, client: Client) -> Result<(), IoError> {
+        let change = Change::Account {
+            client,
+            before: self.inner.get_account(client)?,
+            after: None,
+        };
+        self.logged(vec![change], |inner| inner.remove_account(client))
+    }
+    fn remove_transaction(&mut self, tx_id: TxId) -> Result<(), IoError> {
+        let change = Change::Transaction {
+            tx: tx_id,
+            before: self.inner.get_transaction(tx_id)?,
+            after: None,
+        };
+        self.logged(vec![change], |inner| inner.remove_transaction(tx_id))
+    }
+    fn get_recorded(&self, key: &str) -> Result<Option<Recorded>, IoError> {
+        self.inner.get_recorded(key)
+    }
+    fn put_recorded(&mut self, key: &str, recorded: Recorded) -> Result<(), IoError> {
+        self.inner.put_recorded(key, recorded)
+    }
+    fn recorded<'q>(&'q self) -> Box<dyn Iterator<Item = IterResult<(String, Recorded)>> + 'q> {
+        self.inner.recorded()
+    }
+}
+
+/// `execute_source` logging the source name and the line of every request
+pub fn execute_source_audited<L: Ledger>(
+    src: impl TxSource,
+    ledger: &mut AuditLedger<L>,
+    source: &str,
+) -> Result<(), ExecError> {
+    for result in src {
+        let (pos, r) = result?;
+        ledger.begin(Some(source), Some(pos.line), &r);
+        match apply_request(ledger, &r) {
+            Ok(()) | Err(TxError::Rejected(_)) | Err(TxError::Ignored(_)) => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    ledger.end();
+    Ok(())
+}
+
+/// State of the records replayed from the chain
+#[derive(Debug, Default)]
+pub struct Replay {
+    pub entries: u64,
+    /// Hash of the last entry
+    pub last: String,
+    pub accounts: HashMap<Client, Option<Account>>,
+    pub transactions: HashMap<TxId, Option<Transaction>>,
+}
+
+impl Replay {
+    /// Compares the replayed records with the ledger
+    pub fn check_ledger(&self, ledger: &dyn Ledger) -> Result<(), ExecError> {
+        let mismatch = |what: String| {
+            ExecError::StringError(format!("{what} of the ledger differs from the audit log"))
+        };
+        for (client, account) in &self.accounts {
+            if ledger.get_account(*client)? != *account {
+                return Err(mismatch(format!("account {}", client.0)));
+            }
+        }
+        for (tx_id, tx) in &self.transactions {
+            if ledger.get_transaction(*tx_id)? != *tx {
+                return Err(mismatch(format!("transaction {}", tx_id.0)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replays the chain, fails on the first broken entry
+pub fn verify(rd: impl BufRead) -> Result<Replay, ExecError> {
+    let mut replay = Replay {
+        last: GENESIS.into(),
+        ..Default::default()
+    };
+    for line in rd.lines() {
+        let seq = replay.entries + 1;
+        let broken = |why: &str| ExecError::StringError(format!("audit entry {seq}: {why}"));
+        let entry: AuditEntry = serde_json::from_str(&line?).map_err(|e| broken(&e.to_string()))?;
+        if entry.seq != seq {
+            return Err(broken(&format!("unexpected sequence number {}", entry.seq)));
+        }
+        if entry.prev != replay.last {
+            return Err(broken("does not follow the previous entry"));
+        }
+        if entry.digest() != entry.hash {
+            return Err(broken("hash mismatch"));
+        }
+        // the first entry of a record starts its history
+        let continues = match &entry.change {
+            Change::Account {
+                client,
+                before,
+                after,
+            } => replay
+                .accounts
+                .insert(*client, *after)
+                .is_none_or(|last| last == *before),
+            Change::Transaction { tx, before, after } => replay
+                .transactions
+                .insert(*tx, *after)
+                .is_none_or(|last| last == *before),
+        };
+        if !continues {
+            return Err(broken("the value before differs from the previous change"));
+        }
+        replay.entries = seq;
+        replay.last = entry.hash;
+    }
+    Ok(replay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        advanced::SledLedger,
+        basic::HashLedger,
+        faults::{Fault, FaultRule, FaultyLedger, StorageOp},
+        libcsv::ExecError,
+        source::CsvSource,
+    };
+    use rust_decimal_macros::dec;
+
+    /// Log writer kept by the test
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn audited(ledger: impl Ledger) -> Result<(Vec<String>, impl Ledger), ExecError> {
+        let out = Shared::default();
+        let log = Arc::new(Mutex::new(AuditLog::new(out.clone())));
+        let mut ledger = AuditLedger::new(ledger, log, "tester");
+        let input = "type,client,tx,amount\n\
+            deposit,1,1,5\n\
+            deposit,2,2,3\n\
+            withdrawal,1,3,10\n\
+            dispute,1,1,\n\
+            chargeback,1,1,\n";
+        execute_source_audited(CsvSource::new(input.as_bytes()), &mut ledger, "in.csv")?;
+        ledger.put_account(Client(3), Default::default())?;
+        ledger.remove_account(Client(3))?;
+        let log = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        Ok((log.lines().map(String::from).collect(), ledger.into_inner()))
+    }
+
+    #[test]
+    fn test_audit_chain() -> Result<(), ExecError> {
+        let (lines, ledger) = audited(SledLedger::new().unwrap())?;
+        // 4 applied operations write a transaction and an account each
+        assert_eq!(lines.len(), 10);
+        let entry: AuditEntry = serde_json::from_str(&lines[7]).unwrap();
+        assert_eq!(
+            (entry.actor.as_str(), entry.source.as_deref()),
+            ("tester", Some("in.csv"))
+        );
+        assert_eq!(entry.line, Some(6));
+        assert_eq!(entry.request.unwrap().tx_type, TxType::Chargeback);
+        let replay = verify(lines.join("\n").as_bytes())?;
+        assert_eq!(replay.entries, 10);
+        assert_eq!(replay.accounts[&Client(3)], None);
+        replay.check_ledger(&ledger)?;
+
+        let other = HashLedger::new();
+        assert!(replay.check_ledger(&other).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tampering() -> Result<(), ExecError> {
+        let (lines, _) = audited(HashLedger::new())?;
+        let error = |lines: &[String]| match verify(lines.join("\n").as_bytes()) {
+            Err(e) => e.to_string(),
+            Ok(_) => "verified".into(),
+        };
+        let mut changed = lines.clone();
+        changed[1] = changed[1].replace("\"5\"", "\"50\"");
+        assert_eq!(error(&changed), "audit entry 2: hash mismatch");
+        let mut removed = lines.clone();
+        removed.remove(4);
+        assert_eq!(
+            error(&removed),
+            "audit entry 5: unexpected sequence number 6"
+        );
+
+        // the rehashed chain still has to replay the changes
+        let mut entries: Vec<AuditEntry> = lines
+            .iter()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        entries.remove(4);
+        let mut prev = GENESIS.to_string();
+        for (i, e) in entries.iter_mut().enumerate() {
+            e.seq = i as u64 + 1;
+            e.prev = prev;
+            e.hash = e.digest();
+            prev = e.hash.clone();
+        }
+        let forged: Vec<_> = entries
+            .iter()
+            .map(|e| serde_json::to_string(e).unwrap())
+            .collect();
+        assert_eq!(
+            error(&forged),
+            "audit entry 6: the value before differs from the previous change"
+        );
+        Ok(())
+    }
+
+    /// Log writer failing every write
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("disk full"))
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_failed_writes() -> Result<(), ExecError> {
+        // the partial write of the operation stores its transaction only
+        let rule = FaultRule::new(Some(StorageOp::PutAccount), Fault::PartialWrite);
+        let out = Shared::default();
+        let log = Arc::new(Mutex::new(AuditLog::new(out.clone())));
+        let faulty = FaultyLedger::new(HashLedger::new(), vec![rule]);
+        let mut ledger = AuditLedger::new(faulty, log, "tester");
+        assert!(ledger.deposit(Client(1), TxId(1), dec!(5)).is_err());
+        let replay = verify(out.0.lock().unwrap().as_slice())?;
+        assert_eq!(replay.entries, 4);
+        assert_eq!(replay.accounts[&Client(1)], None);
+        replay.check_ledger(&ledger)?;
+
+        // the write is not done when it can not be logged
+        let log = Arc::new(Mutex::new(AuditLog::new(Broken)));
+        let mut ledger = AuditLedger::new(HashLedger::new(), log, "tester");
+        assert!(ledger.deposit(Client(1), TxId(1), dec!(5)).is_err());
+        assert_eq!(ledger.get_transaction(TxId(1))?, None);
+        assert_eq!(ledger.get_account(Client(1))?, None);
+        Ok(())
+    }
+}
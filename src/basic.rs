@@ -1,12 +1,26 @@
 use crate::common::*;
 use core::default::Default;
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug)]
 pub struct HashLedger {
     transactions: HashMap<TxId, Transaction>,
     accounts: HashMap<Client, Account>,
+    recorded: HashMap<String, Recorded>,
     policy: Policy,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for HashLedger {
+    fn default() -> Self {
+        Self {
+            transactions: Default::default(),
+            accounts: Default::default(),
+            recorded: Default::default(),
+            policy: Default::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
 }
 
 impl HashLedger {
@@ -21,6 +35,10 @@ impl HashLedger {
             ..Default::default()
         }
     }
+    /// The ledger taking the current time of the time rules from the clock
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self { clock, ..self }
+    }
 }
 
 impl Ledger for HashLedger {
@@ -49,10 +67,33 @@ impl Ledger for HashLedger {
     fn policy(&self) -> Policy {
         self.policy
     }
+    fn now(&self) -> Timestamp {
+        self.clock.now()
+    }
+    fn remove_account(&mut self, client: Client) -> Result<(), std::io::Error> {
+        self.accounts.remove(&client);
+        Ok(())
+    }
+    fn remove_transaction(&mut self, tx_id: TxId) -> Result<(), std::io::Error> {
+        self.transactions.remove(&tx_id);
+        Ok(())
+    }
+    fn get_recorded(&self, key: &str) -> Result<Option<Recorded>, std::io::Error> {
+        Ok(self.recorded.get(key).cloned())
+    }
+    fn put_recorded(&mut self, key: &str, recorded: Recorded) -> Result<(), std::io::Error> {
+        self.recorded.insert(key.to_string(), recorded);
+        Ok(())
+    }
+    fn recorded<'q>(&'q self) -> Box<dyn Iterator<Item = IterResult<(String, Recorded)>> + 'q> {
+        Box::new(self.recorded.iter().map(|v| Ok((v.0.clone(), v.1.clone()))))
+    }
 }
 
 #[cfg(test)]
 use crate::libcsv::{execute_csv, validate_accounts, ExecError};
+#[cfg(test)]
+use rust_decimal::Decimal;
 
 #[cfg(test)]
 pub const TRANSACTIONS: &str = r#"# CSV sample
@@ -101,3 +142,16 @@ fn test_csv_processing() -> Result<(), ExecError> {
     execute_csv(std::io::Cursor::new(TRANSACTIONS.as_bytes()), &mut ledger)?;
     validate_accounts(std::io::Cursor::new(ACCOUNTS.as_bytes()), &ledger)
 }
+
+#[test]
+fn test_amount_overflow() -> Result<(), ExecError> {
+    let mut ledger = HashLedger::new();
+    ledger.deposit(Client(1), TxId(1), Decimal::MAX)?;
+    assert!(matches!(
+        ledger.deposit(Client(1), TxId(2), Decimal::ONE),
+        Err(TxError::Rejected(_))
+    ));
+    assert_eq!(ledger.get_account(Client(1))?.unwrap().total, Decimal::MAX);
+    assert!(ledger.get_transaction(TxId(2))?.is_none());
+    Ok(())
+}
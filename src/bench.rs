@@ -0,0 +1,284 @@
+use crate::{
+    advanced::sharded_execute_csv,
+    common::*,
+    libcsv::{execute_csv, ExecError},
+    routing::ShardRouter,
+};
+use rust_decimal::Decimal;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Ledger recording the latency of every transaction operation
+pub struct Timed<L> {
+    pub inner: L,
+    pub latencies: Vec<Duration>,
+}
+
+impl<L> Timed<L> {
+    pub fn new(inner: L) -> Self {
+        Self {
+            inner,
+            latencies: Vec::new(),
+        }
+    }
+    fn timed<T>(&mut self, op: impl FnOnce(&mut L) -> T) -> T {
+        let started = Instant::now();
+        let res = op(&mut self.inner);
+        self.latencies.push(started.elapsed());
+        res
+    }
+}
+
+impl<L: Ledger> Ledger for Timed<L> {
+    fn policy(&self) -> Policy {
+        self.inner.policy()
+    }
+    fn now(&self) -> Timestamp {
+        self.inner.now()
+    }
+    fn get_account(&self, client: Client) -> Result<Option<Account>, std::io::Error> {
+        self.inner.get_account(client)
+    }
+    fn put_account(&mut self, client: Client, account: Account) -> Result<(), std::io::Error> {
+        self.inner.put_account(client, account)
+    }
+    fn accounts<'q>(&'q self) -> Box<dyn Iterator<Item = IterResult<(Client, Account)>> + 'q> {
+        self.inner.accounts()
+    }
+    fn get_transaction(&self, tx_id: TxId) -> Result<Option<Transaction>, std::io::Error> {
+        self.inner.get_transaction(tx_id)
+    }
+    fn put_transaction(&mut self, tx_id: TxId, tx: Transaction) -> Result<(), std::io::Error> {
+        self.inner.put_transaction(tx_id, tx)
+    }
+    fn transactions<'q>(
+        &'q self,
+    ) -> Box<dyn Iterator<Item = IterResult<(TxId, Transaction)>> + 'q> {
+        self.inner.transactions()
+    }
+    fn put_account_transaction(
+        &mut self,
+        client: Client,
+        account: Account,
+        tx_id: TxId,
+        tx: Transaction,
+    ) -> Result<(), std::io::Error> {
+        self.inner
+            .put_account_transaction(client, account, tx_id, tx)
+    }
+    fn put_account_transaction_recorded(
+        &mut self,
+        client: Client,
+        account: Account,
+        tx_id: TxId,
+        tx: Transaction,
+        key: &str,
+        recorded: Recorded,
+    ) -> Result<(), std::io::Error> {
+        self.inner
+            .put_account_transaction_recorded(client, account, tx_id, tx, key, recorded)
+    }
+    fn remove_account(&mut self, client: Client) -> Result<(), std::io::Error> {
+        self.inner.remove_account(client)
+    }
+    fn remove_transaction(&mut self, tx_id: TxId) -> Result<(), std::io::Error> {
+        self.inner.remove_transaction(tx_id)
+    }
+    fn get_recorded(&self, key: &str) -> Result<Option<Recorded>, std::io::Error> {
+        self.inner.get_recorded(key)
+    }
+    fn put_recorded(&mut self, key: &str, recorded: Recorded) -> Result<(), std::io::Error> {
+        self.inner.put_recorded(key, recorded)
+    }
+    fn recorded<'q>(&'q self) -> Box<dyn Iterator<Item = IterResult<(String, Recorded)>> + 'q> {
+        self.inner.recorded()
+    }
+    fn deposit_at(
+        &mut self,
+        client: Client,
+        tx_id: TxId,
+        amount: Decimal,
+        time: Option<Timestamp>,
+    ) -> Result<(), TxError> {
+        self.timed(|l| l.deposit_at(client, tx_id, amount, time))
+    }
+    fn withdrawal_at(
+        &mut self,
+        client: Client,
+        tx_id: TxId,
+        amount: Decimal,
+        time: Option<Timestamp>,
+    ) -> Result<(), TxError> {
+        self.timed(|l| l.withdrawal_at(client, tx_id, amount, time))
+    }
+    fn dispute_at(
+        &mut self,
+        client: Client,
+        tx_id: TxId,
+        time: Option<Timestamp>,
+    ) -> Result<(), TxError> {
+        self.timed(|l| l.dispute_at(client, tx_id, time))
+    }
+    fn resolve(&mut self, client: Client, tx_id: TxId) -> Result<(), TxError> {
+        self.timed(|l| l.resolve(client, tx_id))
+    }
+    fn chargeback(&mut self, client: Client, tx_id: TxId) -> Result<(), TxError> {
+        self.timed(|l| l.chargeback(client, tx_id))
+    }
+}
+
+/// Percentiles of operation latencies
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Latency {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Latency {
+    pub fn of(mut latencies: Vec<Duration>) -> Latency {
+        latencies.sort_unstable();
+        let at = |p: usize| match latencies.len() {
+            0 => Duration::ZERO,
+            n => latencies[(n * p / 100).min(n - 1)],
+        };
+        Latency {
+            p50: at(50),
+            p90: at(90),
+            p99: at(99),
+            max: latencies.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+/// Result of one benchmark run
+#[derive(Clone, Debug)]
+pub struct BenchResult {
+    pub name: String,
+    pub rows: usize,
+    pub elapsed: Duration,
+    /// Latency of operations applied to the ledger, without queueing
+    pub latency: Latency,
+}
+
+impl BenchResult {
+    pub fn rows_per_sec(&self) -> f64 {
+        self.rows as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+    pub fn header() -> String {
+        format!(
+            "{:<24} {:>10} {:>12} {:>12} {:>12} {:>12} {:>12}",
+            "benchmark", "rows", "rows/sec", "p50", "p90", "p99", "max"
+        )
+    }
+}
+
+impl std::fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:<24} {:>10} {:>12.0} {:>12.1?} {:>12.1?} {:>12.1?} {:>12.1?}",
+            self.name,
+            self.rows,
+            self.rows_per_sec(),
+            self.latency.p50,
+            self.latency.p90,
+            self.latency.p99,
+            self.latency.max
+        )
+    }
+}
+
+fn rows(csv: &[u8]) -> usize {
+    csv.iter()
+        .filter(|b| **b == b'\n')
+        .count()
+        .saturating_sub(1)
+}
+
+/// Runs `execute_csv` over the csv
+pub fn bench_serial(name: &str, csv: &[u8], ledger: impl Ledger) -> Result<BenchResult, ExecError> {
+    let mut ledger = Timed::new(ledger);
+    let started = Instant::now();
+    execute_csv(std::io::Cursor::new(csv), &mut ledger)?;
+    Ok(BenchResult {
+        name: name.into(),
+        rows: rows(csv),
+        elapsed: started.elapsed(),
+        latency: Latency::of(ledger.latencies),
+    })
+}
+
+/// Runs `sharded_execute_csv` over the csv with a shard per ledger
+pub fn bench_sharded<L: Ledger + Send + 'static>(
+    name: &str,
+    csv: &[u8],
+    ledgers: impl IntoIterator<Item = L>,
+    index: impl ShardRouter,
+) -> Result<BenchResult, ExecError> {
+    let timed: Vec<_> = ledgers
+        .into_iter()
+        .map(|l| Arc::new(Mutex::new(Timed::new(l))))
+        .collect();
+    if timed.is_empty() {
+        return Err(ExecError::StringError("no ledgers to shard".into()));
+    }
+    let sharding: Vec<_> = timed
+        .iter()
+        .map(|l| l.clone() as Arc<Mutex<dyn Ledger + Send>>)
+        .collect();
+    let started = Instant::now();
+    sharded_execute_csv(std::io::Cursor::new(csv), &sharding, index)?;
+    let elapsed = started.elapsed();
+    let latencies = timed
+        .iter()
+        .flat_map(|l| std::mem::take(&mut l.lock().unwrap().latencies))
+        .collect();
+    Ok(BenchResult {
+        name: name.into(),
+        rows: rows(csv),
+        elapsed,
+        latency: Latency::of(latencies),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        advanced::{index_by_client, SledLedger},
+        basic::HashLedger,
+        workload::{Workload, WorkloadOptions},
+    };
+
+    #[test]
+    fn test_bench_runs() -> Result<(), ExecError> {
+        let csv = Workload::new(WorkloadOptions {
+            rows: 1000,
+            ..Default::default()
+        })
+        .to_csv();
+        let serial = bench_serial("hash", &csv, HashLedger::new())?;
+        assert_eq!(serial.rows, 1000);
+        assert!(serial.latency.p50 <= serial.latency.p99);
+        let sled = SledLedger::new().unwrap();
+        let sharded = bench_sharded("sled", &csv, vec![sled; 3], index_by_client)?;
+        assert_eq!(sharded.rows, 1000);
+        assert!(sharded.latency.max > Duration::ZERO);
+        let none = bench_sharded("none", &csv, Vec::<HashLedger>::new(), index_by_client);
+        assert!(none.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_latency_percentiles() {
+        let latency = Latency::of((1..=100).rev().map(Duration::from_millis).collect());
+        assert_eq!(latency.p50, Duration::from_millis(51));
+        assert_eq!(latency.p99, Duration::from_millis(100));
+        assert_eq!(latency.max, Duration::from_millis(100));
+        assert_eq!(Latency::of(Vec::new()).max, Duration::ZERO);
+    }
+}
@@ -0,0 +1,101 @@
+use clap::Parser;
+use toybank::{
+    advanced::SledLedger,
+    basic::HashLedger,
+    bench::{bench_serial, bench_sharded, BenchResult},
+    libcsv::ExecError,
+    routing::Router,
+    workload::{Workload, WorkloadOptions},
+};
+
+#[derive(Parser, Debug)]
+struct Arguments {
+    /// Count of clients
+    #[clap(long, default_value_t = 1000)]
+    clients: u16,
+
+    /// Count of generated rows
+    #[clap(long, default_value_t = 100_000)]
+    rows: usize,
+
+    /// Share of rows disputing deposits, the same share closes disputes
+    #[clap(long, default_value_t = 0.01)]
+    dispute_rate: f64,
+
+    /// Share of closed disputes ending with chargeback
+    #[clap(long, default_value_t = 0.2)]
+    chargeback_rate: f64,
+
+    /// Share of rows repeating earlier deposits
+    #[clap(long, default_value_t = 0.001)]
+    duplicate_rate: f64,
+
+    /// Share of withdrawals
+    #[clap(long, default_value_t = 0.2)]
+    withdrawal_rate: f64,
+
+    /// Zipf exponent of clients activity, 0 is uniform
+    #[clap(long, default_value_t = 0.0)]
+    skew: f64,
+
+    /// Seed of the generator
+    #[clap(long, default_value_t = 1)]
+    seed: u64,
+
+    /// Comma separated counts of workers of sharded runs, 0 means count of vCPUs
+    #[clap(short = 'p', value_delimiter = ',', default_value = "4")]
+    workers: Vec<usize>,
+
+    /// Router of clients to workers: hash, modulo, jump, rendezvous
+    #[clap(long, default_value = "hash")]
+    router: Router,
+
+    /// Benchmark HashLedger only
+    #[clap(long)]
+    no_sled: bool,
+
+    /// Write the generated csv to the file instead of running benchmarks
+    #[clap(long)]
+    output: Option<String>,
+}
+
+fn main() -> Result<(), ExecError> {
+    let args = Arguments::parse();
+    let workload = Workload::new(WorkloadOptions {
+        clients: args.clients,
+        rows: args.rows,
+        dispute_rate: args.dispute_rate,
+        chargeback_rate: args.chargeback_rate,
+        duplicate_rate: args.duplicate_rate,
+        withdrawal_rate: args.withdrawal_rate,
+        skew: args.skew,
+        seed: args.seed,
+    });
+    if let Some(file) = &args.output {
+        return workload.write_csv(std::fs::File::create(file)?);
+    }
+    let csv = workload.to_csv();
+    let sled = || SledLedger::new().map_err(|e| ExecError::StringError(e.to_string()));
+    println!("{}", BenchResult::header());
+    println!("{}", bench_serial("hash serial", &csv, HashLedger::new())?);
+    if !args.no_sled {
+        println!("{}", bench_serial("sled serial", &csv, sled()?)?);
+    }
+    for n in args.workers {
+        let n = match n {
+            0 => std::thread::available_parallelism().unwrap().get(),
+            n => n,
+        };
+        let hash = (0..n).map(|_| HashLedger::new());
+        let name = format!("hash sharded -p {n}");
+        println!("{}", bench_sharded(&name, &csv, hash, &args.router)?);
+        if !args.no_sled {
+            let name = format!("sled sharded -p {n}");
+            println!(
+                "{}",
+                bench_sharded(&name, &csv, vec![sled()?; n], &args.router)?
+            );
+        }
+    }
+    Ok(())
+}
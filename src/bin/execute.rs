@@ -1,84 +1,679 @@
-use clap::Parser;
+use clap::{error::ErrorKind, Args, CommandFactory, Parser, Subcommand};
+#[cfg(feature = "repl")]
+use rustyline::{
+    completion::Completer, error::ReadlineError, history::DefaultHistory, Context, Editor, Helper,
+    Highlighter, Hinter, Validator,
+};
+#[cfg(feature = "repl")]
+use std::{cell::RefCell, rc::Rc};
 use std::{
     path::Path,
     sync::{Arc, Mutex},
+    time::Duration,
 };
+#[cfg(feature = "repl")]
+use toybank::repl::{self, Session};
 use toybank::{
-    advanced::{index_by_client, sharded_dump_accounts, sharded_execute_csv_file, SledLedger},
+    advanced::{
+        sharded_dump_accounts_with, sharded_execute_source_with, sharded_restore, sharded_snapshot,
+        ErrorMode, ShardedOptions, SledLedger,
+    },
+    audit::{execute_source_audited, verify, AuditLedger, AuditLog},
     basic::HashLedger,
-    common::{Ledger, Policy},
-    libcsv::{dump_accounts, execute_csv_file, ExecError},
+    common::{Client, Ledger, Policy, TxId},
+    dialect::Dialect,
+    invariants::{check_state, repair},
+    libcsv::{
+        dump_accounts_with, dump_transactions, execute_source, validate_accounts, write_accounts,
+        write_transactions, Column, DumpOptions, ExecError, Rounding,
+    },
+    pipeline::{ParallelCsvSource, ParallelOptions},
+    routing::Router,
+    snapshot::{export_ledger_file, import_ledger_file, Snapshot},
+    source::{CsvSource, TxSource},
+    stats::LedgerStats,
 };
 
-#[derive(Parser, Default, Debug)]
+/// Processes transactions and operates the persistent ledger
+#[derive(Parser, Debug)]
+#[command(name = "execute")]
 struct Arguments {
-    /// CSV file containing transactions
-    input_file: String,
+    #[command(flatten)]
+    ledger: LedgerArgs,
+
+    /// CSV file containing transactions, `execute FILE` is `execute run FILE`
+    input_file: Option<String>,
 
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Ledger selection shared by all commands
+#[derive(Args, Default, Debug)]
+struct LedgerArgs {
     /// Count of workers to process transactions, 0 means count of vCPUs
-    #[clap(short = 'p')]
+    #[clap(short = 'p', global = true)]
     concurrency: Option<usize>,
 
     /// Allow negative balance for disputes
-    #[clap(short = 'n')]
+    #[clap(short = 'n', global = true)]
     allow_negative_dispute: bool,
 
+    /// Reject disputes later than the days after the deposit
+    #[clap(long, global = true)]
+    dispute_window_days: Option<u64>,
+
+    /// Deposits older than the days can not be disputed, `finalize` finalizes them
+    #[clap(long, global = true)]
+    finalize_after_days: Option<u64>,
+
     /// Persistent ledger name, or `inmem` to use inmem SledDB, otherwise hashtable is used
-    #[clap(long)]
+    #[clap(long, global = true)]
     ledger: Option<String>,
 
-    /// Drop ledger content on start)
-    #[clap(long = "drop")]
+    /// Drop ledger content on start, with `run`, `import` and `repl` only
+    #[clap(long = "drop", global = true)]
     drop_on_start: bool,
+
+    /// Router of clients to workers: hash, modulo, jump, rendezvous,
+    /// persistent ledger keeps the router it has been processed with
+    #[clap(long, global = true)]
+    router: Option<Router>,
+
+    /// Pin the client to the worker, CLIENT:WORKER, can be repeated
+    #[clap(long, value_parser = parse_pin, global = true)]
+    pin: Vec<(Client, usize)>,
+
+    /// Append the writes of the command to the hash-chained audit log file,
+    /// `run` processes transactions serially
+    #[clap(long, global = true)]
+    audit: Option<String>,
+
+    /// Actor id recorded in the audit log, `$USER` by default
+    #[clap(long, global = true)]
+    actor: Option<String>,
 }
 
-fn main() -> Result<(), ExecError> {
-    let args = Arguments::parse();
-    let policy = Policy {
-        allow_negative_balance_for_dispute: args.allow_negative_dispute,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Process transactions of the csv file and dump accounts
+    Run(RunArgs),
+    /// Compare accounts of the ledger with the csv file of accounts
+    Validate {
+        /// CSV file containing accounts
+        accounts_file: String,
+    },
+    /// Dump accounts of the ledger
+    DumpAccounts(DumpArgs),
+    /// Dump transactions of the ledger
+    DumpTransactions {
+        /// Dump transactions sorted by id
+        #[clap(long)]
+        sorted: bool,
+    },
+    /// Print the account of the client
+    Account {
+        client: u16,
+        #[command(flatten)]
+        dump: DumpArgs,
+    },
+    /// Print the transaction
+    Tx { tx: u32 },
+    /// Complete operations interrupted by crashes and check invariants of the ledger
+    Repair,
+    /// Print counts of accounts and transactions and sums of balances
+    Stats,
+    /// Finalize deposits older than --finalize-after-days
+    Finalize,
+    /// Save the ledger state to the snapshot file
+    Export { file: String },
+    /// Load the ledger state from the snapshot file
+    Import { file: String },
+    /// Replay the audit log checking its hash chain, and compare it with the persistent
+    /// ledger when one is given
+    VerifyAudit { file: String },
+    /// Inspect and operate the ledger interactively, `help` lists commands
+    #[cfg(feature = "repl")]
+    Repl {
+        /// File to load and save the command history
+        #[clap(long)]
+        history: Option<String>,
+    },
+}
+
+impl Command {
+    /// Commands that can start with the dropped ledger, the others only read
+    ///   or complete the ledger
+    fn drops(&self) -> bool {
+        match self {
+            Command::Run(_) | Command::Import { .. } => true,
+            #[cfg(feature = "repl")]
+            Command::Repl { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct RunArgs {
+    /// CSV file containing transactions
+    input_file: String,
+
+    /// Dialect of the input csv, TOML or JSON file
+    #[clap(long)]
+    dialect: Option<String>,
+
+    /// Snapshot file to load the ledger state from before processing
+    #[clap(long)]
+    import: Option<String>,
+
+    /// Snapshot file to save the ledger state to after processing
+    #[clap(long)]
+    export: Option<String>,
+
+    /// Process all rows collecting failures instead of stopping on the first one,
+    /// per shard summary is printed to stderr
+    #[clap(long)]
+    collect_errors: bool,
+
+    /// Print per worker load statistics and hot workers to stderr
+    #[clap(long)]
+    stats: bool,
+
+    #[command(flatten)]
+    dump: DumpArgs,
+}
+
+impl RunArgs {
+    /// Processing of the file with default options
+    fn new(input_file: String) -> Self {
+        Self {
+            input_file,
+            dialect: None,
+            import: None,
+            export: None,
+            collect_errors: false,
+            stats: false,
+            dump: DumpArgs {
+                sorted: false,
+                scale: None,
+                rounding: Rounding::default(),
+                columns: Vec::new(),
+            },
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct DumpArgs {
+    /// Dump accounts sorted by client
+    #[clap(long)]
+    sorted: bool,
+
+    /// Dump decimals with the fixed count of decimal places
+    #[clap(long)]
+    scale: Option<u32>,
+
+    /// Rounding for the fixed scale: half-even, half-up, half-down, up, down, floor, ceiling
+    #[clap(long, default_value = "half-even")]
+    rounding: Rounding,
+
+    /// Comma separated columns to dump: client, available, held, total, locked
+    #[clap(long, value_delimiter = ',')]
+    columns: Vec<Column>,
+}
+
+impl DumpArgs {
+    fn options(self) -> DumpOptions {
+        DumpOptions {
+            sorted: self.sorted,
+            scale: self.scale,
+            rounding: self.rounding,
+            columns: match self.columns.is_empty() {
+                true => Column::ALL.to_vec(),
+                false => self.columns,
+            },
+        }
+    }
+}
+
+fn parse_pin(s: &str) -> Result<(Client, usize), String> {
+    let bad = || format!("`{s}` is not CLIENT:WORKER");
+    let (client, shard) = s.split_once(':').ok_or_else(bad)?;
+    Ok((
+        Client(client.trim().parse().map_err(|_| bad())?),
+        shard.trim().parse().map_err(|_| bad())?,
+    ))
+}
+
+impl LedgerArgs {
+    fn policy(&self) -> Policy {
+        let days = |n: u64| Duration::from_secs(n * 24 * 60 * 60);
+        Policy {
+            allow_negative_balance_for_dispute: self.allow_negative_dispute,
+            dispute_window: self.dispute_window_days.map(days),
+            finalize_after: self.finalize_after_days.map(days),
+        }
+    }
+    fn concurrency(&self) -> usize {
+        match self.concurrency {
+            Some(0) => std::thread::available_parallelism().unwrap().get(),
+            Some(n) => n,
+            None => 1,
+        }
+    }
+    fn router(&self) -> Result<Option<Router>, ExecError> {
+        let router = match (&self.router, self.pin.is_empty()) {
+            (None, true) => return Ok(None),
+            (router, _) => self
+                .pin
+                .iter()
+                .fold(router.clone().unwrap_or_default(), |r, (c, s)| {
+                    r.pin(*c, *s)
+                }),
+        };
+        router.validate(self.concurrency())?;
+        Ok(Some(router))
+    }
+    /// Opens the persistent ledger, `None` is the hashtable ledger
+    fn open(&self) -> Result<Option<SledLedger>, ExecError> {
+        let policy = self.policy();
+        let ledger = match &self.ledger {
+            None => return Ok(None),
+            Some(name) if name == "inmem" => SledLedger::new_empty(None, policy),
+            Some(name) => match self.drop_on_start {
+                true => SledLedger::new_empty(Some(name.clone()), policy),
+                _ => SledLedger::open(name.clone(), policy),
+            },
+        };
+        ledger
+            .map(Some)
+            .map_err(|e| ExecError::StringError(e.to_string()))
+    }
+    /// Opens the persistent ledger the command requires
+    fn open_persistent(&self) -> Result<SledLedger, ExecError> {
+        self.open()?.ok_or_else(|| {
+            ExecError::StringError("the command requires a persistent --ledger".into())
+        })
+    }
+    /// Opens the audit log the writes are appended to
+    fn audit(&self) -> Result<Option<Audit>, ExecError> {
+        let Some(file) = &self.audit else {
+            return Ok(None);
+        };
+        Ok(Some(Audit {
+            log: Arc::new(Mutex::new(AuditLog::open(file)?)),
+            actor: self
+                .actor
+                .clone()
+                .or_else(|| std::env::var("USER").ok())
+                .unwrap_or_else(|| "unknown".into()),
+        }))
+    }
+}
+
+#[cfg(feature = "repl")]
+type ReplSession = Rc<RefCell<Session<Box<dyn Ledger>>>>;
+
+/// Completes client and tx ids of the ledger
+#[cfg(feature = "repl")]
+#[derive(Helper, Highlighter, Hinter, Validator)]
+struct ReplHelper(ReplSession);
+
+#[cfg(feature = "repl")]
+impl Completer for ReplHelper {
+    type Candidate = String;
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.0.borrow().complete(line, pos))
+    }
+}
+
+#[cfg(feature = "repl")]
+fn run_repl(ledger: Box<dyn Ledger>, history: Option<String>) -> Result<(), ExecError> {
+    let readline_error = |e: ReadlineError| ExecError::StringError(e.to_string());
+    let session = Rc::new(RefCell::new(Session::new(ledger)));
+    let mut editor: Editor<ReplHelper, DefaultHistory> = Editor::new().map_err(readline_error)?;
+    editor.set_helper(Some(ReplHelper(session.clone())));
+    if let Some(file) = &history {
+        // the history file does not exist on the first run
+        let _ = editor.load_history(file);
+    }
+    loop {
+        let line = match editor.readline("toybank> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(readline_error(e)),
+        };
+        let command = match repl::parse(&line) {
+            Ok(Some(repl::Command::Quit)) => break,
+            Ok(Some(command)) => command,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("{e}");
+                continue;
+            }
+        };
+        let _ = editor.add_history_entry(line.as_str());
+        match session.borrow_mut().run(command, &line) {
+            Ok(out) => println!("{out}"),
+            Err(e) => eprintln!("error: {e}"),
+        }
+    }
+    if let Some(file) = &history {
+        editor.save_history(file).map_err(readline_error)?;
+    }
+    Ok(())
+}
+
+/// Executes transactions with shards, returns count of failed rows
+fn execute_sharded(
+    src: impl TxSource,
+    sharding: &[Arc<Mutex<dyn Ledger + Send>>],
+    router: &Router,
+    collect_errors: bool,
+    stats: bool,
+) -> Result<usize, ExecError> {
+    let errors = match collect_errors {
+        true => ErrorMode::Collect,
+        false => ErrorMode::FailFast,
     };
+    let report = sharded_execute_source_with(
+        src,
+        sharding,
+        router,
+        &ShardedOptions {
+            errors,
+            ..Default::default()
+        },
+    )?;
+    if collect_errors {
+        eprint!("{report}");
+    }
+    if stats {
+        eprint!("{}", report.stats_table());
+    }
+    Ok(report.errors().count())
+}
+
+/// Audit log and actor the writes are recorded with
+struct Audit {
+    log: Arc<Mutex<AuditLog>>,
+    actor: String,
+}
+
+impl Audit {
+    fn ledger<L: Ledger>(&self, ledger: L) -> AuditLedger<L> {
+        AuditLedger::new(ledger, self.log.clone(), self.actor.clone())
+    }
+}
+
+/// Runs the command with the ledger, appending its writes to the audit log when it is given
+fn audited<L: Ledger, T>(
+    mut ledger: L,
+    audit: Option<&Audit>,
+    command: impl FnOnce(&mut dyn Ledger) -> Result<T, ExecError>,
+) -> Result<T, ExecError> {
+    match audit {
+        None => command(&mut ledger),
+        Some(audit) => command(&mut audit.ledger(ledger)),
+    }
+}
+
+/// Imports the snapshot and executes transactions serially,
+///   appending the writes to the audit log when it is given
+fn execute_serial<L: Ledger>(
+    src: impl TxSource,
+    ledger: L,
+    input: &str,
+    import: Option<&str>,
+    audit: Option<&Audit>,
+) -> Result<L, ExecError> {
+    match audit {
+        None => {
+            let mut ledger = ledger;
+            if let Some(file) = import {
+                import_ledger_file(file, &mut ledger)?;
+            }
+            execute_source(src, &mut ledger)?;
+            Ok(ledger)
+        }
+        Some(audit) => {
+            let mut ledger = audit.ledger(ledger);
+            if let Some(file) = import {
+                import_ledger_file(file, &mut ledger)?;
+            }
+            execute_source_audited(src, &mut ledger, input)?;
+            Ok(ledger.into_inner())
+        }
+    }
+}
+
+/// Processes the csv file, returns count of failed rows
+fn run(ledger_args: &LedgerArgs, args: RunArgs) -> Result<usize, ExecError> {
+    let policy = ledger_args.policy();
     let path = Path::new(&args.input_file);
-    let concurrency = match args.concurrency {
-        Some(0) => std::thread::available_parallelism().unwrap().get(),
-        Some(n) => n,
-        None => 1,
+    let dump = args.dump.options();
+    let dialect = match &args.dialect {
+        Some(file) => Dialect::from_file(file)?,
+        None => Dialect::default(),
+    };
+    let source = || CsvSource::with_dialect(&dialect, std::fs::File::open(path)?);
+    // sharded execution parses the csv in parallel
+    let parallel_source = || {
+        let opts = ParallelOptions::default();
+        ParallelCsvSource::new(&dialect, std::fs::File::open(path)?, &opts)
     };
-    match args.ledger {
+    let concurrency = ledger_args.concurrency();
+    let sharded = concurrency > 1 || args.collect_errors || args.stats;
+    if sharded && ledger_args.audit.is_some() {
+        return Err(ExecError::StringError(
+            "--audit processes serially, it excludes -p, --collect-errors and --stats".into(),
+        ));
+    }
+    let audit = ledger_args.audit()?;
+    let router = ledger_args.router()?;
+    let failed = match ledger_args.open()? {
         // SledDb
-        Some(name) => {
-            let mut ledger = if name == "inmem" {
-                SledLedger::new_empty(None, policy)
-            } else {
-                match args.drop_on_start {
-                    true => SledLedger::new_empty(Some(name), policy),
-                    _ => SledLedger::open(name, policy),
+        Some(mut ledger) => {
+            let failed = if sharded {
+                if let Some(file) = &args.import {
+                    import_ledger_file(file, &mut ledger)?;
                 }
-            }
-            .map_err(|e| ExecError::StringError(e.to_string()))?;
-            if concurrency > 1 {
+                let router = ledger.open_router(router)?;
+                router.validate(concurrency)?;
                 let sharding = ledger.sharding(concurrency);
-                sharded_execute_csv_file(path, &sharding, index_by_client)
+                execute_sharded(
+                    parallel_source()?,
+                    &sharding,
+                    &router,
+                    args.collect_errors,
+                    args.stats,
+                )?
             } else {
-                execute_csv_file(path, &mut ledger)
-            }?;
-            dump_accounts(std::io::stdout(), &ledger)
+                execute_serial(
+                    source()?,
+                    ledger.clone(),
+                    &args.input_file,
+                    args.import.as_deref(),
+                    audit.as_ref(),
+                )?;
+                0
+            };
+            if let Some(file) = &args.export {
+                export_ledger_file(file, &ledger)?;
+            }
+            dump_accounts_with(std::io::stdout(), &ledger, &dump)?;
+            failed
         }
         // HashMap
         None => {
-            if concurrency > 1 {
-                let sharding = (0..concurrency)
+            if sharded {
+                let router = router.unwrap_or_default();
+                let sharding: Vec<_> = (0..concurrency)
                     .map(|_| {
                         Arc::new(Mutex::new(HashLedger::with_policy(policy)))
                             as Arc<Mutex<dyn Ledger + Send>>
                     })
                     .collect();
-                sharded_execute_csv_file(path, &sharding, index_by_client)?;
-                sharded_dump_accounts(std::io::stdout(), &sharding, index_by_client)
+                if let Some(file) = &args.import {
+                    let snapshot = Snapshot::read(std::fs::File::open(file)?)?;
+                    sharded_restore(&snapshot, &sharding, &router)?;
+                }
+                let failed = execute_sharded(
+                    parallel_source()?,
+                    &sharding,
+                    &router,
+                    args.collect_errors,
+                    args.stats,
+                )?;
+                if let Some(file) = &args.export {
+                    sharded_snapshot(&sharding, &router)?.write(std::fs::File::create(file)?)?;
+                }
+                sharded_dump_accounts_with(std::io::stdout(), &sharding, &router, &dump)?;
+                failed
             } else {
-                let mut ledger = HashLedger::with_policy(policy);
-                execute_csv_file(path, &mut ledger)?;
-                dump_accounts(std::io::stdout(), &ledger)
+                let ledger = execute_serial(
+                    source()?,
+                    HashLedger::with_policy(policy),
+                    &args.input_file,
+                    args.import.as_deref(),
+                    audit.as_ref(),
+                )?;
+                if let Some(file) = &args.export {
+                    export_ledger_file(file, &ledger)?;
+                }
+                dump_accounts_with(std::io::stdout(), &ledger, &dump)?;
+                0
             }
         }
+    };
+    Ok(failed)
+}
+
+fn main() -> Result<(), ExecError> {
+    let args = Arguments::parse();
+    let command = match (args.command, args.input_file) {
+        (Some(command), None) => command,
+        (Some(_), Some(input_file)) => Arguments::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                format!("the input file `{input_file}` can not be given with a command"),
+            )
+            .exit(),
+        (None, Some(input_file)) => Command::Run(RunArgs::new(input_file)),
+        (None, None) => Arguments::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "the input file or a command is required",
+            )
+            .exit(),
+    };
+    if args.ledger.drop_on_start && !command.drops() {
+        Arguments::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--drop can be given with run, import and repl only",
+            )
+            .exit()
+    }
+    let not_found = |what: String| ExecError::StringError(format!("{what} not found"));
+    let failed = match command {
+        Command::Run(run_args) => run(&args.ledger, run_args)?,
+        Command::Validate { accounts_file } => {
+            let ledger = args.ledger.open_persistent()?;
+            validate_accounts(std::fs::File::open(accounts_file)?, &ledger)?;
+            0
+        }
+        Command::DumpAccounts(dump) => {
+            let ledger = args.ledger.open_persistent()?;
+            dump_accounts_with(std::io::stdout(), &ledger, &dump.options())?;
+            0
+        }
+        Command::DumpTransactions { sorted } => {
+            let ledger = args.ledger.open_persistent()?;
+            dump_transactions(std::io::stdout(), &ledger, sorted)?;
+            0
+        }
+        Command::Account { client, dump } => {
+            let ledger = args.ledger.open_persistent()?;
+            let acc = ledger
+                .get_account(Client(client))?
+                .ok_or_else(|| not_found(format!("account {client}")))?;
+            let accounts = std::iter::once(Ok((Client(client), acc)));
+            write_accounts(std::io::stdout(), accounts, &dump.options())?;
+            0
+        }
+        Command::Tx { tx } => {
+            let ledger = args.ledger.open_persistent()?;
+            let found = ledger
+                .get_transaction(TxId(tx))?
+                .ok_or_else(|| not_found(format!("transaction {tx}")))?;
+            write_transactions(std::io::stdout(), std::iter::once(Ok((TxId(tx), found))))?;
+            0
+        }
+        Command::Repair => {
+            let ledger = args.ledger.open_persistent()?;
+            let audit = args.ledger.audit()?;
+            for fixed in audited(ledger.clone(), audit.as_ref(), repair)? {
+                println!("{fixed}");
+            }
+            check_state(&ledger).map_err(ExecError::StringError)?;
+            0
+        }
+        Command::Stats => {
+            let ledger = args.ledger.open_persistent()?;
+            print!("{}", LedgerStats::of(&ledger)?);
+            0
+        }
+        Command::Finalize => {
+            let ledger = args.ledger.open_persistent()?;
+            let audit = args.ledger.audit()?;
+            let finalized = audited(ledger, audit.as_ref(), |l| Ok(l.finalize_expired()?))?;
+            println!("{finalized} deposits finalized");
+            0
+        }
+        Command::Export { file } => {
+            let ledger = args.ledger.open_persistent()?;
+            export_ledger_file(file, &ledger)?;
+            0
+        }
+        Command::Import { file } => {
+            let ledger = args.ledger.open_persistent()?;
+            let audit = args.ledger.audit()?;
+            audited(ledger, audit.as_ref(), |l| import_ledger_file(file, l))?;
+            0
+        }
+        Command::VerifyAudit { file } => {
+            let replay = verify(std::io::BufReader::new(std::fs::File::open(file)?))?;
+            if let Some(ledger) = args.ledger.open()? {
+                replay.check_ledger(&ledger)?;
+            }
+            println!("{} entries, last hash {}", replay.entries, replay.last);
+            0
+        }
+        #[cfg(feature = "repl")]
+        Command::Repl { history } => {
+            let ledger: Box<dyn Ledger> = match args.ledger.open()? {
+                Some(ledger) => Box::new(ledger),
+                None => Box::new(HashLedger::with_policy(args.ledger.policy())),
+            };
+            // the writes of the session, its undos included, are audited
+            let ledger: Box<dyn Ledger> = match args.ledger.audit()? {
+                Some(audit) => Box::new(audit.ledger(ledger)),
+                None => ledger,
+            };
+            run_repl(ledger, history)?;
+            0
+        }
+    };
+    match failed {
+        0 => Ok(()),
+        n => Err(ExecError::StringError(format!("{n} rows failed"))),
     }
 }
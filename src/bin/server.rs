@@ -0,0 +1,254 @@
+use clap::Parser;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+#[cfg(feature = "events")]
+use toybank::events::{last_seq, EventBus, EventLedger, JsonLines};
+#[cfg(feature = "grpc")]
+use toybank::grpc;
+#[cfg(feature = "ingest")]
+use toybank::ingest::{self, serve_tcp};
+#[cfg(feature = "notify")]
+use toybank::notify::{Notification, Notifier, Rule, Sink};
+use toybank::{
+    advanced::SledLedger,
+    basic::HashLedger,
+    common::{Ledger, Policy},
+    libcsv::ExecError,
+    routing::Router,
+    server::serve,
+    service::LedgerService,
+};
+
+#[derive(Parser, Debug)]
+struct Arguments {
+    /// Address to listen on
+    #[clap(long, default_value = "127.0.0.1:8080")]
+    listen: String,
+
+    /// Address to serve the gRPC API on
+    #[cfg(feature = "grpc")]
+    #[clap(long)]
+    grpc: Option<String>,
+
+    /// Address to accept csv request lines on
+    #[cfg(feature = "ingest")]
+    #[clap(long)]
+    ingest: Option<String>,
+
+    /// Unix socket path to accept csv request lines on, replaced when exists
+    #[cfg(all(unix, feature = "ingest"))]
+    #[clap(long)]
+    ingest_socket: Option<std::path::PathBuf>,
+
+    /// Count of shards serializing requests of their clients, 0 means count of vCPUs
+    #[clap(short = 'p', default_value_t = 4)]
+    concurrency: usize,
+
+    /// Allow negative balance for disputes
+    #[clap(short = 'n')]
+    allow_negative_dispute: bool,
+
+    /// Reject disputes later than the days after the deposit
+    #[clap(long)]
+    dispute_window_days: Option<u64>,
+
+    /// Deposits older than the days can not be disputed
+    #[clap(long)]
+    finalize_after_days: Option<u64>,
+
+    /// Persistent ledger name, or `inmem` to use inmem SledDB, otherwise hashtable is used
+    #[clap(long)]
+    ledger: Option<String>,
+
+    /// Drop ledger content on start)
+    #[clap(long = "drop")]
+    drop_on_start: bool,
+
+    /// Router of clients to shards: hash, modulo, jump, rendezvous,
+    /// persistent ledger keeps the router it has been processed with
+    #[clap(long)]
+    router: Option<Router>,
+
+    /// File to append ledger events to as JSON lines, continuing its sequence numbers
+    #[cfg(feature = "events")]
+    #[clap(long)]
+    events: Option<String>,
+
+    /// Address to stream ledger events to connected subscribers on
+    #[cfg(feature = "events")]
+    #[clap(long)]
+    events_listen: Option<String>,
+
+    /// Rule of events to notify about: account-locked, dispute-opened, balance-below:AMOUNT,
+    /// can be repeated
+    #[cfg(feature = "notify")]
+    #[clap(long)]
+    notify: Vec<Rule>,
+
+    /// Url to POST notifications to
+    #[cfg(feature = "notify")]
+    #[clap(long)]
+    notify_url: Option<String>,
+
+    /// Count of retries of failed POSTs
+    #[cfg(feature = "notify")]
+    #[clap(long, default_value_t = 3)]
+    notify_retries: usize,
+
+    /// File to append notifications failed all POST attempts to
+    #[cfg(feature = "notify")]
+    #[clap(long)]
+    notify_dead_letter: Option<std::path::PathBuf>,
+
+    /// Shell command run with every notification on its stdin
+    #[cfg(feature = "notify")]
+    #[clap(long)]
+    notify_command: Option<String>,
+
+    /// Print notifications to stdout
+    #[cfg(feature = "notify")]
+    #[clap(long)]
+    notify_stdout: bool,
+}
+
+/// Events are published to the bus with the `events` feature only
+#[cfg(feature = "events")]
+type Bus = EventBus;
+#[cfg(not(feature = "events"))]
+type Bus = ();
+
+/// The shard of the ledger publishing to the bus
+fn shard<L: Ledger + Send + 'static>(ledger: L, bus: &Bus) -> Arc<Mutex<dyn Ledger + Send>> {
+    #[cfg(feature = "events")]
+    let ledger = EventLedger::new(ledger, bus.clone());
+    #[cfg(not(feature = "events"))]
+    let _ = bus;
+    Arc::new(Mutex::new(ledger))
+}
+
+/// The bus of the events file, subscribers and notifications of the arguments
+#[cfg(feature = "events")]
+fn event_bus(args: &Arguments) -> Result<EventBus, ExecError> {
+    let bus = EventBus::starting_at(match &args.events {
+        Some(file) => last_seq(file)? + 1,
+        None => 1,
+    });
+    if let Some(file) = &args.events {
+        bus.subscribe(JsonLines::file(file)?);
+    }
+    if let Some(addr) = &args.events_listen {
+        let listener = std::net::TcpListener::bind(addr)?;
+        eprintln!("streaming events on {}", listener.local_addr()?);
+        bus.serve_subscribers(listener);
+    }
+    #[cfg(feature = "notify")]
+    if !args.notify.is_empty() {
+        let mut sinks = Vec::new();
+        if let Some(url) = args.notify_url.clone() {
+            sinks.push(Sink::Http {
+                url,
+                retries: args.notify_retries,
+                backoff: Duration::from_millis(100),
+                dead_letter: args.notify_dead_letter.clone(),
+            });
+        }
+        if let Some(cmd) = args.notify_command.clone() {
+            sinks.push(Sink::Command(cmd));
+        }
+        if args.notify_stdout {
+            sinks.push(Sink::Stdout);
+        }
+        let failed =
+            |n: &Notification, e| eprintln!("notification {} is not delivered: {e}", n.event.seq);
+        Notifier::new(args.notify.clone(), sinks).spawn(bus.bounded_channel(), failed);
+    }
+    Ok(bus)
+}
+
+#[cfg(feature = "ingest")]
+fn connection_failed(e: ExecError) {
+    eprintln!("connection failed: {e}");
+}
+
+fn days(n: u64) -> Duration {
+    Duration::from_secs(n * 24 * 60 * 60)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), ExecError> {
+    let args = Arguments::parse();
+    let policy = Policy {
+        allow_negative_balance_for_dispute: args.allow_negative_dispute,
+        dispute_window: args.dispute_window_days.map(days),
+        finalize_after: args.finalize_after_days.map(days),
+    };
+    let concurrency = match args.concurrency {
+        0 => std::thread::available_parallelism().unwrap().get(),
+        n => n,
+    };
+    #[cfg(feature = "events")]
+    let bus = event_bus(&args)?;
+    #[cfg(not(feature = "events"))]
+    let bus = ();
+    let (sharding, router) = match args.ledger {
+        // SledDb
+        Some(name) => {
+            let ledger = match (name.as_str(), args.drop_on_start) {
+                ("inmem", _) => SledLedger::new_empty(None, policy),
+                (_, true) => SledLedger::new_empty(Some(name), policy),
+                _ => SledLedger::open(name, policy),
+            }
+            .map_err(|e| ExecError::StringError(e.to_string()))?;
+            let router = ledger.open_router(args.router)?;
+            (
+                (0..concurrency)
+                    .map(|_| shard(ledger.clone(), &bus))
+                    .collect(),
+                router,
+            )
+        }
+        // HashMap
+        None => (
+            (0..concurrency)
+                .map(|_| shard(HashLedger::with_policy(policy), &bus))
+                .collect(),
+            args.router.unwrap_or_default(),
+        ),
+    };
+    let service = LedgerService::new(sharding, router)?;
+    #[cfg(feature = "grpc")]
+    if let Some(addr) = args.grpc {
+        let listener = tokio::net::TcpListener::bind(&addr).await?;
+        eprintln!("serving gRPC on {}", listener.local_addr()?);
+        tokio::spawn(grpc::serve(listener, service.clone()));
+    }
+    #[cfg(feature = "ingest")]
+    if let Some(addr) = args.ingest {
+        let listener = tokio::net::TcpListener::bind(&addr).await?;
+        eprintln!("ingesting on {}", listener.local_addr()?);
+        tokio::spawn(serve_tcp(
+            listener,
+            service.clone(),
+            Default::default(),
+            connection_failed,
+        ));
+    }
+    #[cfg(all(unix, feature = "ingest"))]
+    if let Some(path) = args.ingest_socket {
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        eprintln!("ingesting on {}", path.display());
+        tokio::spawn(ingest::serve_unix(
+            listener,
+            service.clone(),
+            Default::default(),
+            connection_failed,
+        ));
+    }
+    let listener = tokio::net::TcpListener::bind(&args.listen).await?;
+    eprintln!("listening on {}", listener.local_addr()?);
+    serve(listener, service).await?;
+    Ok(())
+}
@@ -1,6 +1,11 @@
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
+use std::{
+    fmt::Debug,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use thiserror::Error;
 
 #[derive(Copy, Clone, Default, PartialEq, Debug, Eq, Hash, Serialize, Deserialize)]
@@ -15,7 +20,149 @@ impl From<u32> for Client {
 pub struct TxId(pub u32);
 impl From<u32> for TxId {
     fn from(v: u32) -> Self {
-        TxId(v as u32)
+        TxId(v)
+    }
+}
+
+/// Seconds since the Unix epoch
+#[derive(
+    Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Debug, Hash, Serialize, Deserialize,
+)]
+pub struct Timestamp(pub u64);
+
+impl Timestamp {
+    /// The timestamp the duration later
+    pub fn after(self, d: Duration) -> Timestamp {
+        Timestamp(self.0.saturating_add(d.as_secs()))
+    }
+}
+
+impl std::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Parses seconds since the epoch or RFC 3339 time,
+///   `2024-01-31T12:00:00Z` or `2024-01-31T14:00:00+02:00`
+impl FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let bad = || format!("bad time `{s}`");
+        if let Ok(secs) = s.parse() {
+            return Ok(Timestamp(secs));
+        }
+        let num = |from: usize, to: usize| -> Result<i64, String> {
+            let digits = s.get(from..to).ok_or_else(bad)?;
+            match digits.bytes().all(|b| b.is_ascii_digit()) {
+                true => digits.parse().map_err(|_| bad()),
+                false => Err(bad()),
+            }
+        };
+        let b = s.as_bytes();
+        if b.len() < 20
+            || (b[4], b[7], b[13], b[16]) != (b'-', b'-', b':', b':')
+            || !b"Tt ".contains(&b[10])
+        {
+            return Err(bad());
+        }
+        let (year, month, day) = (num(0, 4)?, num(5, 7)?, num(8, 10)?);
+        let (hour, minute, second) = (num(11, 13)?, num(14, 16)?, num(17, 19)?);
+        // a leap second is counted as the last second of its minute
+        if !(1..=12).contains(&month)
+            || !(1..=days_in_month(year, month)).contains(&day)
+            || hour > 23
+            || minute > 59
+            || second > 60
+        {
+            return Err(bad());
+        }
+        // fractions of seconds are dropped
+        let mut zone = 19;
+        if b[zone] == b'.' {
+            zone += 1;
+            while zone < b.len() && b[zone].is_ascii_digit() {
+                zone += 1;
+            }
+        }
+        let offset = match &s[zone..] {
+            "Z" | "z" => 0,
+            z if z.len() == 6 && b"+-".contains(&b[zone]) && b[zone + 3] == b':' => {
+                let (hours, minutes) = (num(zone + 1, zone + 3)?, num(zone + 4, zone + 6)?);
+                if hours > 23 || minutes > 59 {
+                    return Err(bad());
+                }
+                let offset = hours * 3600 + minutes * 60;
+                if b[zone] == b'+' {
+                    offset
+                } else {
+                    -offset
+                }
+            }
+            _ => return Err(bad()),
+        };
+        let secs =
+            days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second.min(59)
+                - offset;
+        u64::try_from(secs).map(Timestamp).map_err(|_| bad())
+    }
+}
+
+/// Number of days in the month of the proleptic Gregorian year
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of the proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Source of the current time, the tests inject the `ManualClock`
+pub trait Clock: Send + Sync + Debug {
+    fn now(&self) -> Timestamp;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        let since = SystemTime::now().duration_since(UNIX_EPOCH);
+        Timestamp(since.map(|d| d.as_secs()).unwrap_or_default())
+    }
+}
+
+/// Clock moved by hand
+#[derive(Debug, Default)]
+pub struct ManualClock(AtomicU64);
+
+impl ManualClock {
+    pub fn new(now: Timestamp) -> Self {
+        Self(AtomicU64::new(now.0))
+    }
+    pub fn set(&self, now: Timestamp) {
+        self.0.store(now.0, Ordering::SeqCst);
+    }
+    pub fn advance(&self, d: Duration) {
+        self.0.fetch_add(d.as_secs(), Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Timestamp {
+        Timestamp(self.0.load(Ordering::SeqCst))
     }
 }
 
@@ -33,7 +180,7 @@ pub enum TxError {
     Empty,
 }
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum TxType {
     #[serde(rename = "deposit")]
     Deposit,
@@ -47,7 +194,7 @@ pub enum TxType {
     Chargeback,
 }
 
-#[derive(Copy, Clone, Default, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
 pub struct Account {
     pub available: Decimal,
     pub total: Decimal,
@@ -64,21 +211,88 @@ pub enum TxState {
     Cancelled, // the transaction amount is not longer count in client account
 }
 
-#[derive(Copy, Clone, Default, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
 pub struct Transaction {
     pub client: Client,
     pub amount: Decimal,
     pub state: TxState,
+    /// Time of the deposit or withdrawal request
+    #[serde(default)]
+    pub time: Option<Timestamp>,
 }
 
 #[derive(Clone, Copy, Debug, Default)]
 pub struct Policy {
     pub allow_negative_balance_for_dispute: bool,
+    /// Disputes later than the window after the deposit are rejected
+    pub dispute_window: Option<Duration>,
+    /// Deposits older than this by the clock of the ledger can not be disputed,
+    ///   `Ledger::finalize_expired` finalizes them
+    pub finalize_after: Option<Duration>,
+}
+
+/// Result of the transaction request accepted by the ledger
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Outcome {
+    Applied,
+    Rejected(String),
+    Ignored(String),
+}
+
+impl Outcome {
+    /// Splits the ledger result into the outcome and the failure
+    pub fn of(res: Result<(), TxError>) -> Result<Outcome, TxError> {
+        match res {
+            Ok(_) => Ok(Outcome::Applied),
+            Err(TxError::Rejected(e)) => Ok(Outcome::Rejected(e)),
+            Err(TxError::Ignored(e)) => Ok(Outcome::Ignored(e)),
+            Err(e) => Err(e),
+        }
+    }
+    /// The ledger result the outcome is split from
+    pub fn into_result(self) -> Result<(), TxError> {
+        match self {
+            Outcome::Applied => Ok(()),
+            Outcome::Rejected(e) => Err(TxError::Rejected(e)),
+            Outcome::Ignored(e) => Err(TxError::Ignored(e)),
+        }
+    }
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Applied => write!(f, "applied"),
+            Outcome::Rejected(e) => write!(f, "rejected: {e}"),
+            Outcome::Ignored(e) => write!(f, "ignored: {e}"),
+        }
+    }
+}
+
+/// Operation applied under an idempotency key with its outcome,
+///   the operation tells a retry from a reuse of the key by another request
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Recorded {
+    pub tx_type: TxType,
+    pub client: Client,
+    pub tx_id: TxId,
+    pub amount: Option<Decimal>,
+    pub outcome: Outcome,
 }
 
 pub type IterResult<T> = Result<T, std::io::Error>;
+
+/// Checked balance arithmetic, the overflowed balance rejects the transaction
+fn checked(v: Option<Decimal>) -> Result<Decimal, TxError> {
+    v.ok_or_else(|| TxError::Rejected("amount overflow".to_string()))
+}
+
 pub trait Ledger {
     fn policy(&self) -> Policy;
+    /// Current time of the time rules, disputes without time are at the current time
+    fn now(&self) -> Timestamp {
+        SystemClock.now()
+    }
     fn get_account(&self, client: Client) -> Result<Option<Account>, std::io::Error>;
     fn put_account(&mut self, client: Client, account: Account) -> Result<(), std::io::Error>;
     fn accounts<'q>(&'q self) -> Box<dyn Iterator<Item = IterResult<(Client, Account)>> + 'q>;
@@ -87,7 +301,84 @@ pub trait Ledger {
     fn transactions<'q>(&'q self)
         -> Box<dyn Iterator<Item = IterResult<(TxId, Transaction)>> + 'q>;
 
+    /// Puts the account and the transaction changed by one operation,
+    ///   persistent ledgers override it to write both atomically
+    fn put_account_transaction(
+        &mut self,
+        client: Client,
+        account: Account,
+        tx_id: TxId,
+        tx: Transaction,
+    ) -> Result<(), std::io::Error> {
+        self.put_transaction(tx_id, tx)?;
+        self.put_account(client, account)
+    }
+    /// Puts the account and the transaction changed by one operation with its outcome
+    ///   recorded under the idempotency key, persistent ledgers write all of them atomically
+    fn put_account_transaction_recorded(
+        &mut self,
+        client: Client,
+        account: Account,
+        tx_id: TxId,
+        tx: Transaction,
+        key: &str,
+        recorded: Recorded,
+    ) -> Result<(), std::io::Error> {
+        self.put_account_transaction(client, account, tx_id, tx)?;
+        self.put_recorded(key, recorded)
+    }
+
+    /// Removes the account, used to undo operations that created it
+    fn remove_account(&mut self, _client: Client) -> Result<(), std::io::Error> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "the ledger can not remove accounts",
+        ))
+    }
+    /// Removes the transaction, used to undo operations that created it
+    fn remove_transaction(&mut self, _tx_id: TxId) -> Result<(), std::io::Error> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "the ledger can not remove transactions",
+        ))
+    }
+
+    /// The operation recorded under the idempotency key
+    fn get_recorded(&self, _key: &str) -> Result<Option<Recorded>, std::io::Error> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "the ledger has no idempotency keys",
+        ))
+    }
+    /// Records the operation under the idempotency key
+    fn put_recorded(&mut self, _key: &str, _recorded: Recorded) -> Result<(), std::io::Error> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "the ledger has no idempotency keys",
+        ))
+    }
+    /// The operations recorded under idempotency keys
+    fn recorded<'q>(&'q self) -> Box<dyn Iterator<Item = IterResult<(String, Recorded)>> + 'q> {
+        Box::new(std::iter::empty())
+    }
+
     fn deposit(&mut self, client: Client, tx_id: TxId, amount: Decimal) -> Result<(), TxError> {
+        self.deposit_at(client, tx_id, amount, None)
+    }
+    fn withdrawal(&mut self, client: Client, tx_id: TxId, amount: Decimal) -> Result<(), TxError> {
+        self.withdrawal_at(client, tx_id, amount, None)
+    }
+    fn dispute(&mut self, client: Client, tx_id: TxId) -> Result<(), TxError> {
+        self.dispute_at(client, tx_id, None)
+    }
+    /// Deposits storing the time of the request
+    fn deposit_at(
+        &mut self,
+        client: Client,
+        tx_id: TxId,
+        amount: Decimal,
+        time: Option<Timestamp>,
+    ) -> Result<(), TxError> {
         let opt_acc = self.get_account(client)?;
         if self.get_transaction(tx_id)?.is_some() {
             return Err(TxError::Ignored("duplicated transaction".to_string()));
@@ -97,32 +388,33 @@ pub trait Ledger {
                 return Err(TxError::Rejected("account is locked".to_string()));
             }
         }
-        self.put_transaction(
+        let acc = opt_acc.unwrap_or_default();
+        let acc = Account {
+            available: checked(acc.available.checked_add(amount))?,
+            total: checked(acc.total.checked_add(amount))?,
+            ..acc
+        };
+        self.put_account_transaction(
+            client,
+            acc,
             tx_id,
             Transaction {
                 client,
                 amount,
                 state: TxState::Committed,
-            },
-        )?;
-        self.put_account(
-            client,
-            match opt_acc {
-                Some(acc) => Account {
-                    available: amount + acc.available,
-                    total: amount + acc.total,
-                    ..acc
-                },
-                None => Account {
-                    available: amount,
-                    total: amount,
-                    ..Default::default()
-                },
+                time,
             },
         )?;
         Ok(())
     }
-    fn withdrawal(&mut self, client: Client, tx_id: TxId, amount: Decimal) -> Result<(), TxError> {
+    /// Withdraws storing the time of the request
+    fn withdrawal_at(
+        &mut self,
+        client: Client,
+        tx_id: TxId,
+        amount: Decimal,
+        time: Option<Timestamp>,
+    ) -> Result<(), TxError> {
         let opt_acc = self.get_account(client)?;
         match opt_acc {
             None => Err(TxError::Rejected("account does not exist".to_string())),
@@ -134,42 +426,59 @@ pub trait Ledger {
                 Err(TxError::Rejected("insufficient funds".to_string()))
             }
             Some(acc) => {
+                let acc = Account {
+                    available: checked(acc.available.checked_sub(amount))?,
+                    total: checked(acc.total.checked_sub(amount))?,
+                    ..acc
+                };
                 // store transaction for prevent double spending only,
                 // it can not be disputed
-                self.put_transaction(
+                self.put_account_transaction(
+                    client,
+                    acc,
                     tx_id,
                     Transaction {
                         client,
                         amount,
                         state: TxState::Finalized,
-                    },
-                )?;
-                self.put_account(
-                    client,
-                    Account {
-                        available: acc.available - amount,
-                        total: acc.total - amount,
-                        ..acc
+                        time,
                     },
                 )?;
                 Ok(())
             }
         }
     }
-    fn dispute(&mut self, client: Client, tx_id: TxId) -> Result<(), TxError> {
+    /// Disputes at the time of the request, the current time of the clock when it has none
+    fn dispute_at(
+        &mut self,
+        client: Client,
+        tx_id: TxId,
+        time: Option<Timestamp>,
+    ) -> Result<(), TxError> {
         let (tx, acc) = self.get_and_check_tx_acc(client, tx_id, TxState::Committed)?;
-        self.put_account(
+        let policy = self.policy();
+        if let Some(deposited) = tx.time {
+            if policy
+                .finalize_after
+                .is_some_and(|after| self.now() >= deposited.after(after))
+            {
+                return Err(TxError::Rejected("can not be disputed".to_string()));
+            }
+            let at = time.unwrap_or_else(|| self.now());
+            if policy
+                .dispute_window
+                .is_some_and(|window| at > deposited.after(window))
+            {
+                return Err(TxError::Rejected("dispute window has passed".to_string()));
+            }
+        }
+        self.put_account_transaction(
             client,
             Account {
-                available: acc.available - tx.amount,
-                held: acc.held + tx.amount,
+                available: checked(acc.available.checked_sub(tx.amount))?,
+                held: checked(acc.held.checked_add(tx.amount))?,
                 ..acc
             },
-        )?;
-        // TODO: this means any IO error place storage into "required to repair" state
-        // TODO: held/available may be corrected by summing dispute transactions after
-        // TODO: repair
-        self.put_transaction(
             tx_id,
             Transaction {
                 state: TxState::Disputed,
@@ -180,18 +489,13 @@ pub trait Ledger {
     }
     fn resolve(&mut self, client: Client, tx_id: TxId) -> Result<(), TxError> {
         let (tx, acc) = self.get_and_check_tx_acc(client, tx_id, TxState::Disputed)?;
-        self.put_account(
+        self.put_account_transaction(
             client,
             Account {
-                available: acc.available + tx.amount,
-                held: acc.held - tx.amount,
+                available: checked(acc.available.checked_add(tx.amount))?,
+                held: checked(acc.held.checked_sub(tx.amount))?,
                 ..acc
             },
-        )?;
-        // TODO: this means any IO error place storage into "requires repair" state
-        // TODO: held/available may be corrected by summing dispute transactions after
-        // TODO: repair
-        self.put_transaction(
             tx_id,
             Transaction {
                 // TODO: if it can be disputed again it must be TxState::Committed
@@ -203,19 +507,14 @@ pub trait Ledger {
     }
     fn chargeback(&mut self, client: Client, tx_id: TxId) -> Result<(), TxError> {
         let (tx, acc) = self.get_and_check_tx_acc(client, tx_id, TxState::Disputed)?;
-        self.put_account(
+        self.put_account_transaction(
             client,
             Account {
-                total: acc.total - tx.amount,
-                held: acc.held - tx.amount,
+                total: checked(acc.total.checked_sub(tx.amount))?,
+                held: checked(acc.held.checked_sub(tx.amount))?,
                 locked: true,
                 ..acc
             },
-        )?;
-        // TODO: this means any IO error place storage into "requires repair" state
-        // TODO: held/available may be corrected by summing dispute transactions after
-        // TODO: repair
-        self.put_transaction(
             tx_id,
             Transaction {
                 state: TxState::Cancelled,
@@ -224,6 +523,35 @@ pub trait Ledger {
         )?;
         Ok(())
     }
+    /// Finalizes the deposits older than `Policy::finalize_after` by the clock,
+    ///   returns the count of finalized deposits
+    fn finalize_expired(&mut self) -> Result<usize, TxError> {
+        let policy = self.policy();
+        let Some(after) = policy.finalize_after else {
+            return Ok(0);
+        };
+        let now = self.now();
+        let expired = self
+            .transactions()
+            .filter(|res| match res {
+                Ok((_, tx)) => {
+                    tx.state == TxState::Committed
+                        && tx.time.is_some_and(|time| now >= time.after(after))
+                }
+                Err(_) => true,
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for (tx_id, tx) in &expired {
+            self.put_transaction(
+                *tx_id,
+                Transaction {
+                    state: TxState::Finalized,
+                    ..*tx
+                },
+            )?;
+        }
+        Ok(expired.len())
+    }
     fn get_and_check_tx_acc(
         &self,
         client: Client,
@@ -267,3 +595,113 @@ pub trait Ledger {
         }
     }
 }
+
+/// Puts the account and the transaction changed by one operation to the ledger,
+///   with the outcome recorded under the idempotency key when it has one
+pub(crate) fn put_operation<L: Ledger + ?Sized>(
+    ledger: &mut L,
+    client: Client,
+    account: Account,
+    tx_id: TxId,
+    tx: Transaction,
+    recorded: Option<(&str, Recorded)>,
+) -> Result<(), std::io::Error> {
+    match recorded {
+        Some((key, recorded)) => {
+            ledger.put_account_transaction_recorded(client, account, tx_id, tx, key, recorded)
+        }
+        None => ledger.put_account_transaction(client, account, tx_id, tx),
+    }
+}
+
+/// Boxed ledgers are ledgers too, so decorators wrap them as well
+impl<L: Ledger + ?Sized> Ledger for Box<L> {
+    fn policy(&self) -> Policy {
+        (**self).policy()
+    }
+    fn now(&self) -> Timestamp {
+        (**self).now()
+    }
+    fn get_account(&self, client: Client) -> Result<Option<Account>, std::io::Error> {
+        (**self).get_account(client)
+    }
+    fn put_account(&mut self, client: Client, account: Account) -> Result<(), std::io::Error> {
+        (**self).put_account(client, account)
+    }
+    fn accounts<'q>(&'q self) -> Box<dyn Iterator<Item = IterResult<(Client, Account)>> + 'q> {
+        (**self).accounts()
+    }
+    fn get_transaction(&self, tx_id: TxId) -> Result<Option<Transaction>, std::io::Error> {
+        (**self).get_transaction(tx_id)
+    }
+    fn put_transaction(&mut self, tx_id: TxId, tx: Transaction) -> Result<(), std::io::Error> {
+        (**self).put_transaction(tx_id, tx)
+    }
+    fn transactions<'q>(
+        &'q self,
+    ) -> Box<dyn Iterator<Item = IterResult<(TxId, Transaction)>> + 'q> {
+        (**self).transactions()
+    }
+    fn put_account_transaction(
+        &mut self,
+        client: Client,
+        account: Account,
+        tx_id: TxId,
+        tx: Transaction,
+    ) -> Result<(), std::io::Error> {
+        (**self).put_account_transaction(client, account, tx_id, tx)
+    }
+    fn put_account_transaction_recorded(
+        &mut self,
+        client: Client,
+        account: Account,
+        tx_id: TxId,
+        tx: Transaction,
+        key: &str,
+        recorded: Recorded,
+    ) -> Result<(), std::io::Error> {
+        (**self).put_account_transaction_recorded(client, account, tx_id, tx, key, recorded)
+    }
+    fn remove_account(&mut self, client: Client) -> Result<(), std::io::Error> {
+        (**self).remove_account(client)
+    }
+    fn remove_transaction(&mut self, tx_id: TxId) -> Result<(), std::io::Error> {
+        (**self).remove_transaction(tx_id)
+    }
+    fn get_recorded(&self, key: &str) -> Result<Option<Recorded>, std::io::Error> {
+        (**self).get_recorded(key)
+    }
+    fn put_recorded(&mut self, key: &str, recorded: Recorded) -> Result<(), std::io::Error> {
+        (**self).put_recorded(key, recorded)
+    }
+    fn recorded<'q>(&'q self) -> Box<dyn Iterator<Item = IterResult<(String, Recorded)>> + 'q> {
+        (**self).recorded()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timestamp() {
+        let parse = |s: &str| s.parse::<Timestamp>().map(|t| t.0);
+        assert_eq!(parse("1704067200"), Ok(1704067200));
+        assert_eq!(parse("2024-01-01T00:00:00Z"), Ok(1704067200));
+        assert_eq!(parse("2024-01-01 02:00:00.25+02:00"), Ok(1704067200));
+        assert_eq!(parse("2000-03-01T00:00:00Z"), Ok(951868800));
+        assert_eq!(parse("1970-01-01T00:00:00-01:00"), Ok(3600));
+        assert!(parse("1969-12-31T23:59:59Z").is_err());
+        assert!(parse("2024-13-01T00:00:00Z").is_err());
+        assert_eq!(parse("2024-02-29T00:00:00Z"), Ok(1709164800));
+        assert!(parse("2023-02-29T00:00:00Z").is_err());
+        assert!(parse("2024-02-31T00:00:00Z").is_err());
+        assert!(parse("2024-04-31T00:00:00Z").is_err());
+        assert_eq!(parse("2016-12-31T23:59:60Z"), Ok(1483228799));
+        assert!(parse("2024-01-01T00:00:61Z").is_err());
+        assert!(parse("2024-01-01T00:00:99Z").is_err());
+        assert!(parse("2024-01-01T00:00:00+24:00").is_err());
+        assert!(parse("2024-01-01T00:00:00").is_err());
+        assert_eq!(parse("yesterday"), Err("bad time `yesterday`".into()));
+    }
+}
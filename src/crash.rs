@@ -0,0 +1,300 @@
+//! Crash-consistency harness of persistent ledgers
+//!
+//! `CrashingLedger` simulates the process crash at the Nth write:
+//!   the write and all following writes fail and nothing more reaches the inner ledger.
+//! `check_crash_consistency` crashes every operation at every write point,
+//!   reopens the ledger and requires the valid state before or after the operation.
+use crate::{
+    common::{Account, Client, IterResult, Ledger, Policy, Recorded, Timestamp, Transaction, TxId},
+    invariants::check_state,
+    libcsv::{apply_request, TxRequest},
+    snapshot::Snapshot,
+};
+use std::io::Error as IoError;
+
+/// Ledger failing at the `crash_at` write and after it, as a crashed process does
+pub struct CrashingLedger<L> {
+    inner: L,
+    crash_at: usize,
+    writes: usize,
+    /// Writes the account, the transaction and the recorded outcome of one operation
+    ///   as separate writes instead of the single write of the inner ledger
+    split: bool,
+}
+
+impl<L: Ledger> CrashingLedger<L> {
+    pub fn new(inner: L, crash_at: usize, split: bool) -> Self {
+        Self {
+            inner,
+            crash_at,
+            writes: 0,
+            split,
+        }
+    }
+    /// Number of attempted writes, including the failed ones
+    pub fn writes(&self) -> usize {
+        self.writes
+    }
+    pub fn crashed(&self) -> bool {
+        self.writes > self.crash_at
+    }
+    pub fn into_inner(self) -> L {
+        self.inner
+    }
+    fn write(&mut self) -> Result<(), IoError> {
+        self.writes += 1;
+        match self.crashed() {
+            true => Err(IoError::other("simulated crash")),
+            false => Ok(()),
+        }
+    }
+}
+
+impl<L: Ledger> Ledger for CrashingLedger<L> {
+    fn policy(&self) -> Policy {
+        self.inner.policy()
+    }
+    fn now(&self) -> Timestamp {
+        self.inner.now()
+    }
+    fn get_account(&self, client: Client) -> Result<Option<Account>, IoError> {
+        self.inner.get_account(client)
+    }
+    fn put_account(&mut self, client: Client, account: Account) -> Result<(), IoError> {
+        self.write()?;
+        self.inner.put_account(client, account)
+    }
+    fn accounts<'q>(&'q self) -> Box<dyn Iterator<Item = IterResult<(Client, Account)>> + 'q> {
+        self.inner.accounts()
+    }
+    fn get_transaction(&self, tx_id: TxId) -> Result<Option<Transaction>, IoError> {
+        self.inner.get_transaction(tx_id)
+    }
+    fn put_transaction(&mut self, tx_id: TxId, tx: Transaction) -> Result<(), IoError> {
+        self.write()?;
+        self.inner.put_transaction(tx_id, tx)
+    }
+    fn transactions<'q>(
+        &'q self,
+    ) -> Box<dyn Iterator<Item = IterResult<(TxId, Transaction)>> + 'q> {
+        self.inner.transactions()
+    }
+    fn put_account_transaction(
+        &mut self,
+        client: Client,
+        account: Account,
+        tx_id: TxId,
+        tx: Transaction,
+    ) -> Result<(), IoError> {
+        if self.split {
+            self.put_transaction(tx_id, tx)?;
+            return self.put_account(client, account);
+        }
+        self.write()?;
+        self.inner
+            .put_account_transaction(client, account, tx_id, tx)
+    }
+    fn put_account_transaction_recorded(
+        &mut self,
+        client: Client,
+        account: Account,
+        tx_id: TxId,
+        tx: Transaction,
+        key: &str,
+        recorded: Recorded,
+    ) -> Result<(), IoError> {
+        if self.split {
+            self.put_account_transaction(client, account, tx_id, tx)?;
+            return self.put_recorded(key, recorded);
+        }
+        self.write()?;
+        self.inner
+            .put_account_transaction_recorded(client, account, tx_id, tx, key, recorded)
+    }
+    fn remove_account(&mut self, client: Client) -> Result<(), IoError> {
+        self.write()?;
+        self.inner.remove_account(client)
+    }
+    fn remove_transaction(&mut self, tx_id: TxId) -> Result<(), IoError> {
+        self.write()?;
+        self.inner.remove_transaction(tx_id)
+    }
+    fn get_recorded(&self, key: &str) -> Result<Option<Recorded>, IoError> {
+        self.inner.get_recorded(key)
+    }
+    fn put_recorded(&mut self, key: &str, recorded: Recorded) -> Result<(), IoError> {
+        self.write()?;
+        self.inner.put_recorded(key, recorded)
+    }
+    fn recorded<'q>(&'q self) -> Box<dyn Iterator<Item = IterResult<(String, Recorded)>> + 'q> {
+        self.inner.recorded()
+    }
+}
+
+/// Crashes every request at every its write point and checks the reopened ledger
+///
+/// `create` opens the empty ledger, `reopen` opens the ledger left by the crashed one.
+/// Returns the number of checked crash points.
+pub fn check_crash_consistency<L: Ledger>(
+    requests: &[TxRequest],
+    mut create: impl FnMut() -> L,
+    mut reopen: impl FnMut() -> L,
+    split: bool,
+) -> Result<usize, String> {
+    let snapshot = |ledger: &dyn Ledger| Snapshot::from_ledger(ledger).map_err(|e| e.to_string());
+    // states after every request and write points of every request without crashes
+    let mut ledger = CrashingLedger::new(create(), usize::MAX, split);
+    let mut states = vec![snapshot(&ledger)?];
+    let mut writes = Vec::new();
+    for r in requests {
+        let before = ledger.writes();
+        let _ = apply_request(&mut ledger, r);
+        writes.push(ledger.writes() - before);
+        states.push(snapshot(&ledger)?);
+    }
+    drop(ledger);
+
+    let mut checked = 0;
+    for (k, r) in requests.iter().enumerate() {
+        for crash_at in 0..writes[k] {
+            let mut ledger = create();
+            for r in &requests[..k] {
+                let _ = apply_request(&mut ledger, r);
+            }
+            let mut ledger = CrashingLedger::new(ledger, crash_at, split);
+            let _ = apply_request(&mut ledger, r);
+            drop(ledger);
+
+            let ledger = reopen();
+            let fail = |e: String| format!("request {k} {r:?}, crash at write {crash_at}: {e}");
+            check_state(&ledger).map_err(fail)?;
+            let state = snapshot(&ledger)?;
+            if state != states[k] && state != states[k + 1] {
+                return Err(fail(format!(
+                    "partial state {state:?}, expected {:?} or {:?}",
+                    states[k],
+                    states[k + 1]
+                )));
+            }
+            checked += 1;
+        }
+    }
+    Ok(checked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        advanced::SledLedger,
+        common::{TxId, TxType},
+    };
+    use rust_decimal_macros::dec;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn request(tx_type: TxType, client: u16, tx_id: u32) -> TxRequest {
+        TxRequest {
+            tx_type,
+            client: Client(client),
+            tx_id: TxId(tx_id),
+            amount: match tx_type {
+                TxType::Deposit | TxType::Withdrawal => Some(dec!(1.5)),
+                _ => None,
+            },
+            key: None,
+            time: None,
+        }
+    }
+
+    fn requests() -> Vec<TxRequest> {
+        use TxType::*;
+        vec![
+            request(Deposit, 1, 1),
+            request(Deposit, 1, 2),
+            request(Withdrawal, 1, 3),
+            request(Dispute, 1, 1),
+            request(Resolve, 1, 1),
+            request(Dispute, 1, 2),
+            request(Chargeback, 1, 2),
+            request(Deposit, 2, 4),
+        ]
+    }
+
+    fn check_sled(split: bool) -> Result<usize, String> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "toybank-crash-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let path = path.to_string_lossy().to_string();
+        let policy = Policy::default();
+        // the flusher thread of the dropped db releases the file lock a bit later
+        let retry = |open: &dyn Fn() -> sled::Result<SledLedger>| {
+            for _ in 0..100 {
+                if let Ok(ledger) = open() {
+                    return ledger;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            open().unwrap()
+        };
+        let result = check_crash_consistency(
+            &requests(),
+            || retry(&|| SledLedger::new_empty(Some(path.clone()), policy)),
+            || retry(&|| SledLedger::open(path.clone(), policy)),
+            split,
+        );
+        let _ = std::fs::remove_dir_all(&path);
+        result
+    }
+
+    #[test]
+    fn test_split_writes_exposed() {
+        let e = check_sled(true).unwrap_err();
+        assert!(e.starts_with("request 0 "), "{e}");
+        assert!(e.contains("without account"), "{e}");
+    }
+
+    #[test]
+    fn test_atomic_writes_consistent() {
+        // every applied request of the sequence is a single write point
+        assert_eq!(check_sled(false), Ok(requests().len()));
+    }
+
+    #[test]
+    fn test_keyed_request_applied_once() {
+        let r = TxRequest {
+            key: Some("k1".into()),
+            ..request(TxType::Deposit, 1, 1)
+        };
+        let sled = || SledLedger::new_empty(None, Policy::default()).unwrap();
+        // the operation and its recorded outcome are a single write point
+        let mut ledger = CrashingLedger::new(sled(), usize::MAX, false);
+        apply_request(&mut ledger, &r).unwrap();
+        assert_eq!(ledger.writes(), 1);
+
+        let mut ledger = CrashingLedger::new(sled(), 0, false);
+        assert!(apply_request(&mut ledger, &r).is_err());
+        let mut ledger = ledger.into_inner();
+        assert_eq!(ledger.get_recorded("k1").unwrap(), None);
+        apply_request(&mut ledger, &r).unwrap();
+        apply_request(&mut ledger, &r).unwrap();
+        assert_eq!(
+            ledger.get_account(Client(1)).unwrap().unwrap().total,
+            dec!(1.5)
+        );
+        assert_eq!(ledger.recorded().count(), 1);
+    }
+
+    #[test]
+    fn test_crashing_ledger() {
+        let mut ledger = CrashingLedger::new(crate::basic::HashLedger::new(), 1, true);
+        assert!(apply_request(&mut ledger, &request(TxType::Deposit, 1, 1)).is_err());
+        assert!(ledger.crashed());
+        assert!(ledger.put_account(Client(2), Account::default()).is_err());
+        let ledger = ledger.into_inner();
+        assert!(ledger.get_transaction(TxId(1)).unwrap().is_some());
+        assert!(ledger.get_account(Client(1)).unwrap().is_none());
+    }
+}
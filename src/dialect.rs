@@ -0,0 +1,369 @@
+use crate::{
+    common::{Client, TxId, TxType},
+    libcsv::{ExecError, TxRequest},
+};
+use csv::StringRecord;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::Path, str::FromStr};
+
+/// Column of the input referenced by its header name or by its 0-based index
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ColumnRef {
+    Index(usize),
+    Name(String),
+}
+
+impl From<&str> for ColumnRef {
+    fn from(s: &str) -> Self {
+        ColumnRef::Name(s.into())
+    }
+}
+
+/// Mapping of input columns onto `TxRequest` fields
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ColumnMap {
+    #[serde(rename = "type")]
+    pub tx_type: ColumnRef,
+    pub client: ColumnRef,
+    #[serde(rename = "tx")]
+    pub tx_id: ColumnRef,
+    pub amount: ColumnRef,
+    /// Optional column of idempotency keys
+    pub key: ColumnRef,
+    /// Optional column of request times, seconds since the epoch or RFC 3339
+    pub time: ColumnRef,
+}
+
+impl Default for ColumnMap {
+    fn default() -> Self {
+        Self {
+            tx_type: "type".into(),
+            client: "client".into(),
+            tx_id: "tx".into(),
+            amount: "amount".into(),
+            key: "key".into(),
+            time: "time".into(),
+        }
+    }
+}
+
+/// Decimal format of the amount column
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DecimalFormat {
+    pub decimal_separator: char,
+    pub thousands_separator: Option<char>,
+}
+
+impl Default for DecimalFormat {
+    fn default() -> Self {
+        Self {
+            decimal_separator: '.',
+            thousands_separator: None,
+        }
+    }
+}
+
+impl DecimalFormat {
+    /// Parses the amount without trailing zeros, as the serde reader of `execute_csv` did,
+    ///   so `2.0` is dumped as `2`
+    pub fn parse(&self, s: &str) -> Option<Decimal> {
+        let s: String = s
+            .chars()
+            .filter(|c| Some(*c) != self.thousands_separator)
+            .map(|c| match c {
+                c if c == self.decimal_separator => '.',
+                c => c,
+            })
+            .collect();
+        Decimal::from_str(&s).ok().map(|d| d.normalize())
+    }
+}
+
+/// CSV dialect of the transactions input
+///
+/// The default dialect is the format `execute_csv` always read:
+///   `,` delimited, `#` comments, trimmed, with `type, client, tx, amount` header.
+/// A dialect can be loaded from TOML or JSON, every field is optional:
+/// ```toml
+/// delimiter = ";"
+/// decimal = { decimal_separator = ",", thousands_separator = "." }
+/// [columns]
+/// client = "client_id"
+/// tx = "tx_id"
+/// [types]
+/// payout = "withdrawal"
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Dialect {
+    pub delimiter: char,
+    pub comment: Option<char>,
+    pub quoting: bool,
+    pub quote: char,
+    pub escape: Option<char>,
+    pub trim: bool,
+    pub has_headers: bool,
+    pub columns: ColumnMap,
+    pub decimal: DecimalFormat,
+    /// Additional names of transaction types, e.g. `payout = "withdrawal"`
+    pub types: HashMap<String, String>,
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Self {
+            delimiter: ',',
+            comment: Some('#'),
+            quoting: true,
+            quote: '"',
+            escape: None,
+            trim: true,
+            has_headers: true,
+            columns: Default::default(),
+            decimal: Default::default(),
+            types: Default::default(),
+        }
+    }
+}
+
+impl Dialect {
+    pub fn from_toml(s: &str) -> Result<Dialect, ExecError> {
+        let dialect: Dialect =
+            toml::from_str(s).map_err(|e| ExecError::StringError(format!("bad dialect: {e}")))?;
+        dialect.validate()
+    }
+
+    pub fn from_json(s: &str) -> Result<Dialect, ExecError> {
+        let dialect: Dialect = serde_json::from_str(s)
+            .map_err(|e| ExecError::StringError(format!("bad dialect: {e}")))?;
+        dialect.validate()
+    }
+
+    /// Rejects the decimal format parsing amounts ambiguously
+    fn validate(self) -> Result<Dialect, ExecError> {
+        let decimal = &self.decimal;
+        if decimal.thousands_separator == Some(decimal.decimal_separator) {
+            return Err(ExecError::StringError(format!(
+                "bad dialect: `{}` is both the decimal and the thousands separator",
+                decimal.decimal_separator
+            )));
+        }
+        Ok(self)
+    }
+
+    /// Loads the dialect from `.json` file, any other file is read as TOML
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Dialect, ExecError> {
+        let text = std::fs::read_to_string(path.as_ref())?;
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("json") => Self::from_json(&text),
+            _ => Self::from_toml(&text),
+        }
+    }
+
+    pub fn reader_builder(&self) -> Result<csv::ReaderBuilder, ExecError> {
+        let byte = |c: char, what: &str| {
+            u8::try_from(c)
+                .map_err(|_| ExecError::StringError(format!("{what} `{c}` is not a single byte")))
+        };
+        let mut builder = csv::ReaderBuilder::new();
+        builder
+            .delimiter(byte(self.delimiter, "delimiter")?)
+            .comment(self.comment.map(|c| byte(c, "comment")).transpose()?)
+            .quoting(self.quoting)
+            .quote(byte(self.quote, "quote")?)
+            .escape(self.escape.map(|c| byte(c, "escape")).transpose()?)
+            .has_headers(self.has_headers)
+            .flexible(true)
+            .trim(match self.trim {
+                true => csv::Trim::All,
+                false => csv::Trim::None,
+            });
+        Ok(builder)
+    }
+
+    /// Resolves the column mapping against the header record
+    pub fn resolve(&self, headers: Option<&StringRecord>) -> Result<Columns, ExecError> {
+        let find = |c: &ColumnRef| match (c, headers) {
+            (ColumnRef::Index(i), _) => Some(*i),
+            (ColumnRef::Name(name), Some(h)) => h.iter().position(|x| x.trim() == name),
+            (ColumnRef::Name(_), None) => None,
+        };
+        let required = |c: &ColumnRef| {
+            find(c).ok_or_else(|| ExecError::StringError(format!("there is no column {c:?}")))
+        };
+        Ok(Columns {
+            tx_type: required(&self.columns.tx_type)?,
+            client: required(&self.columns.client)?,
+            tx_id: required(&self.columns.tx_id)?,
+            amount: find(&self.columns.amount),
+            key: find(&self.columns.key),
+            time: find(&self.columns.time),
+        })
+    }
+
+    pub fn tx_type(&self, s: &str) -> Option<TxType> {
+        let s = s.trim().to_lowercase();
+        let s = self.types.get(&s).map(|x| x.as_str()).unwrap_or(&s);
+        match s {
+            "deposit" => Some(TxType::Deposit),
+            "withdrawal" => Some(TxType::Withdrawal),
+            "dispute" => Some(TxType::Dispute),
+            "resolve" => Some(TxType::Resolve),
+            "chargeback" => Some(TxType::Chargeback),
+            _ => None,
+        }
+    }
+
+    /// Maps the csv record onto the transaction request
+    pub fn request(&self, columns: &Columns, record: &StringRecord) -> Result<TxRequest, String> {
+        let field = |i: usize| record.get(i).map(|x| x.trim()).unwrap_or("");
+        let tx_type = field(columns.tx_type);
+        let client = field(columns.client);
+        let tx_id = field(columns.tx_id);
+        let amount = columns.amount.map(field).unwrap_or("");
+        let key = columns.key.map(field).unwrap_or("");
+        let time = columns.time.map(field).unwrap_or("");
+        Ok(TxRequest {
+            tx_type: self
+                .tx_type(tx_type)
+                .ok_or_else(|| format!("unknown transaction type `{tx_type}`"))?,
+            client: Client(
+                client
+                    .parse()
+                    .map_err(|_| format!("bad client `{client}`"))?,
+            ),
+            tx_id: TxId(tx_id.parse().map_err(|_| format!("bad tx `{tx_id}`"))?),
+            amount: match amount {
+                "" => None,
+                a => Some(
+                    self.decimal
+                        .parse(a)
+                        .ok_or_else(|| format!("bad amount `{a}`"))?,
+                ),
+            },
+            key: (!key.is_empty()).then(|| key.to_string()),
+            time: match time {
+                "" => None,
+                t => Some(t.parse()?),
+            },
+        })
+    }
+}
+
+/// Indices of `TxRequest` fields in a csv record
+#[derive(Clone, Copy, Debug)]
+pub struct Columns {
+    pub tx_type: usize,
+    pub client: usize,
+    pub tx_id: usize,
+    pub amount: Option<usize>,
+    pub key: Option<usize>,
+    pub time: Option<usize>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        basic::HashLedger,
+        common::Ledger,
+        libcsv::execute_source,
+        source::{CsvSource, Position},
+    };
+    use rust_decimal_macros::dec;
+
+    const PARTNER: &str = r#"
+delimiter = ";"
+comment = "%"
+decimal = { decimal_separator = ",", thousands_separator = " " }
+[columns]
+type = "kind"
+client = "client_id"
+tx = "tx_id"
+amount = "sum"
+[types]
+payout = "withdrawal"
+"#;
+
+    #[test]
+    fn test_partner_dialect() -> Result<(), ExecError> {
+        let dialect = Dialect::from_toml(PARTNER)?;
+        let input = "\
+            client_id; tx_id; kind;     sum\n\
+            % a comment\n\
+            1;         1;     Deposit;  1 000,50\n\
+            1;         2;     payout;   0,5\n";
+        let mut ledger = HashLedger::new();
+        execute_source(
+            CsvSource::with_dialect(&dialect, std::io::Cursor::new(input))?,
+            &mut ledger,
+        )?;
+        let acc = ledger.get_account(Client(1))?.unwrap();
+        assert_eq!(acc.total, dec!(1000));
+        assert_eq!(acc.available, dec!(1000.00));
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_dialect_without_headers() -> Result<(), ExecError> {
+        let dialect = Dialect::from_json(
+            r#"{"has_headers": false, "columns": {"type": 0, "client": 1, "tx": 2, "amount": 3}}"#,
+        )?;
+        let mut src = CsvSource::with_dialect(&dialect, std::io::Cursor::new("deposit,7,3,2.5\n"))?;
+        let (pos, r) = src.next().unwrap()?;
+        assert_eq!(pos, Position { line: 1, record: 0 });
+        assert_eq!(
+            (r.client, r.tx_id, r.amount),
+            (Client(7), TxId(3), Some(dec!(2.5)))
+        );
+        assert!(src.next().is_none());
+        Ok(())
+    }
+
+    /// The output of the baseline serde reader
+    #[test]
+    fn test_default_dialect_output() -> Result<(), ExecError> {
+        let mut ledger = HashLedger::new();
+        execute_source(
+            CsvSource::new(std::fs::File::open("tests/test_tx_1.csv")?),
+            &mut ledger,
+        )?;
+        let mut out = Vec::new();
+        let opts = crate::libcsv::DumpOptions {
+            sorted: true,
+            ..Default::default()
+        };
+        crate::libcsv::dump_accounts_with(&mut out, &ledger, &opts)?;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n\
+            1,1.5,0,1.5,true\n\
+            2,2,0,2,false\n\
+            3,3,0,3,false\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_ambiguous_decimal_format() {
+        let same = "decimal = { decimal_separator = \".\", thousands_separator = \".\" }";
+        assert!(Dialect::from_toml(same).is_err());
+        let json = r#"{"decimal": {"decimal_separator": ",", "thousands_separator": ","}}"#;
+        assert!(Dialect::from_json(json).is_err());
+    }
+
+    #[test]
+    fn test_missing_column() {
+        let dialect = Dialect::from_toml("columns = { client = \"client_id\" }").unwrap();
+        let mut src = CsvSource::with_dialect(
+            &dialect,
+            std::io::Cursor::new("type,client,tx,amount\ndeposit,1,1,1\n"),
+        )
+        .unwrap();
+        assert!(matches!(src.next(), Some(Err(ExecError::StringError(_)))));
+    }
+}
@@ -0,0 +1,158 @@
+use crate::{
+    advanced::{sharded_execute_source_with, sharded_snapshot, ShardedOptions},
+    common::{Account, Client, Ledger, Transaction, TxId},
+    libcsv::{apply_request, ExecError, Outcome, TxRequest},
+    routing::ShardRouter,
+    snapshot::Snapshot,
+    source::requests_source,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+/// Everything observable of an execution: per row outcomes and the final state
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Run {
+    pub outcomes: Vec<Outcome>,
+    pub accounts: Vec<(Client, Account)>,
+    pub transactions: Vec<(TxId, Transaction)>,
+}
+
+impl Run {
+    fn with_snapshot(outcomes: Vec<Outcome>, snapshot: Snapshot) -> Run {
+        Run {
+            outcomes,
+            accounts: snapshot.accounts,
+            transactions: snapshot.transactions,
+        }
+    }
+}
+
+/// Applies the requests one by one with `apply_request`
+pub fn run_serial(ledger: &mut dyn Ledger, requests: &[TxRequest]) -> Result<Run, ExecError> {
+    let outcomes = requests
+        .iter()
+        .map(|r| Outcome::of(apply_request(ledger, r)))
+        .collect::<Result<_, _>>()?;
+    Ok(Run::with_snapshot(outcomes, Snapshot::from_ledger(ledger)?))
+}
+
+/// Applies the requests with the sharded executor
+pub fn run_sharded(
+    ledgers: &[Arc<Mutex<dyn Ledger + Send>>],
+    index: impl ShardRouter + Copy,
+    requests: &[TxRequest],
+) -> Result<Run, ExecError> {
+    let opts = ShardedOptions {
+        outcomes: true,
+        ..Default::default()
+    };
+    let src = requests_source(requests.to_vec());
+    let report = sharded_execute_source_with(src, ledgers, index, &opts)?;
+    let mut outcomes: Vec<_> = report.shards.into_iter().flat_map(|s| s.outcomes).collect();
+    outcomes.sort_by_key(|(position, _)| position.record);
+    let outcomes = outcomes.into_iter().map(|(_, outcome)| outcome).collect();
+    Ok(Run::with_snapshot(
+        outcomes,
+        sharded_snapshot(ledgers, index)?,
+    ))
+}
+
+/// Clients sharing a transaction id with another client
+///
+/// Transaction ids are unique within a shard only, so the sharded execution
+///   differs from the serial one for these clients: a deposit reusing the id
+///   of another shard is applied, a dispute of a transaction of another shard
+///   is rejected with another reason, and the shards race for the shared id.
+pub fn colliding_clients(requests: &[TxRequest]) -> HashSet<Client> {
+    let mut clients: HashMap<TxId, HashSet<Client>> = HashMap::new();
+    for r in requests {
+        clients.entry(r.tx_id).or_default().insert(r.client);
+    }
+    clients
+        .into_values()
+        .filter(|c| c.len() > 1)
+        .flatten()
+        .collect()
+}
+
+/// Describes the first difference of two runs over the same requests
+pub fn diff(requests: &[TxRequest], expected: &Run, actual: &Run) -> Option<String> {
+    diff_except(requests, expected, actual, &HashSet::new())
+}
+
+/// Like `diff`, the rows, accounts and transactions of the excluded clients are not compared
+pub fn diff_except(
+    requests: &[TxRequest],
+    expected: &Run,
+    actual: &Run,
+    excluded: &HashSet<Client>,
+) -> Option<String> {
+    let compared = |i: &usize| {
+        requests
+            .get(*i)
+            .is_none_or(|r| !excluded.contains(&r.client))
+    };
+    if let Some(i) = (0..expected.outcomes.len().max(actual.outcomes.len()))
+        .filter(compared)
+        .find(|i| expected.outcomes.get(*i) != actual.outcomes.get(*i))
+    {
+        return Some(format!(
+            "row {i} {:?}: expected {:?}, actual {:?}",
+            requests.get(i),
+            expected.outcomes.get(i),
+            actual.outcomes.get(i)
+        ));
+    }
+    let accounts = |run: &Run| {
+        run.accounts
+            .iter()
+            .filter(|(c, _)| !excluded.contains(c))
+            .copied()
+            .collect::<Vec<_>>()
+    };
+    let transactions = |run: &Run| {
+        run.transactions
+            .iter()
+            .filter(|(_, tx)| !excluded.contains(&tx.client))
+            .copied()
+            .collect::<Vec<_>>()
+    };
+    let (expected, actual) = (
+        Run {
+            outcomes: Vec::new(),
+            accounts: accounts(expected),
+            transactions: transactions(expected),
+        },
+        Run {
+            outcomes: Vec::new(),
+            accounts: accounts(actual),
+            transactions: transactions(actual),
+        },
+    );
+    if expected.accounts != actual.accounts {
+        return Some(format!(
+            "accounts: expected {:?}, actual {:?}",
+            expected.accounts, actual.accounts
+        ));
+    }
+    if expected.transactions != actual.transactions {
+        return Some(format!(
+            "transactions: expected {:?}, actual {:?}",
+            expected.transactions, actual.transactions
+        ));
+    }
+    None
+}
+
+/// Formats the requests as csv to reproduce a failure with `execute`
+pub fn to_csv(requests: &[TxRequest]) -> String {
+    let mut csv = String::from("type,client,tx,amount\n");
+    for r in requests {
+        let tx_type = format!("{:?}", r.tx_type).to_lowercase();
+        let amount = r.amount.map(|a| a.to_string()).unwrap_or_default();
+        csv += &format!("{tx_type},{},{},{amount}\n", r.client.0, r.tx_id.0);
+    }
+    csv
+}
@@ -0,0 +1,570 @@
+//! Change data capture, typed events of ledger writes with sequence numbers
+//!
+//! `EventLedger` decorates a ledger and publishes an event for every write to the
+//!   subscribers of its `EventBus`: a channel, a file of JSON lines or a socket.
+//! The bus is shared by the shards of a ledger, so sequence numbers are unique and
+//!   the events of a client are published in the order of its writes.
+//! Every sink is written by its own thread from a bounded queue, so a slow sink does not
+//!   block the writes of the ledger: a sink failing to take an event or letting its queue
+//!   fill up is unsubscribed.
+use crate::{common::*, libcsv::ExecError};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{BufRead, Write},
+    net::TcpListener,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Events queued for a sink before it is unsubscribed as too slow
+pub const SINK_QUEUE_LENGTH: usize = 1024;
+
+/// Time a socket subscriber has to take an event before it is unsubscribed
+pub const SOCKET_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What has been written to the ledger
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    DepositApplied {
+        client: Client,
+        tx: TxId,
+        amount: Decimal,
+        account: Account,
+    },
+    WithdrawalApplied {
+        client: Client,
+        tx: TxId,
+        amount: Decimal,
+        account: Account,
+    },
+    DisputeOpened {
+        client: Client,
+        tx: TxId,
+        amount: Decimal,
+        account: Account,
+    },
+    DisputeResolved {
+        client: Client,
+        tx: TxId,
+        amount: Decimal,
+        account: Account,
+    },
+    Chargeback {
+        client: Client,
+        tx: TxId,
+        amount: Decimal,
+        account: Account,
+    },
+    /// Follows the write locking the account
+    AccountLocked { client: Client, account: Account },
+    /// Account written apart from an operation, by import, repair or undo
+    AccountUpdated { client: Client, account: Account },
+    /// Transaction written apart from an operation
+    TransactionUpdated { tx: TxId, transaction: Transaction },
+    /// Account removed by undo
+    AccountRemoved { client: Client, account: Account },
+    /// Transaction removed by undo
+    TransactionRemoved { tx: TxId, transaction: Transaction },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// Consumer of published events
+pub trait EventSink: Send {
+    fn send(&mut self, event: &Event) -> std::io::Result<()>;
+}
+
+impl EventSink for crossbeam_channel::Sender<Event> {
+    fn send(&mut self, event: &Event) -> std::io::Result<()> {
+        crossbeam_channel::Sender::send(self, event.clone()).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, "the receiver is dropped")
+        })
+    }
+}
+
+/// Sink writing an event per line as JSON, to a file or a socket
+pub struct JsonLines<W>(pub W);
+
+impl JsonLines<std::fs::File> {
+    /// Appends to the file, creates it when it does not exist
+    pub fn file(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map(JsonLines)
+    }
+}
+
+impl<W: Write + Send> EventSink for JsonLines<W> {
+    fn send(&mut self, event: &Event) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        self.0.write_all(&line)?;
+        self.0.flush()
+    }
+}
+
+struct Bus {
+    next_seq: u64,
+    queues: Vec<crossbeam_channel::Sender<Event>>,
+}
+
+/// Subscribers of the events of one ledger
+#[derive(Clone)]
+pub struct EventBus(Arc<Mutex<Bus>>);
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::starting_at(1)
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The bus numbering events from `seq`, to continue the sequence of a previous run
+    pub fn starting_at(seq: u64) -> Self {
+        EventBus(Arc::new(Mutex::new(Bus {
+            next_seq: seq,
+            queues: Vec::new(),
+        })))
+    }
+
+    /// Subscribes the sink written by its thread from the queue of `SINK_QUEUE_LENGTH` events
+    pub fn subscribe(&self, mut sink: impl EventSink + 'static) {
+        let (s, r) = crossbeam_channel::bounded::<Event>(SINK_QUEUE_LENGTH);
+        std::thread::spawn(move || {
+            // the failed sink drops the queue, so the next event unsubscribes it
+            for event in r {
+                if sink.send(&event).is_err() {
+                    break;
+                }
+            }
+        });
+        self.0.lock().unwrap().queues.push(s);
+    }
+
+    /// Subscribes the unbounded channel, the dropped receiver unsubscribes it
+    pub fn channel(&self) -> crossbeam_channel::Receiver<Event> {
+        let (s, r) = crossbeam_channel::unbounded();
+        self.0.lock().unwrap().queues.push(s);
+        r
+    }
+
+    /// Subscribes the channel of `SINK_QUEUE_LENGTH` events, it is unsubscribed when the queue
+    ///   fills up or the receiver is dropped
+    pub fn bounded_channel(&self) -> crossbeam_channel::Receiver<Event> {
+        let (s, r) = crossbeam_channel::bounded(SINK_QUEUE_LENGTH);
+        self.0.lock().unwrap().queues.push(s);
+        r
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.0.lock().unwrap().queues.len()
+    }
+
+    /// Numbers the events and queues them for every subscriber
+    pub fn publish(&self, kinds: impl IntoIterator<Item = EventKind>) {
+        let mut bus = self.0.lock().unwrap();
+        for kind in kinds {
+            let event = Event {
+                seq: bus.next_seq,
+                kind,
+            };
+            bus.next_seq += 1;
+            bus.queues
+                .retain(|queue| queue.try_send(event.clone()).is_ok());
+        }
+    }
+
+    /// Subscribes connections of the listener until it fails, in the background thread,
+    ///   a connection not taking an event for `SOCKET_WRITE_TIMEOUT` is unsubscribed
+    pub fn serve_subscribers(&self, listener: TcpListener) -> std::thread::JoinHandle<()> {
+        let bus = self.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    break;
+                };
+                if stream.set_write_timeout(Some(SOCKET_WRITE_TIMEOUT)).is_ok() {
+                    bus.subscribe(JsonLines(stream));
+                }
+            }
+        })
+    }
+}
+
+/// Events of the JSON lines input
+pub fn read_events(rd: impl BufRead) -> impl Iterator<Item = Result<Event, ExecError>> {
+    rd.lines()
+        .map(|line| serde_json::from_str(&line?).map_err(|e| ExecError::StringError(e.to_string())))
+}
+
+/// Sequence number of the last event of the file, 0 when there is none
+pub fn last_seq(path: impl AsRef<Path>) -> Result<u64, ExecError> {
+    let f = match std::fs::File::open(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        f => f?,
+    };
+    let mut seq = 0;
+    for event in read_events(std::io::BufReader::new(f)) {
+        seq = event?.seq;
+    }
+    Ok(seq)
+}
+
+/// The operation changing the transaction from the `before` state
+fn operation(
+    before: Option<Transaction>,
+    tx_id: TxId,
+    tx: &Transaction,
+    account: Account,
+) -> Option<EventKind> {
+    use TxState::*;
+    let (client, amount) = (tx.client, tx.amount);
+    Some(match (before.map(|t| t.state), tx.state) {
+        (None, Committed) => EventKind::DepositApplied {
+            client,
+            tx: tx_id,
+            amount,
+            account,
+        },
+        (None, Finalized) => EventKind::WithdrawalApplied {
+            client,
+            tx: tx_id,
+            amount,
+            account,
+        },
+        (Some(Committed), Disputed) => EventKind::DisputeOpened {
+            client,
+            tx: tx_id,
+            amount,
+            account,
+        },
+        (Some(Disputed), Finalized) => EventKind::DisputeResolved {
+            client,
+            tx: tx_id,
+            amount,
+            account,
+        },
+        (Some(Disputed), Cancelled) => EventKind::Chargeback {
+            client,
+            tx: tx_id,
+            amount,
+            account,
+        },
+        _ => return None,
+    })
+}
+
+/// Ledger publishing its writes to the bus
+///
+/// The state before the write is read only while the bus has subscribers.
+pub struct EventLedger<L> {
+    inner: L,
+    bus: EventBus,
+}
+
+impl<L: Ledger> EventLedger<L> {
+    pub fn new(inner: L, bus: EventBus) -> Self {
+        Self { inner, bus }
+    }
+
+    pub fn bus(&self) -> &EventBus {
+        &self.bus
+    }
+
+    pub fn into_inner(self) -> L {
+        self.inner
+    }
+
+    /// The event of the write locking the account
+    fn locking(&self, client: Client, account: &Account) -> Result<Option<EventKind>, IoError> {
+        if !account.locked || self.inner.get_account(client)?.is_some_and(|a| a.locked) {
+            return Ok(None);
+        }
+        Ok(Some(EventKind::AccountLocked {
+            client,
+            account: *account,
+        }))
+    }
+
+    /// Puts the operation and publishes its events
+    fn put_operation(
+        &mut self,
+        client: Client,
+        account: Account,
+        tx_id: TxId,
+        tx: Transaction,
+        recorded: Option<(&str, Recorded)>,
+    ) -> Result<(), IoError> {
+        if self.bus.subscribers() == 0 {
+            return put_operation(&mut self.inner, client, account, tx_id, tx, recorded);
+        }
+        let before = self.inner.get_transaction(tx_id)?;
+        let locking = self.locking(client, &account)?;
+        put_operation(&mut self.inner, client, account, tx_id, tx, recorded)?;
+        let kind = operation(before, tx_id, &tx, account)
+            .unwrap_or(EventKind::AccountUpdated { client, account });
+        self.bus.publish(std::iter::once(kind).chain(locking));
+        Ok(())
+    }
+}
+
+type IoError = std::io::Error;
+
+impl<L: Ledger> Ledger for EventLedger<L> {
+    fn policy(&self) -> Policy {
+        self.inner.policy()
+    }
+    fn now(&self) -> Timestamp {
+        self.inner.now()
+    }
+    fn get_account(&self, client: Client) -> Result<Option<Account>, IoError> {
+        self.inner.get_account(client)
+    }
+    fn put_account(&mut self, client: Client, account: Account) -> Result<(), IoError> {
+        if self.bus.subscribers() == 0 {
+            return self.inner.put_account(client, account);
+        }
+        let locking = self.locking(client, &account)?;
+        self.inner.put_account(client, account)?;
+        let updated = EventKind::AccountUpdated { client, account };
+        self.bus.publish(std::iter::once(updated).chain(locking));
+        Ok(())
+    }
+    fn accounts<'q>(&'q self) -> Box<dyn Iterator<Item = IterResult<(Client, Account)>> + 'q> {
+        self.inner.accounts()
+    }
+    fn get_transaction(&self, tx_id: TxId) -> Result<Option<Transaction>, IoError> {
+        self.inner.get_transaction(tx_id)
+    }
+    fn put_transaction(&mut self, tx_id: TxId, tx: Transaction) -> Result<(), IoError> {
+        self.inner.put_transaction(tx_id, tx)?;
+        if self.bus.subscribers() > 0 {
+            self.bus.publish([EventKind::TransactionUpdated {
+                tx: tx_id,
+                transaction: tx,
+            }]);
+        }
+        Ok(())
+    }
+    fn transactions<'q>(
+        &'q self,
+    ) -> Box<dyn Iterator<Item = IterResult<(TxId, Transaction)>> + 'q> {
+        self.inner.transactions()
+    }
+    fn put_account_transaction(
+        &mut self,
+        client: Client,
+        account: Account,
+        tx_id: TxId,
+        tx: Transaction,
+    ) -> Result<(), IoError> {
+        self.put_operation(client, account, tx_id, tx, None)
+    }
+    fn put_account_transaction_recorded(
+        &mut self,
+        client: Client,
+        account: Account,
+        tx_id: TxId,
+        tx: Transaction,
+        key: &str,
+        recorded: Recorded,
+    ) -> Result<(), IoError> {
+        self.put_operation(client, account, tx_id, tx, Some((key, recorded)))
+    }
+    fn remove_account(&mut self, client: Client) -> Result<(), IoError> {
+        if self.bus.subscribers() == 0 {
+            return self.inner.remove_account(client);
+        }
+        let before = self.inner.get_account(client)?;
+        self.inner.remove_account(client)?;
+        let removed = before.map(|account| EventKind::AccountRemoved { client, account });
+        self.bus.publish(removed);
+        Ok(())
+    }
+    fn remove_transaction(&mut self, tx_id: TxId) -> Result<(), IoError> {
+        if self.bus.subscribers() == 0 {
+            return self.inner.remove_transaction(tx_id);
+        }
+        let before = self.inner.get_transaction(tx_id)?;
+        self.inner.remove_transaction(tx_id)?;
+        let removed = before.map(|transaction| EventKind::TransactionRemoved {
+            tx: tx_id,
+            transaction,
+        });
+        self.bus.publish(removed);
+        Ok(())
+    }
+    fn get_recorded(&self, key: &str) -> Result<Option<Recorded>, IoError> {
+        self.inner.get_recorded(key)
+    }
+    fn put_recorded(&mut self, key: &str, recorded: Recorded) -> Result<(), IoError> {
+        self.inner.put_recorded(key, recorded)
+    }
+    fn recorded<'q>(&'q self) -> Box<dyn Iterator<Item = IterResult<(String, Recorded)>> + 'q> {
+        self.inner.recorded()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{advanced::SledLedger, basic::HashLedger};
+    use rust_decimal_macros::dec;
+
+    fn names(events: &[Event]) -> Vec<String> {
+        events
+            .iter()
+            .map(|e| {
+                let v = serde_json::to_value(e).unwrap();
+                format!("{} {}", v["seq"], v["event"].as_str().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_channel_events() -> Result<(), TxError> {
+        let bus = EventBus::new();
+        let mut ledger = EventLedger::new(HashLedger::new(), bus.clone());
+        ledger.deposit(Client(1), TxId(1), dec!(5))?;
+        let receiver = bus.channel();
+        ledger.deposit(Client(1), TxId(2), dec!(2))?;
+        ledger.withdrawal(Client(1), TxId(3), dec!(1))?;
+        ledger.dispute(Client(1), TxId(1))?;
+        ledger.chargeback(Client(1), TxId(1))?;
+        ledger.put_account(Client(2), Default::default())?;
+        // removals of absent records publish nothing
+        ledger.remove_account(Client(2))?;
+        ledger.remove_account(Client(2))?;
+        ledger.remove_transaction(TxId(3))?;
+        ledger.remove_transaction(TxId(3))?;
+        let events: Vec<_> = receiver.try_iter().collect();
+        assert_eq!(
+            names(&events),
+            [
+                "1 deposit_applied",
+                "2 withdrawal_applied",
+                "3 dispute_opened",
+                "4 chargeback",
+                "5 account_locked",
+                "6 account_updated",
+                "7 account_removed",
+                "8 transaction_removed",
+            ]
+        );
+        assert_eq!(
+            events[3].kind,
+            EventKind::Chargeback {
+                client: Client(1),
+                tx: TxId(1),
+                amount: dec!(5),
+                account: Account {
+                    available: dec!(1),
+                    held: dec!(0),
+                    total: dec!(1),
+                    locked: true,
+                },
+            }
+        );
+        // the dropped receiver is unsubscribed
+        drop(receiver);
+        ledger.deposit(Client(3), TxId(4), dec!(1))?;
+        assert_eq!(bus.subscribers(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_and_socket_events() -> Result<(), ExecError> {
+        let path = std::env::temp_dir().join(format!("toybank-events-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        // shards of the ledger share the bus
+        let bus = EventBus::starting_at(last_seq(&path)? + 1);
+        bus.subscribe(JsonLines::file(&path)?);
+        bus.serve_subscribers(listener);
+        let socket = std::net::TcpStream::connect(addr)?;
+        while bus.subscribers() < 2 {
+            std::thread::yield_now();
+        }
+        let sled = SledLedger::new().unwrap();
+        let mut shards: Vec<_> = (0..2)
+            .map(|_| EventLedger::new(sled.clone(), bus.clone()))
+            .collect();
+        for i in 0..4u16 {
+            shards[i as usize % 2].deposit(Client(i), TxId(i.into()), dec!(1))?;
+        }
+        shards[1].dispute(Client(1), TxId(1))?;
+        shards[1].resolve(Client(1), TxId(1))?;
+
+        let from_socket: Vec<_> = read_events(std::io::BufReader::new(socket))
+            .take(6)
+            .collect::<Result<_, _>>()?;
+        // the sinks are written by their threads
+        while last_seq(&path)? < 6 {
+            std::thread::yield_now();
+        }
+        let from_file: Vec<_> = read_events(std::io::BufReader::new(std::fs::File::open(&path)?))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(from_file, from_socket);
+        assert_eq!(
+            names(&from_file)[4..],
+            ["5 dispute_opened", "6 dispute_resolved"]
+        );
+        assert_eq!(last_seq(&path)?, 6);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_stalled_subscriber() -> Result<(), ExecError> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let bus = EventBus::new();
+        let receiver = bus.channel();
+        bus.serve_subscribers(listener);
+        // the subscriber never reads
+        let _socket = std::net::TcpStream::connect(addr)?;
+        while bus.subscribers() < 2 {
+            std::thread::yield_now();
+        }
+        let mut ledger = EventLedger::new(HashLedger::new(), bus.clone());
+        let mut published = 0u32;
+        // the socket buffers fill up, then the queue, and the subscriber is dropped
+        while bus.subscribers() == 2 && published < 1_000_000 {
+            published += 1;
+            ledger.deposit(Client(1), TxId(published), dec!(1))?;
+        }
+        assert_eq!(bus.subscribers(), 1);
+        ledger.deposit(Client(1), TxId(published + 1), dec!(1))?;
+        assert_eq!(receiver.try_iter().count(), published as usize + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bounded_channel() -> Result<(), TxError> {
+        let bus = EventBus::new();
+        let receiver = bus.bounded_channel();
+        let mut ledger = EventLedger::new(HashLedger::new(), bus.clone());
+        for tx in 0..=SINK_QUEUE_LENGTH as u32 {
+            ledger.deposit(Client(1), TxId(tx), dec!(1))?;
+        }
+        // the event not fitting the queue unsubscribes the channel
+        assert_eq!(bus.subscribers(), 0);
+        assert_eq!(receiver.iter().count(), SINK_QUEUE_LENGTH);
+        Ok(())
+    }
+}
@@ -0,0 +1,321 @@
+//! Fault injection into any ledger backend
+//!
+//! `FaultyLedger` wraps a ledger and injects IO errors, latency or partial writes
+//!   into its storage calls by the configured rules.
+use crate::common::{
+    put_operation, Account, Client, IterResult, Ledger, Policy, Recorded, Timestamp, Transaction,
+    TxId,
+};
+use std::{cell::Cell, fmt, io::Error as IoError, str::FromStr, time::Duration};
+
+/// Storage calls the faults are injected into
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageOp {
+    GetAccount,
+    PutAccount,
+    GetTransaction,
+    PutTransaction,
+}
+
+impl fmt::Display for StorageOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StorageOp::GetAccount => "get_account",
+            StorageOp::PutAccount => "put_account",
+            StorageOp::GetTransaction => "get_transaction",
+            StorageOp::PutTransaction => "put_transaction",
+        })
+    }
+}
+
+impl FromStr for StorageOp {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "get_account" => Ok(StorageOp::GetAccount),
+            "put_account" => Ok(StorageOp::PutAccount),
+            "get_transaction" => Ok(StorageOp::GetTransaction),
+            "put_transaction" => Ok(StorageOp::PutTransaction),
+            _ => Err(format!("unknown storage call {s}")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The call fails with the IO error and changes nothing
+    Error,
+    /// The call is delayed and then performed
+    Latency(Duration),
+    /// Only a part of the write reaches the storage and the call fails:
+    ///   the account is stored with the new `available` and `held` only,
+    ///   the transaction is stored, of the account and transaction pair only the transaction is.
+    /// Reads are not affected
+    PartialWrite,
+}
+
+/// The fault injected into the matching calls
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FaultRule {
+    /// `None` matches all storage calls
+    pub op: Option<StorageOp>,
+    pub fault: Fault,
+    /// Number of matching calls passed before the first fault
+    pub after: usize,
+    /// Number of faulty calls, `None` is all the following calls
+    pub times: Option<usize>,
+}
+
+impl FaultRule {
+    pub fn new(op: Option<StorageOp>, fault: Fault) -> Self {
+        Self {
+            op,
+            fault,
+            after: 0,
+            times: None,
+        }
+    }
+    fn active(&self, call: usize) -> bool {
+        call >= self.after && self.times.is_none_or(|t| call < self.after + t)
+    }
+}
+
+/// Ledger decorator injecting faults into the storage calls of the inner ledger
+pub struct FaultyLedger<L> {
+    inner: L,
+    rules: Vec<FaultRule>,
+    /// Matching calls seen by every rule
+    calls: Vec<Cell<usize>>,
+    injected: Cell<usize>,
+}
+
+impl<L: Ledger> FaultyLedger<L> {
+    pub fn new(inner: L, rules: Vec<FaultRule>) -> Self {
+        Self {
+            inner,
+            calls: rules.iter().map(|_| Cell::new(0)).collect(),
+            rules,
+            injected: Cell::new(0),
+        }
+    }
+    /// Number of injected faults of all kinds
+    pub fn injected(&self) -> usize {
+        self.injected.get()
+    }
+    pub fn into_inner(self) -> L {
+        self.inner
+    }
+    /// Applies the rules matching the call, returns whether the write must be partial
+    fn inject(&self, op: StorageOp) -> Result<bool, IoError> {
+        let mut partial = false;
+        for (rule, calls) in self.rules.iter().zip(&self.calls) {
+            if rule.op.is_some_and(|o| o != op) {
+                continue;
+            }
+            let call = calls.replace(calls.get() + 1);
+            if !rule.active(call) {
+                continue;
+            }
+            match rule.fault {
+                Fault::Error => {
+                    self.injected.set(self.injected.get() + 1);
+                    return Err(IoError::other(format!("injected {op} failure")));
+                }
+                Fault::Latency(delay) => std::thread::sleep(delay),
+                Fault::PartialWrite => match op {
+                    StorageOp::PutAccount | StorageOp::PutTransaction => partial = true,
+                    StorageOp::GetAccount | StorageOp::GetTransaction => continue,
+                },
+            }
+            self.injected.set(self.injected.get() + 1);
+        }
+        Ok(partial)
+    }
+    /// The operation is one write of the inner ledger, the faults of both puts apply to it
+    fn put_operation(
+        &mut self,
+        client: Client,
+        account: Account,
+        tx_id: TxId,
+        tx: Transaction,
+        recorded: Option<(&str, Recorded)>,
+    ) -> Result<(), IoError> {
+        let partial = self.inject(StorageOp::PutTransaction)?;
+        let partial = self.inject(StorageOp::PutAccount)? || partial;
+        if partial {
+            self.inner.put_transaction(tx_id, tx)?;
+            return Err(partial_write(StorageOp::PutAccount));
+        }
+        put_operation(&mut self.inner, client, account, tx_id, tx, recorded)
+    }
+}
+
+fn partial_write(op: StorageOp) -> IoError {
+    IoError::other(format!("injected partial {op}"))
+}
+
+impl<L: Ledger> Ledger for FaultyLedger<L> {
+    fn policy(&self) -> Policy {
+        self.inner.policy()
+    }
+    fn now(&self) -> Timestamp {
+        self.inner.now()
+    }
+    fn get_account(&self, client: Client) -> Result<Option<Account>, IoError> {
+        self.inject(StorageOp::GetAccount)?;
+        self.inner.get_account(client)
+    }
+    fn put_account(&mut self, client: Client, account: Account) -> Result<(), IoError> {
+        if self.inject(StorageOp::PutAccount)? {
+            let stored = self.inner.get_account(client)?.unwrap_or_default();
+            let torn = Account {
+                available: account.available,
+                held: account.held,
+                ..stored
+            };
+            self.inner.put_account(client, torn)?;
+            return Err(partial_write(StorageOp::PutAccount));
+        }
+        self.inner.put_account(client, account)
+    }
+    fn accounts<'q>(&'q self) -> Box<dyn Iterator<Item = IterResult<(Client, Account)>> + 'q> {
+        self.inner.accounts()
+    }
+    fn get_transaction(&self, tx_id: TxId) -> Result<Option<Transaction>, IoError> {
+        self.inject(StorageOp::GetTransaction)?;
+        self.inner.get_transaction(tx_id)
+    }
+    fn put_transaction(&mut self, tx_id: TxId, tx: Transaction) -> Result<(), IoError> {
+        if self.inject(StorageOp::PutTransaction)? {
+            self.inner.put_transaction(tx_id, tx)?;
+            return Err(partial_write(StorageOp::PutTransaction));
+        }
+        self.inner.put_transaction(tx_id, tx)
+    }
+    fn transactions<'q>(
+        &'q self,
+    ) -> Box<dyn Iterator<Item = IterResult<(TxId, Transaction)>> + 'q> {
+        self.inner.transactions()
+    }
+    fn put_account_transaction(
+        &mut self,
+        client: Client,
+        account: Account,
+        tx_id: TxId,
+        tx: Transaction,
+    ) -> Result<(), IoError> {
+        self.put_operation(client, account, tx_id, tx, None)
+    }
+    fn put_account_transaction_recorded(
+        &mut self,
+        client: Client,
+        account: Account,
+        tx_id: TxId,
+        tx: Transaction,
+        key: &str,
+        recorded: Recorded,
+    ) -> Result<(), IoError> {
+        self.put_operation(client, account, tx_id, tx, Some((key, recorded)))
+    }
+    /// Removals are writes, partial removals remove nothing
+    fn remove_account(&mut self, client: Client) -> Result<(), IoError> {
+        if self.inject(StorageOp::PutAccount)? {
+            return Err(partial_write(StorageOp::PutAccount));
+        }
+        self.inner.remove_account(client)
+    }
+    fn remove_transaction(&mut self, tx_id: TxId) -> Result<(), IoError> {
+        if self.inject(StorageOp::PutTransaction)? {
+            return Err(partial_write(StorageOp::PutTransaction));
+        }
+        self.inner.remove_transaction(tx_id)
+    }
+    fn get_recorded(&self, key: &str) -> Result<Option<Recorded>, IoError> {
+        self.inner.get_recorded(key)
+    }
+    fn put_recorded(&mut self, key: &str, recorded: Recorded) -> Result<(), IoError> {
+        self.inner.put_recorded(key, recorded)
+    }
+    fn recorded<'q>(&'q self) -> Box<dyn Iterator<Item = IterResult<(String, Recorded)>> + 'q> {
+        self.inner.recorded()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{basic::HashLedger, common::TxError};
+    use rust_decimal_macros::dec;
+
+    fn faulty(rule: FaultRule) -> FaultyLedger<HashLedger> {
+        FaultyLedger::new(HashLedger::new(), vec![rule])
+    }
+
+    #[test]
+    fn test_errors_after_and_times() -> Result<(), TxError> {
+        let mut ledger = faulty(FaultRule {
+            after: 1,
+            times: Some(1),
+            ..FaultRule::new(Some(StorageOp::PutAccount), Fault::Error)
+        });
+        ledger.deposit(Client(1), TxId(1), dec!(1))?;
+        assert!(matches!(
+            ledger.deposit(Client(1), TxId(2), dec!(2)),
+            Err(TxError::IOError(_))
+        ));
+        // the failed write changed nothing
+        assert_eq!(ledger.get_transaction(TxId(2))?, None);
+        ledger.deposit(Client(1), TxId(2), dec!(2))?;
+        assert_eq!(ledger.get_account(Client(1))?.unwrap().total, dec!(3));
+        assert_eq!(ledger.injected(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_writes() -> Result<(), TxError> {
+        let mut ledger = faulty(FaultRule {
+            after: 1,
+            ..FaultRule::new(Some(StorageOp::PutAccount), Fault::PartialWrite)
+        });
+        ledger.deposit(Client(1), TxId(1), dec!(1))?;
+        assert!(ledger.deposit(Client(1), TxId(2), dec!(2)).is_err());
+        assert!(ledger.get_transaction(TxId(2))?.is_some());
+        assert_eq!(ledger.get_account(Client(1))?.unwrap().total, dec!(1));
+
+        let acc = Account {
+            available: dec!(5),
+            total: dec!(5),
+            ..Default::default()
+        };
+        assert!(ledger.put_account(Client(1), acc).is_err());
+        let torn = ledger.get_account(Client(1))?.unwrap();
+        assert_eq!((torn.available, torn.total), (dec!(5), dec!(1)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_latency_and_reads() -> Result<(), TxError> {
+        let delay = Duration::from_millis(20);
+        let mut ledger = FaultyLedger::new(
+            HashLedger::new(),
+            vec![
+                FaultRule::new(None, Fault::Latency(delay)),
+                FaultRule {
+                    after: 2,
+                    ..FaultRule::new(Some(StorageOp::GetTransaction), Fault::Error)
+                },
+            ],
+        );
+        let started = std::time::Instant::now();
+        ledger.deposit(Client(1), TxId(1), dec!(1))?;
+        // get_account, get_transaction and the write are delayed
+        assert!(started.elapsed() >= 3 * delay);
+        ledger.dispute(Client(1), TxId(1))?;
+        assert!(matches!(
+            ledger.resolve(Client(1), TxId(1)),
+            Err(TxError::IOError(_))
+        ));
+        assert_eq!("put_transaction".parse(), Ok(StorageOp::PutTransaction));
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+//! Entry points of the fuzz targets in `fuzz/`
+//!
+//! Every entry point must return for any input without panics,
+//! the tests run them over the seed corpus on the stable toolchain.
+use crate::{
+    advanced::{index_by_client, sharded_execute_csv, SledLedger},
+    basic::HashLedger,
+    common::{Client, Ledger, TxId},
+    libcsv::execute_csv,
+};
+use std::sync::{Arc, Mutex, OnceLock};
+
+pub fn execute_csv_target(data: &[u8]) {
+    let mut ledger = HashLedger::new();
+    let _ = execute_csv(std::io::Cursor::new(data), &mut ledger);
+}
+
+pub fn sharded_execute_csv_target(data: &[u8]) {
+    let sharding: Vec<_> = (0..3)
+        .map(|_| Arc::new(Mutex::new(HashLedger::new())) as Arc<Mutex<dyn Ledger + Send>>)
+        .collect();
+    let _ = sharded_execute_csv(std::io::Cursor::new(data), &sharding, index_by_client);
+}
+
+/// Stores the raw value as the account and as the transaction and uses them
+pub fn sled_decode_target(data: &[u8]) {
+    static LEDGER: OnceLock<SledLedger> = OnceLock::new();
+    let mut ledger = LEDGER.get_or_init(|| SledLedger::new().unwrap()).clone();
+    let _ = SledLedger::decode_account(data);
+    let _ = SledLedger::decode_transaction(data);
+    let (client, tx_id) = (Client(1), TxId(1));
+    ledger.0.clear().unwrap();
+    ledger.0.insert(format!("1'{client:?}"), data).unwrap();
+    ledger.0.insert(format!("2'{tx_id:?}"), data).unwrap();
+    let _ = ledger.get_account(client);
+    let _ = ledger.get_transaction(tx_id);
+    let _ = ledger.accounts().count();
+    let _ = ledger.transactions().count();
+    let _ = ledger.dispute(client, tx_id);
+    let _ = ledger.deposit(client, TxId(2), Default::default());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{Account, Transaction, TxState};
+    use std::path::Path;
+
+    const CORPUS: &str = "fuzz/corpus";
+
+    fn corpus(target: &str) -> Vec<Vec<u8>> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join(CORPUS)
+            .join(target);
+        let mut files: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        files.sort();
+        files.iter().map(|f| std::fs::read(f).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_seed_corpus() {
+        let csv = corpus("execute_csv");
+        assert!(csv.len() > 5);
+        csv.iter().for_each(|data| execute_csv_target(data));
+        corpus("sharded_execute_csv")
+            .iter()
+            .for_each(|data| sharded_execute_csv_target(data));
+        corpus("sled_decode")
+            .iter()
+            .for_each(|data| sled_decode_target(data));
+    }
+
+    #[test]
+    fn test_malformed_inputs() {
+        for data in [
+            &b"type,client,tx,amount\ndeposit,1,1,79228162514264337593543950335\ndeposit,1,2,1"[..],
+            b"type,client,tx,amount\ndeposit,1,1\nwithdrawal,1,2,\xff\n",
+            b"tx,amount\n\"",
+            b"",
+        ] {
+            execute_csv_target(data);
+            sharded_execute_csv_target(data);
+        }
+        sled_decode_target(&[5, 0, 0, 0, 0]);
+        sled_decode_target(&[0xff; 16]);
+    }
+
+    /// Writes the seed corpus from `tests/*.csv`, csv of feature files and valid records
+    #[test]
+    #[ignore]
+    fn write_seed_corpus() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let mut csv = vec![(
+            "basic-transactions.csv".to_string(),
+            crate::basic::TRANSACTIONS.as_bytes().to_vec(),
+        )];
+        for entry in std::fs::read_dir(root.join("tests")).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "csv") {
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                csv.push((name, std::fs::read(&path).unwrap()));
+            }
+        }
+        for feature in [
+            "basic/csv.feature",
+            "basic/other.feature",
+            "advanced/csv.feature",
+        ] {
+            let text = std::fs::read_to_string(root.join("tests/features").join(feature)).unwrap();
+            let (mut block, mut step, mut n) = (None::<String>, "", 0);
+            for line in text.lines().map(str::trim) {
+                match (&mut block, line) {
+                    (None, "\"\"\"") => block = Some(String::new()),
+                    (Some(b), "\"\"\"") => {
+                        if step.contains("execute csv") {
+                            n += 1;
+                            let name = format!("{}-{n}.csv", feature.replace('/', "-"));
+                            csv.push((name, b.clone().into_bytes()));
+                        }
+                        block = None;
+                    }
+                    (Some(b), line) => *b += &format!("{line}\n"),
+                    (None, line) => step = line,
+                }
+            }
+        }
+        for target in ["execute_csv", "sharded_execute_csv"] {
+            let dir = root.join(CORPUS).join(target);
+            std::fs::create_dir_all(&dir).unwrap();
+            for (name, data) in &csv {
+                std::fs::write(dir.join(name), data).unwrap();
+            }
+        }
+        let dir = root.join(CORPUS).join("sled_decode");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut ledger = SledLedger::new().unwrap();
+        ledger
+            .put_account(
+                Client(1),
+                Account {
+                    available: rust_decimal_macros::dec!(1.5),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let tx = Transaction {
+            client: Client(1),
+            amount: rust_decimal_macros::dec!(1.5),
+            state: TxState::Disputed,
+            time: None,
+        };
+        ledger.put_transaction(TxId(1), tx).unwrap();
+        for (name, key) in [("account", "1'Client(1)"), ("transaction", "2'TxId(1)")] {
+            let value = ledger.0.get(key).unwrap().unwrap();
+            std::fs::write(dir.join(name), value).unwrap();
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! gRPC API over the `LedgerService`, the schema is [toybank.proto](../proto/toybank.proto)
+//!
+//! Invalid requests fail with `INVALID_ARGUMENT`, failures of the ledger with `INTERNAL`.
+// `Status` is the error type of the generated service
+#![allow(clippy::result_large_err)]
+use crate::{
+    advanced::MSG_QUEUE_LENGTH,
+    common::{Account, Client, Timestamp, TxId, TxState, TxType},
+    libcsv::{ExecError, Outcome, TxRequest},
+    service::LedgerService,
+};
+use futures::{stream::FuturesOrdered, FutureExt, Stream, StreamExt};
+use rust_decimal::Decimal;
+use std::pin::Pin;
+use tonic::{Request, Response, Status, Streaming};
+
+/// Types generated from the schema
+pub mod proto {
+    tonic::include_proto!("toybank");
+}
+
+use proto::bank_server::{Bank, BankServer};
+
+impl From<TxType> for proto::TxType {
+    fn from(t: TxType) -> Self {
+        match t {
+            TxType::Deposit => proto::TxType::Deposit,
+            TxType::Withdrawal => proto::TxType::Withdrawal,
+            TxType::Dispute => proto::TxType::Dispute,
+            TxType::Resolve => proto::TxType::Resolve,
+            TxType::Chargeback => proto::TxType::Chargeback,
+        }
+    }
+}
+
+impl From<TxState> for proto::TxState {
+    fn from(s: TxState) -> Self {
+        match s {
+            TxState::Committed => proto::TxState::Committed,
+            TxState::Disputed => proto::TxState::Disputed,
+            TxState::Finalized => proto::TxState::Finalized,
+            TxState::Cancelled => proto::TxState::Cancelled,
+        }
+    }
+}
+
+impl From<TxRequest> for proto::TxRequest {
+    fn from(r: TxRequest) -> Self {
+        Self {
+            r#type: proto::TxType::from(r.tx_type).into(),
+            client: r.client.0.into(),
+            tx: r.tx_id.0,
+            amount: r.amount.map(|a| a.to_string()),
+            key: r.key,
+            time: r.time.map(|t| t.0),
+        }
+    }
+}
+
+impl TryFrom<proto::TxRequest> for TxRequest {
+    type Error = Status;
+
+    fn try_from(r: proto::TxRequest) -> Result<Self, Status> {
+        let tx_type = match r.r#type() {
+            proto::TxType::Unspecified => return Err(Status::invalid_argument("tx has no type")),
+            proto::TxType::Deposit => TxType::Deposit,
+            proto::TxType::Withdrawal => TxType::Withdrawal,
+            proto::TxType::Dispute => TxType::Dispute,
+            proto::TxType::Resolve => TxType::Resolve,
+            proto::TxType::Chargeback => TxType::Chargeback,
+        };
+        let client = client_arg(r.client)?;
+        let amount = match r.amount {
+            Some(a) => Some(
+                Decimal::from_str_exact(&a)
+                    .map_err(|e| Status::invalid_argument(format!("bad amount `{a}`: {e}")))?,
+            ),
+            None => None,
+        };
+        let request = TxRequest {
+            tx_type,
+            client,
+            tx_id: TxId(r.tx),
+            amount,
+            key: r.key,
+            time: r.time.map(Timestamp),
+        };
+        request.check_amount().map_err(Status::invalid_argument)?;
+        Ok(request)
+    }
+}
+
+fn internal(e: ExecError) -> Status {
+    Status::internal(e.to_string())
+}
+
+fn outcome_reply(outcome: Outcome) -> (proto::Outcome, String) {
+    match outcome {
+        Outcome::Applied => (proto::Outcome::Applied, String::new()),
+        Outcome::Ignored(e) => (proto::Outcome::Ignored, e),
+        Outcome::Rejected(e) => (proto::Outcome::Rejected, e),
+    }
+}
+
+fn account_reply(client: Client, acc: Account) -> proto::Account {
+    proto::Account {
+        client: client.0.into(),
+        available: acc.available.to_string(),
+        held: acc.held.to_string(),
+        total: acc.total.to_string(),
+        locked: acc.locked,
+    }
+}
+
+fn client_arg(client: u32) -> Result<Client, Status> {
+    u16::try_from(client)
+        .map(Client)
+        .map_err(|_| Status::invalid_argument(format!("bad client {client}")))
+}
+
+type ReplyStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+/// Implementation of the `Bank` service
+pub struct BankApi {
+    service: LedgerService,
+}
+
+#[tonic::async_trait]
+impl Bank for BankApi {
+    async fn submit(
+        &self,
+        request: Request<proto::TxRequest>,
+    ) -> Result<Response<proto::SubmitReply>, Status> {
+        let r = TxRequest::try_from(request.into_inner())?;
+        let (outcome, reason) = outcome_reply(self.service.submit(r).await.map_err(internal)?);
+        Ok(Response::new(proto::SubmitReply {
+            outcome: outcome.into(),
+            reason,
+        }))
+    }
+
+    /// Queues the requests while at most `MSG_QUEUE_LENGTH` outcomes are pending,
+    ///   the requests queued before an invalid one are still applied
+    async fn bulk_submit(
+        &self,
+        request: Request<Streaming<proto::TxRequest>>,
+    ) -> Result<Response<proto::BulkSubmitReply>, Status> {
+        let mut requests = request.into_inner();
+        let mut pending = FuturesOrdered::new();
+        let mut reply = proto::BulkSubmitReply::default();
+        let mut record = |index: u64, outcome: Result<Outcome, ExecError>| {
+            let (outcome, reason) = outcome_reply(outcome.map_err(internal)?);
+            match outcome {
+                proto::Outcome::Applied => reply.applied += 1,
+                proto::Outcome::Ignored => reply.ignored += 1,
+                _ => reply.rejected += 1,
+            }
+            if outcome != proto::Outcome::Applied {
+                reply.failures.push(proto::BulkFailure {
+                    index,
+                    outcome: outcome.into(),
+                    reason,
+                });
+            }
+            Ok::<_, Status>(())
+        };
+        let mut index = 0u64;
+        while let Some(r) = requests.message().await? {
+            let r = TxRequest::try_from(r).map_err(|e| {
+                Status::invalid_argument(format!("request {index}: {}", e.message()))
+            })?;
+            let outcome = self.service.enqueue(r).await.map_err(internal)?;
+            pending.push_back(outcome.map(move |outcome| (index, outcome)));
+            index += 1;
+            if pending.len() >= MSG_QUEUE_LENGTH {
+                if let Some((index, outcome)) = pending.next().await {
+                    record(index, outcome)?;
+                }
+            }
+        }
+        while let Some((index, outcome)) = pending.next().await {
+            record(index, outcome)?;
+        }
+        Ok(Response::new(reply))
+    }
+
+    async fn get_account(
+        &self,
+        request: Request<proto::AccountQuery>,
+    ) -> Result<Response<proto::Account>, Status> {
+        let client = client_arg(request.into_inner().client)?;
+        match self.service.account(client).await.map_err(internal)? {
+            Some(acc) => Ok(Response::new(account_reply(client, acc))),
+            None => Err(Status::not_found(format!("account {} not found", client.0))),
+        }
+    }
+
+    type ListAccountsStream = ReplyStream<proto::Account>;
+
+    async fn list_accounts(
+        &self,
+        _request: Request<proto::ListAccountsRequest>,
+    ) -> Result<Response<Self::ListAccountsStream>, Status> {
+        let accounts = self.service.accounts().await.map_err(internal)?;
+        Ok(Response::new(Box::pin(futures::stream::iter(
+            accounts
+                .into_iter()
+                .map(|(client, acc)| Ok(account_reply(client, acc))),
+        ))))
+    }
+
+    type ClientTransactionsStream = ReplyStream<proto::Transaction>;
+
+    async fn client_transactions(
+        &self,
+        request: Request<proto::AccountQuery>,
+    ) -> Result<Response<Self::ClientTransactionsStream>, Status> {
+        let client = client_arg(request.into_inner().client)?;
+        let txs = self
+            .service
+            .client_transactions(client)
+            .await
+            .map_err(internal)?;
+        Ok(Response::new(Box::pin(futures::stream::iter(
+            txs.into_iter().map(|(tx_id, tx)| {
+                Ok(proto::Transaction {
+                    tx: tx_id.0,
+                    client: tx.client.0.into(),
+                    amount: tx.amount.to_string(),
+                    state: proto::TxState::from(tx.state).into(),
+                    time: tx.time.map(|t| t.0),
+                })
+            }),
+        ))))
+    }
+}
+
+/// The `Bank` service to add to a tonic server
+pub fn bank(service: LedgerService) -> BankServer<BankApi> {
+    BankServer::new(BankApi { service })
+}
+
+/// Serves the API until the listener fails
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    service: LedgerService,
+) -> std::io::Result<()> {
+    let incoming = tonic::transport::server::TcpIncoming::from_listener(listener, true, None)
+        .map_err(std::io::Error::other)?;
+    tonic::transport::Server::builder()
+        .add_service(bank(service))
+        .serve_with_incoming(incoming)
+        .await
+        .map_err(std::io::Error::other)
+}
@@ -0,0 +1,158 @@
+//! Line protocol feeding csv requests of socket connections to the `LedgerService`
+//!
+//! A connection sends lines in the format `execute_csv` reads, starting with the header
+//!   when the dialect has headers. Every request line is acknowledged in the order of input
+//!   by the csv record `line,outcome,reason`:
+//! ```text
+//! 2,applied,
+//! 3,ignored,duplicated transaction
+//! 4,rejected,insufficient funds
+//! 5,error,"line 5, record 3: bad client `x`"
+//! ```
+//! Header, comment and empty lines are not acknowledged.
+//! A request is read only after the previous one is queued to its shard, and at most
+//!   `MSG_QUEUE_LENGTH` acknowledgements are pending, so a busy shard or a peer not reading
+//!   its acknowledgements stops the connection from being read. A line longer than
+//!   `MAX_LINE_LENGTH` bytes is acknowledged as an error and fails the connection.
+use crate::{
+    advanced::MSG_QUEUE_LENGTH,
+    dialect::Dialect,
+    libcsv::{ExecError, Outcome},
+    service::LedgerService,
+    source::LineParser,
+};
+use std::{future::Future, pin::Pin};
+use tokio::{
+    io::{
+        AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
+    },
+    sync::mpsc,
+};
+
+/// Length of the longest request line, without its line break
+pub const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+type Pending = Pin<Box<dyn Future<Output = Result<Outcome, ExecError>> + Send>>;
+
+fn ack(line: u64, outcome: Result<Outcome, ExecError>) -> Result<Vec<u8>, ExecError> {
+    let (outcome, reason) = match outcome {
+        Ok(Outcome::Applied) => ("applied", String::new()),
+        Ok(Outcome::Ignored(e)) => ("ignored", e),
+        Ok(Outcome::Rejected(e)) => ("rejected", e),
+        Err(e) => ("error", e.to_string()),
+    };
+    let mut wr = csv::Writer::from_writer(Vec::new());
+    wr.write_record([line.to_string().as_str(), outcome, reason.as_str()])?;
+    wr.into_inner()
+        .map_err(|e| ExecError::StringError(e.to_string()))
+}
+
+/// Serves the connection until the peer closes its sending side
+///
+/// Fails on IO errors of the connection, the requests queued before are still applied.
+pub async fn ingest<S: AsyncRead + AsyncWrite>(
+    stream: S,
+    service: &LedgerService,
+    dialect: &Dialect,
+) -> Result<(), ExecError> {
+    let (rd, wr) = tokio::io::split(stream);
+    let (ack_s, mut ack_r) = mpsc::channel::<(u64, Pending)>(MSG_QUEUE_LENGTH);
+    let reader = async move {
+        let mut parser = LineParser::new(dialect)?;
+        let mut rd = BufReader::new(rd);
+        let mut buf = Vec::new();
+        let mut n = 0u64;
+        loop {
+            buf.clear();
+            let limit = MAX_LINE_LENGTH as u64 + 1;
+            if (&mut rd).take(limit).read_until(b'\n', &mut buf).await? == 0 {
+                break;
+            }
+            n += 1;
+            if buf.last() == Some(&b'\n') {
+                buf.pop();
+                if buf.last() == Some(&b'\r') {
+                    buf.pop();
+                }
+            } else if buf.len() > MAX_LINE_LENGTH {
+                let too_long = || format!("line {n} is longer than {MAX_LINE_LENGTH} bytes");
+                let error = ExecError::StringError(too_long());
+                let _ = ack_s
+                    .send((n, Box::pin(std::future::ready(Err(error)))))
+                    .await;
+                return Err(ExecError::StringError(too_long()));
+            }
+            let line = std::str::from_utf8(&buf)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            let pending: Pending = match parser.parse(line, n) {
+                None => continue,
+                Some(Ok((_, r))) => match service.enqueue(r).await {
+                    Ok(outcome) => Box::pin(outcome),
+                    Err(e) => Box::pin(std::future::ready(Err(e))),
+                },
+                Some(Err(e)) => Box::pin(std::future::ready(Err(e))),
+            };
+            if ack_s.send((n, pending)).await.is_err() {
+                // the writer has failed
+                break;
+            }
+        }
+        Ok::<_, ExecError>(())
+    };
+    let writer = async move {
+        let mut wr = BufWriter::new(wr);
+        while let Some((n, pending)) = ack_r.recv().await {
+            wr.write_all(&ack(n, pending.await)?).await?;
+            if ack_r.is_empty() {
+                wr.flush().await?;
+            }
+        }
+        wr.flush().await?;
+        wr.into_inner().shutdown().await?;
+        Ok::<_, ExecError>(())
+    };
+    let (read, written) = tokio::join!(reader, writer);
+    read.and(written)
+}
+
+async fn serve_connection<S: AsyncRead + AsyncWrite>(
+    stream: S,
+    service: LedgerService,
+    dialect: Dialect,
+    failed: impl Fn(ExecError),
+) {
+    if let Err(e) = ingest(stream, &service, &dialect).await {
+        failed(e);
+    }
+}
+
+/// Serves connections of the TCP listener until it fails,
+///   `failed` is called with the errors of failed connections
+pub async fn serve_tcp(
+    listener: tokio::net::TcpListener,
+    service: LedgerService,
+    dialect: Dialect,
+    failed: impl Fn(ExecError) + Clone + Send + 'static,
+) -> std::io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let connection = serve_connection(stream, service.clone(), dialect.clone(), failed.clone());
+        tokio::spawn(connection);
+    }
+}
+
+/// Serves connections of the Unix socket listener until it fails,
+///   `failed` is called with the errors of failed connections
+#[cfg(unix)]
+pub async fn serve_unix(
+    listener: tokio::net::UnixListener,
+    service: LedgerService,
+    dialect: Dialect,
+    failed: impl Fn(ExecError) + Clone + Send + 'static,
+) -> std::io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let connection = serve_connection(stream, service.clone(), dialect.clone(), failed.clone());
+        tokio::spawn(connection);
+    }
+}
@@ -0,0 +1,301 @@
+use crate::{
+    common::{Account, Client, Ledger, TxState, TxType},
+    libcsv::{checked_amount, ExecError, TxRequest},
+    snapshot::Snapshot,
+};
+use rust_decimal::Decimal;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// The allowed graph of transaction states, `None` is the absent transaction:
+/// ```text
+/// None -> Committed (deposit) -> Disputed -> Finalized (resolve)
+///                                         -> Cancelled (chargeback)
+///                              -> Finalized (expired deposit)
+/// None -> Finalized (withdrawal)
+/// ```
+pub fn allowed_transition(from: Option<TxState>, to: TxState) -> bool {
+    use TxState::*;
+    matches!(
+        (from, to),
+        (None, Committed)
+            | (None, Finalized)
+            | (Some(Committed), Disputed)
+            | (Some(Committed), Finalized)
+            | (Some(Disputed), Finalized)
+            | (Some(Disputed), Cancelled)
+    )
+}
+
+/// Checks invariants of the ledger state:
+///   `total == available + held` for every account,
+///   `held` is the sum of amounts of its disputed transactions
+pub fn check_state(ledger: &dyn Ledger) -> Result<(), String> {
+    check_snapshot(&Snapshot::from_ledger(ledger).map_err(|e| e.to_string())?)
+}
+
+pub fn check_snapshot(snapshot: &Snapshot) -> Result<(), String> {
+    let mut disputed: HashMap<Client, Decimal> = HashMap::new();
+    let clients: HashSet<Client> = snapshot.accounts.iter().map(|(c, _)| *c).collect();
+    for (tx_id, tx) in &snapshot.transactions {
+        if tx.state == TxState::Disputed {
+            let held = disputed.entry(tx.client).or_default();
+            *held = held
+                .checked_add(tx.amount)
+                .ok_or_else(|| format!("client {}: disputed amounts overflow", tx.client.0))?;
+        }
+        if !clients.contains(&tx.client) {
+            return Err(format!(
+                "{tx_id:?} of client {} without account",
+                tx.client.0
+            ));
+        }
+    }
+    for (client, acc) in &snapshot.accounts {
+        if acc.available.checked_add(acc.held) != Some(acc.total) {
+            return Err(format!(
+                "client {}: total != available + held, {acc:?}",
+                client.0
+            ));
+        }
+        let held = disputed.get(client).copied().unwrap_or_default();
+        if acc.held != held {
+            return Err(format!(
+                "client {}: held {} != disputed {held}",
+                client.0, acc.held
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks the change of the ledger state made by the request:
+///   only the account and the transaction of the request change,
+///   transaction states follow `allowed_transition`,
+///   a locked account changes by chargeback only and only chargeback locks an account
+pub fn check_step(before: &Snapshot, after: &Snapshot, r: &TxRequest) -> Result<(), String> {
+    let account = |s: &Snapshot, c| s.accounts.iter().find(|(x, _)| *x == c).map(|(_, a)| *a);
+    let tx = |s: &Snapshot, id| {
+        s.transactions
+            .iter()
+            .find(|(x, _)| *x == id)
+            .map(|(_, t)| *t)
+    };
+    let others = |s: &Snapshot| {
+        let accounts: Vec<_> = s.accounts.iter().filter(|(c, _)| *c != r.client).collect();
+        let txs: Vec<_> = s
+            .transactions
+            .iter()
+            .filter(|(t, _)| *t != r.tx_id)
+            .collect();
+        format!("{accounts:?}{txs:?}")
+    };
+    if others(before) != others(after) {
+        return Err("the request changed other accounts or transactions".into());
+    }
+    let (acc_before, acc_after) = (account(before, r.client), account(after, r.client));
+    match (acc_before, acc_after) {
+        (Some(b), a) if b.locked && Some(b) != a && r.tx_type != TxType::Chargeback => {
+            return Err(format!("locked account changed: {b:?} -> {a:?}"));
+        }
+        (b, Some(a))
+            if a.locked && !matches!(b, Some(b) if b.locked) && r.tx_type != TxType::Chargeback =>
+        {
+            return Err(format!("{:?} locked the account", r.tx_type));
+        }
+        (Some(_), None) => return Err("the account disappeared".into()),
+        _ => (),
+    }
+    match (tx(before, r.tx_id), tx(after, r.tx_id)) {
+        (None, None) => Ok(()),
+        (Some(_), None) => Err("the transaction disappeared".into()),
+        (Some(b), Some(a)) if b == a => Ok(()),
+        (Some(b), Some(a)) if b.client != a.client || b.amount != a.amount => {
+            Err(format!("transaction changed: {b:?} -> {a:?}"))
+        }
+        (b, Some(a)) if allowed_transition(b.map(|b| b.state), a.state) => Ok(()),
+        (b, Some(a)) => Err(format!(
+            "not allowed transition {:?} -> {:?}",
+            b.map(|b| b.state),
+            a.state
+        )),
+    }
+}
+
+/// Completes operations interrupted between the transaction write and the account write
+///
+/// The transaction is written first, so its state is what the operation intended
+///   and accounts are rolled forward to agree with their transactions:
+///   the missing account is created from the committed and disputed deposits,
+///   the missing part of `held` comes from `available` (dispute),
+///   the excess of `held` returns to `available` (resolve), or leaves `total`
+///   and locks the account if the client has a cancelled transaction (chargeback).
+/// Interrupted deposits to existing accounts and withdrawals can not be detected.
+/// Returns descriptions of the repaired accounts.
+pub fn repair(ledger: &mut dyn Ledger) -> Result<Vec<String>, ExecError> {
+    let snapshot = Snapshot::from_ledger(ledger)?;
+    let (mut disputed, mut committed) = (HashMap::new(), HashMap::new());
+    let mut cancelled = HashSet::new();
+    for (_, tx) in &snapshot.transactions {
+        let sum = match tx.state {
+            TxState::Committed => committed.entry(tx.client).or_insert(Decimal::ZERO),
+            TxState::Disputed => disputed.entry(tx.client).or_insert(Decimal::ZERO),
+            TxState::Cancelled => {
+                cancelled.insert(tx.client);
+                continue;
+            }
+            TxState::Finalized => continue,
+        };
+        *sum = checked_amount(sum.checked_add(tx.amount))?;
+    }
+    let accounts: HashMap<_, _> = snapshot.accounts.iter().copied().collect();
+    let clients: BTreeSet<_> = (snapshot.accounts.iter().map(|(c, _)| c.0))
+        .chain(snapshot.transactions.iter().map(|(_, tx)| tx.client.0))
+        .collect();
+    let mut repaired = Vec::new();
+    for client in clients.into_iter().map(Client) {
+        let held = disputed.get(&client).copied().unwrap_or_default();
+        let before = accounts.get(&client).copied();
+        let mut acc = match before {
+            Some(acc) => acc,
+            None => {
+                let available = committed.get(&client).copied().unwrap_or_default();
+                Account {
+                    available,
+                    held,
+                    total: checked_amount(available.checked_add(held))?,
+                    locked: false,
+                }
+            }
+        };
+        let excess = checked_amount(acc.held.checked_sub(held))?;
+        if excess > Decimal::ZERO && !acc.locked && cancelled.contains(&client) {
+            acc.total = checked_amount(acc.total.checked_sub(excess))?;
+            acc.locked = true;
+        } else {
+            acc.available = checked_amount(acc.available.checked_add(excess))?;
+        }
+        acc.held = held;
+        acc.total = checked_amount(acc.available.checked_add(acc.held))?;
+        if Some(acc) != before {
+            ledger.put_account(client, acc)?;
+            repaired.push(format!("client {}: {before:?} -> {acc:?}", client.0));
+        }
+    }
+    Ok(repaired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        basic::HashLedger,
+        common::{Transaction, TxId},
+        crash::CrashingLedger,
+        libcsv::apply_request,
+    };
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_allowed_transitions() {
+        use TxState::*;
+        assert!(allowed_transition(None, Committed));
+        assert!(allowed_transition(Some(Disputed), Cancelled));
+        assert!(!allowed_transition(Some(Finalized), Disputed));
+        assert!(!allowed_transition(Some(Cancelled), Committed));
+        assert!(!allowed_transition(None, Disputed));
+    }
+
+    #[test]
+    fn test_violations_detected() -> Result<(), crate::libcsv::ExecError> {
+        let mut ledger = HashLedger::new();
+        ledger.deposit(Client(1), TxId(1), dec!(2))?;
+        ledger.dispute(Client(1), TxId(1))?;
+        assert_eq!(check_state(&ledger), Ok(()));
+        let before = Snapshot::from_ledger(&ledger)?;
+        ledger.put_account(
+            Client(1),
+            Account {
+                held: dec!(1),
+                available: dec!(1),
+                total: dec!(2),
+                locked: true,
+            },
+        )?;
+        let after = Snapshot::from_ledger(&ledger)?;
+        assert!(check_snapshot(&after).unwrap_err().contains("held"));
+        let resolve = TxRequest {
+            tx_type: TxType::Resolve,
+            client: Client(1),
+            tx_id: TxId(1),
+            amount: None,
+            key: None,
+            time: None,
+        };
+        assert!(check_step(&before, &after, &resolve)
+            .unwrap_err()
+            .contains("locked"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair_interrupted_operations() -> Result<(), ExecError> {
+        let request = |tx_type, client, tx_id| TxRequest {
+            tx_type,
+            client: Client(client),
+            tx_id: TxId(tx_id),
+            amount: (tx_type == TxType::Deposit).then_some(dec!(2)),
+            key: None,
+            time: None,
+        };
+        use TxType::*;
+        let requests = [
+            request(Deposit, 1, 1),
+            request(Deposit, 2, 2),
+            request(Dispute, 1, 1),
+            request(Dispute, 2, 2),
+            request(Resolve, 1, 1),
+            request(Chargeback, 2, 2),
+        ];
+        let mut ledger = HashLedger::new();
+        let mut states = Vec::new();
+        for r in &requests {
+            apply_request(&mut ledger, r)?;
+            states.push(Snapshot::from_ledger(&ledger)?);
+        }
+        assert!(repair(&mut ledger)?.is_empty());
+        for (k, r) in requests.iter().enumerate() {
+            let mut ledger = HashLedger::new();
+            for r in &requests[..k] {
+                apply_request(&mut ledger, r)?;
+            }
+            // the transaction is written, the account is not
+            let mut crashing = CrashingLedger::new(ledger, 1, true);
+            assert!(apply_request(&mut crashing, r).is_err());
+            let mut ledger = crashing.into_inner();
+            assert!(check_state(&ledger).is_err(), "{r:?}");
+            assert_eq!(repair(&mut ledger)?.len(), 1);
+            assert_eq!(check_state(&ledger), Ok(()));
+            assert_eq!(Snapshot::from_ledger(&ledger)?, states[k], "{r:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair_overflow() -> Result<(), ExecError> {
+        let mut ledger = HashLedger::new();
+        for tx_id in [1, 2] {
+            let tx = Transaction {
+                client: Client(1),
+                amount: Decimal::MAX,
+                state: TxState::Committed,
+                time: None,
+            };
+            ledger.put_transaction(TxId(tx_id), tx)?;
+        }
+        assert_eq!(
+            repair(&mut ledger).unwrap_err().to_string(),
+            "amount overflow"
+        );
+        Ok(())
+    }
+}
@@ -1,4 +1,40 @@
 pub mod advanced;
+#[cfg(feature = "async")]
+pub mod asynchronous;
+pub mod audit;
 pub mod basic;
+#[cfg(any(test, feature = "testing"))]
+pub mod bench;
 pub mod common;
+#[cfg(any(test, feature = "testing"))]
+pub mod crash;
+pub mod dialect;
+#[cfg(any(test, feature = "testing"))]
+pub mod differential;
+#[cfg(feature = "events")]
+pub mod events;
+#[cfg(any(test, feature = "testing"))]
+pub mod faults;
+#[cfg(any(test, feature = "testing"))]
+pub mod fuzzing;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "ingest")]
+pub mod ingest;
+pub mod invariants;
 pub mod libcsv;
+#[cfg(feature = "notify")]
+pub mod notify;
+pub mod pipeline;
+#[cfg(feature = "repl")]
+pub mod repl;
+pub mod routing;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "async")]
+pub mod service;
+pub mod snapshot;
+pub mod source;
+pub mod stats;
+#[cfg(any(test, feature = "testing"))]
+pub mod workload;
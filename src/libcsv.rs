@@ -1,10 +1,18 @@
-use crate::common::{Account, Client, Ledger, TxError, TxId, TxType};
-use rust_decimal::Decimal;
+pub use crate::common::Outcome;
+use crate::{
+    common::{
+        Account, Client, IterResult, Ledger, Policy, Recorded, Timestamp, Transaction, TxError,
+        TxId, TxType,
+    },
+    snapshot::state_name,
+    source::{csv_reader_builder, CsvSource, TxSource},
+};
+use rust_decimal::{Decimal, RoundingStrategy};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::{path::Path, str::FromStr};
 use thiserror::Error;
 
-#[derive(Deserialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
 pub struct TxRequest {
     #[serde(rename = "type")]
     pub tx_type: TxType,
@@ -12,6 +20,26 @@ pub struct TxRequest {
     #[serde(rename = "tx")]
     pub tx_id: TxId,
     pub amount: Option<Decimal>,
+    /// Idempotency key, a request resubmitted with the key gets the recorded outcome
+    #[serde(default)]
+    pub key: Option<String>,
+    /// Time of the request, disputes without time are at the current time of the ledger clock
+    #[serde(default)]
+    pub time: Option<Timestamp>,
+}
+
+impl TxRequest {
+    /// Checks the amount of the request received from the network,
+    ///   deposits and withdrawals require the positive amount
+    pub fn check_amount(&self) -> Result<(), String> {
+        match (self.tx_type, self.amount) {
+            (TxType::Deposit | TxType::Withdrawal, None) => Err("tx has no amount".into()),
+            (_, Some(amount)) if amount <= Decimal::ZERO => {
+                Err(format!("amount {amount} is not positive"))
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -35,31 +63,24 @@ pub enum ExecError {
     TxError(#[from] TxError),
 }
 
+/// Fails the checked arithmetic of amounts outside operations, as `common::checked` does
+pub(crate) fn checked_amount(v: Option<Decimal>) -> Result<Decimal, ExecError> {
+    v.ok_or_else(|| ExecError::StringError("amount overflow".to_string()))
+}
+
 pub fn execute_csv_file(path: impl AsRef<Path>, ledger: &mut dyn Ledger) -> Result<(), ExecError> {
     let mut f = std::fs::File::open(path)?;
     execute_csv(&mut f, ledger)
 }
 
 pub fn execute_csv(rd: impl std::io::Read, ledger: &mut dyn Ledger) -> Result<(), ExecError> {
-    let mut rdr = csv::ReaderBuilder::new()
-        .delimiter(b',')
-        .comment(Some(b'#'))
-        .flexible(true)
-        .trim(csv::Trim::All)
-        .from_reader(rd);
-    for result in rdr.deserialize() {
-        let r: TxRequest = result?;
-        use TxType::*;
-        let err = match (r.tx_type, r.amount) {
-            (Deposit, Some(amount)) => ledger.deposit(r.client, r.tx_id, amount),
-            (Deposit, None) => Err(TxError::StringError("deposit has no amount".into())),
-            (Withdrawal, Some(amount)) => ledger.withdrawal(r.client, r.tx_id, amount),
-            (Withdrawal, None) => Err(TxError::StringError("withdrawal has no amount".into())),
-            (Dispute, _) => ledger.dispute(r.client, r.tx_id),
-            (Resolve, _) => ledger.resolve(r.client, r.tx_id),
-            (Chargeback, _) => ledger.chargeback(r.client, r.tx_id),
-        };
-        match err {
+    execute_source(CsvSource::new(rd), ledger)
+}
+
+pub fn execute_source(src: impl TxSource, ledger: &mut dyn Ledger) -> Result<(), ExecError> {
+    for result in src {
+        let (_, r) = result?;
+        match apply_request(ledger, &r) {
             Err(TxError::Rejected(_e)) => Ok(()),
             Err(TxError::Ignored(_e)) => Ok(()),
             e => e,
@@ -68,6 +89,125 @@ pub fn execute_csv(rd: impl std::io::Read, ledger: &mut dyn Ledger) -> Result<()
     Ok(())
 }
 
+/// Applies the transaction request to the ledger
+///
+/// The outcome of the request with the idempotency key is recorded in the ledger,
+///   the request resubmitted with the key returns it without being applied again,
+///   another request with the same key is rejected. Failures are not recorded.
+/// The applied outcome is written with the operation, so a retry of the request
+///   interrupted by a crash is either applied or returns the recorded outcome.
+pub fn apply_request<L: Ledger + ?Sized>(ledger: &mut L, r: &TxRequest) -> Result<(), TxError> {
+    let Some(key) = &r.key else {
+        return apply_operation(ledger, r);
+    };
+    if let Some(recorded) = ledger.get_recorded(key)? {
+        return match (
+            recorded.tx_type,
+            recorded.client,
+            recorded.tx_id,
+            recorded.amount,
+        ) == (r.tx_type, r.client, r.tx_id, r.amount)
+        {
+            true => recorded.outcome.into_result(),
+            false => Err(TxError::Rejected(format!(
+                "idempotency key `{key}` is used by another request"
+            ))),
+        };
+    }
+    let recorded = Recorded {
+        tx_type: r.tx_type,
+        client: r.client,
+        tx_id: r.tx_id,
+        amount: r.amount,
+        outcome: Outcome::Applied,
+    };
+    let mut keyed = Keyed {
+        inner: ledger,
+        key,
+        recorded: Some(recorded.clone()),
+    };
+    let outcome = Outcome::of(apply_operation(&mut keyed, r))?;
+    if keyed.recorded.is_some() {
+        // nothing is written by the operation, its outcome is recorded alone
+        ledger.put_recorded(
+            key,
+            Recorded {
+                outcome: outcome.clone(),
+                ..recorded
+            },
+        )?;
+    }
+    outcome.into_result()
+}
+
+/// Ledger writing the recorded outcome of the keyed request with its operation
+struct Keyed<'a, L: ?Sized> {
+    inner: &'a mut L,
+    key: &'a str,
+    recorded: Option<Recorded>,
+}
+
+impl<L: Ledger + ?Sized> Ledger for Keyed<'_, L> {
+    fn policy(&self) -> Policy {
+        self.inner.policy()
+    }
+    fn now(&self) -> Timestamp {
+        self.inner.now()
+    }
+    fn get_account(&self, client: Client) -> Result<Option<Account>, std::io::Error> {
+        self.inner.get_account(client)
+    }
+    fn put_account(&mut self, client: Client, account: Account) -> Result<(), std::io::Error> {
+        self.inner.put_account(client, account)
+    }
+    fn accounts<'q>(&'q self) -> Box<dyn Iterator<Item = IterResult<(Client, Account)>> + 'q> {
+        self.inner.accounts()
+    }
+    fn get_transaction(&self, tx_id: TxId) -> Result<Option<Transaction>, std::io::Error> {
+        self.inner.get_transaction(tx_id)
+    }
+    fn put_transaction(&mut self, tx_id: TxId, tx: Transaction) -> Result<(), std::io::Error> {
+        self.inner.put_transaction(tx_id, tx)
+    }
+    fn transactions<'q>(
+        &'q self,
+    ) -> Box<dyn Iterator<Item = IterResult<(TxId, Transaction)>> + 'q> {
+        self.inner.transactions()
+    }
+    fn put_account_transaction(
+        &mut self,
+        client: Client,
+        account: Account,
+        tx_id: TxId,
+        tx: Transaction,
+    ) -> Result<(), std::io::Error> {
+        match self.recorded.take() {
+            Some(recorded) => self
+                .inner
+                .put_account_transaction_recorded(client, account, tx_id, tx, self.key, recorded),
+            None => self
+                .inner
+                .put_account_transaction(client, account, tx_id, tx),
+        }
+    }
+    fn get_recorded(&self, key: &str) -> Result<Option<Recorded>, std::io::Error> {
+        self.inner.get_recorded(key)
+    }
+}
+
+fn apply_operation<L: Ledger + ?Sized>(ledger: &mut L, r: &TxRequest) -> Result<(), TxError> {
+    use TxType::*;
+    match (r.tx_type, r.amount) {
+        (Deposit, Some(amount)) => ledger.deposit_at(r.client, r.tx_id, amount, r.time),
+        (Deposit, None) => Err(TxError::StringError("deposit has no amount".into())),
+        (Withdrawal, Some(amount)) => ledger.withdrawal_at(r.client, r.tx_id, amount, r.time),
+        (Withdrawal, None) => Err(TxError::StringError("withdrawal has no amount".into())),
+        (Dispute, _) => ledger.dispute_at(r.client, r.tx_id, r.time),
+        (Resolve, _) => ledger.resolve(r.client, r.tx_id),
+        (Chargeback, _) => ledger.chargeback(r.client, r.tx_id),
+    }
+}
+
 pub fn validate_accounts(rd: impl std::io::Read, ledger: &dyn Ledger) -> Result<(), ExecError> {
     validate_accounts_internal(rd, |c| ledger.get_account(c))
 }
@@ -76,12 +216,7 @@ pub fn validate_accounts_internal(
     rd: impl std::io::Read,
     get: impl Fn(Client) -> std::io::Result<Option<Account>>,
 ) -> Result<(), ExecError> {
-    let mut rdr = csv::ReaderBuilder::new()
-        .delimiter(b',')
-        .trim(csv::Trim::All)
-        .comment(Some(b'#'))
-        .flexible(true)
-        .from_reader(rd);
+    let mut rdr = csv_reader_builder().from_reader(rd);
     for result in rdr.deserialize() {
         let r: AccountState = result?;
         let client = r.client;
@@ -110,19 +245,397 @@ pub fn validate_accounts_internal(
     Ok(())
 }
 
+/// Columns of the accounts dump
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Column {
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+}
+
+impl Column {
+    pub const ALL: [Column; 5] = [
+        Column::Client,
+        Column::Available,
+        Column::Held,
+        Column::Total,
+        Column::Locked,
+    ];
+    pub fn name(&self) -> &'static str {
+        match self {
+            Column::Client => "client",
+            Column::Available => "available",
+            Column::Held => "held",
+            Column::Total => "total",
+            Column::Locked => "locked",
+        }
+    }
+}
+
+impl FromStr for Column {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Column::ALL
+            .into_iter()
+            .find(|c| c.name() == s.trim())
+            .ok_or_else(|| format!("unknown column `{s}`"))
+    }
+}
+
+/// Rounding strategy applied when decimals are dumped with a fixed scale
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Rounding {
+    #[default]
+    HalfEven,
+    HalfUp,
+    HalfDown,
+    Up,
+    Down,
+    Floor,
+    Ceiling,
+}
+
+impl FromStr for Rounding {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "half-even" | "bankers" => Ok(Rounding::HalfEven),
+            "half-up" => Ok(Rounding::HalfUp),
+            "half-down" => Ok(Rounding::HalfDown),
+            "up" => Ok(Rounding::Up),
+            "down" | "truncate" => Ok(Rounding::Down),
+            "floor" => Ok(Rounding::Floor),
+            "ceiling" => Ok(Rounding::Ceiling),
+            _ => Err(format!("unknown rounding strategy `{s}`")),
+        }
+    }
+}
+
+impl From<Rounding> for RoundingStrategy {
+    fn from(r: Rounding) -> Self {
+        match r {
+            Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::HalfDown => RoundingStrategy::MidpointTowardZero,
+            Rounding::Up => RoundingStrategy::AwayFromZero,
+            Rounding::Down => RoundingStrategy::ToZero,
+            Rounding::Floor => RoundingStrategy::ToNegativeInfinity,
+            Rounding::Ceiling => RoundingStrategy::ToPositiveInfinity,
+        }
+    }
+}
+
+/// Options of the accounts dump
+///
+/// The default options produce the same output as `dump_accounts` always did:
+/// all columns, ledger order and decimals as they are stored.
+#[derive(Clone, Debug)]
+pub struct DumpOptions {
+    /// Sort accounts by client
+    pub sorted: bool,
+    /// Fixed count of decimal places
+    pub scale: Option<u32>,
+    /// Rounding strategy used with a fixed scale
+    pub rounding: Rounding,
+    /// Columns to dump, in the given order
+    pub columns: Vec<Column>,
+}
+
+impl Default for DumpOptions {
+    fn default() -> Self {
+        Self {
+            sorted: false,
+            scale: None,
+            rounding: Default::default(),
+            columns: Column::ALL.to_vec(),
+        }
+    }
+}
+
+impl DumpOptions {
+    pub fn format_decimal(&self, v: Decimal) -> String {
+        match self.scale {
+            Some(scale) => {
+                let mut v = v.round_dp_with_strategy(scale, self.rounding.into());
+                v.rescale(scale);
+                v.to_string()
+            }
+            None => v.to_string(),
+        }
+    }
+    pub fn header(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.columns.iter().map(|c| c.name())
+    }
+    pub fn record(&self, client: Client, acc: &Account) -> Vec<String> {
+        self.columns
+            .iter()
+            .map(|c| match c {
+                Column::Client => client.0.to_string(),
+                Column::Available => self.format_decimal(acc.available),
+                Column::Held => self.format_decimal(acc.held),
+                Column::Total => self.format_decimal(acc.total),
+                Column::Locked => acc.locked.to_string(),
+            })
+            .collect()
+    }
+}
+
 pub fn dump_accounts(wr: impl std::io::Write, ledger: &dyn Ledger) -> Result<(), ExecError> {
+    dump_accounts_with(wr, ledger, &Default::default())
+}
+
+pub fn dump_accounts_with(
+    wr: impl std::io::Write,
+    ledger: &dyn Ledger,
+    opts: &DumpOptions,
+) -> Result<(), ExecError> {
+    if opts.sorted {
+        let accounts = sorted_accounts(ledger.accounts())?;
+        write_accounts(wr, accounts.into_iter().map(Ok), opts)
+    } else {
+        write_accounts(wr, ledger.accounts(), opts)
+    }
+}
+
+pub fn sorted_accounts(
+    accounts: impl Iterator<Item = IterResult<(Client, Account)>>,
+) -> Result<Vec<(Client, Account)>, std::io::Error> {
+    let mut accounts = accounts.collect::<Result<Vec<_>, _>>()?;
+    accounts.sort_by_key(|(client, _)| client.0);
+    Ok(accounts)
+}
+
+pub fn write_accounts(
+    wr: impl std::io::Write,
+    accounts: impl Iterator<Item = IterResult<(Client, Account)>>,
+    opts: &DumpOptions,
+) -> Result<(), ExecError> {
     let mut wrr = csv::WriterBuilder::new().delimiter(b',').from_writer(wr);
-    for pair in ledger.accounts() {
-        match pair {
-            Ok((client, state)) => wrr.serialize(AccountState {
-                client,
-                available: state.available,
-                total: state.total,
-                held: state.held,
-                locked: state.locked,
-            }),
-            Err(e) => Err(e.into()),
-        }?;
+    let mut header = Some(opts.header());
+    for pair in accounts {
+        let (client, state) = pair?;
+        if let Some(header) = header.take() {
+            wrr.write_record(header)?;
+        }
+        wrr.write_record(opts.record(client, &state))?;
     }
+    wrr.flush()?;
     Ok(())
 }
+
+/// Dumps transactions as `tx,client,amount,state`, sorted by transaction id if requested;
+///   the `time` column is added when some transaction has a timestamp
+pub fn dump_transactions(
+    wr: impl std::io::Write,
+    ledger: &dyn Ledger,
+    sorted: bool,
+) -> Result<(), ExecError> {
+    let mut transactions = ledger.transactions().collect::<Result<Vec<_>, _>>()?;
+    if sorted {
+        transactions.sort_by_key(|(tx_id, _)| tx_id.0);
+    }
+    write_transactions(wr, transactions.into_iter().map(Ok))
+}
+
+pub fn write_transactions(
+    wr: impl std::io::Write,
+    transactions: impl Iterator<Item = IterResult<(TxId, Transaction)>>,
+) -> Result<(), ExecError> {
+    let transactions = transactions.collect::<Result<Vec<_>, _>>()?;
+    let timed = transactions.iter().any(|(_, tx)| tx.time.is_some());
+    let mut wrr = csv::WriterBuilder::new().delimiter(b',').from_writer(wr);
+    let mut header = vec!["tx", "client", "amount", "state"];
+    if timed {
+        header.push("time");
+    }
+    wrr.write_record(header)?;
+    for (tx_id, tx) in transactions {
+        let mut record = vec![
+            tx_id.0.to_string(),
+            tx.client.0.to_string(),
+            tx.amount.to_string(),
+            state_name(tx.state).to_string(),
+        ];
+        if timed {
+            record.push(tx.time.map(|t| t.to_string()).unwrap_or_default());
+        }
+        wrr.write_record(record)?;
+    }
+    wrr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic::HashLedger;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_dump_options() -> Result<(), ExecError> {
+        let mut ledger = HashLedger::new();
+        // as the serde writer did, there is no header without accounts
+        let mut out = Vec::new();
+        dump_accounts(&mut out, &ledger)?;
+        assert!(out.is_empty());
+        for (c, tx, amount) in [(3, 1, dec!(1.125)), (1, 2, dec!(2)), (2, 3, dec!(0.135))] {
+            ledger.deposit(Client(c), TxId(tx), amount)?;
+        }
+        let mut out = Vec::new();
+        let opts = DumpOptions {
+            sorted: true,
+            scale: Some(2),
+            rounding: "half-up".parse().unwrap(),
+            columns: vec![Column::Client, Column::Total],
+        };
+        dump_accounts_with(&mut out, &ledger, &opts)?;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,total\n1,2.00\n2,0.14\n3,1.13\n"
+        );
+        let mut out = Vec::new();
+        let opts = DumpOptions {
+            rounding: Rounding::HalfEven,
+            ..opts
+        };
+        dump_accounts_with(&mut out, &ledger, &opts)?;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,total\n1,2.00\n2,0.14\n3,1.12\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_transactions() -> Result<(), ExecError> {
+        let mut ledger = HashLedger::new();
+        ledger.deposit(Client(2), TxId(3), dec!(1.5))?;
+        ledger.deposit(Client(1), TxId(1), dec!(2))?;
+        ledger.dispute(Client(1), TxId(1))?;
+        let mut out = Vec::new();
+        dump_transactions(&mut out, &ledger, true)?;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "tx,client,amount,state\n1,1,2,disputed\n3,2,1.5,committed\n"
+        );
+        ledger.deposit_at(Client(2), TxId(4), dec!(1), Some(Timestamp(1_700_000_000)))?;
+        let mut out = Vec::new();
+        dump_transactions(&mut out, &ledger, true)?;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "tx,client,amount,state,time\n1,1,2,disputed,\n3,2,1.5,committed,\n\
+            4,2,1,committed,1700000000\n"
+        );
+        Ok(())
+    }
+
+    fn check_idempotency_keys(ledger: &mut dyn Ledger) -> Result<(), ExecError> {
+        let input = "type,client,tx,amount,key
+            deposit,1,1,10,a
+            dispute,1,1,,b
+            dispute,1,1,,b
+            chargeback,1,1,,c
+            chargeback,1,1,,c
+            chargeback,1,1,,
+            deposit,1,2,5,a
+            deposit,1,1,10,a";
+        let mut outcomes = Vec::new();
+        for item in CsvSource::new(input.as_bytes()) {
+            let (_, r) = item?;
+            outcomes.push(Outcome::of(apply_request(ledger, &r))?.to_string());
+        }
+        assert_eq!(
+            outcomes,
+            [
+                "applied",
+                "applied",
+                "applied",
+                "applied",
+                "applied",
+                "rejected: transaction is not disputed",
+                "rejected: idempotency key `a` is used by another request",
+                "applied",
+            ]
+        );
+        let account = ledger.get_account(Client(1))?.unwrap();
+        assert_eq!(
+            (account.total, account.held, account.locked),
+            (dec!(0), dec!(0), true)
+        );
+        assert_eq!(ledger.transactions().count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_idempotency_keys() -> Result<(), ExecError> {
+        check_idempotency_keys(&mut HashLedger::new())?;
+        check_idempotency_keys(&mut crate::advanced::SledLedger::new().unwrap())
+    }
+
+    fn check_time_policies(
+        ledger: &mut dyn Ledger,
+        clock: &crate::common::ManualClock,
+    ) -> Result<(), ExecError> {
+        let input = "type,client,tx,amount,time
+            deposit,1,1,10,2024-01-01T00:00:00Z
+            deposit,1,2,5,2024-03-01T00:00:00Z
+            deposit,1,3,1,
+            deposit,1,4,2,1706745600
+            dispute,1,1,,2024-06-01T00:00:00Z
+            dispute,1,2,,2024-06-01T00:00:00Z
+            resolve,1,2,,
+            dispute,1,3,,
+            dispute,1,4,,";
+        let mut outcomes = Vec::new();
+        for item in CsvSource::new(input.as_bytes()) {
+            let (_, r) = item?;
+            if r.tx_type == TxType::Dispute && r.tx_id == TxId(4) {
+                clock.set("2025-03-01T00:00:00Z".parse().unwrap());
+            }
+            outcomes.push(Outcome::of(apply_request(ledger, &r))?.to_string());
+        }
+        assert_eq!(
+            outcomes[4..],
+            [
+                "rejected: dispute window has passed",
+                "applied",
+                "applied",
+                "applied",
+                "rejected: can not be disputed",
+            ]
+        );
+        let tx = ledger.get_transaction(TxId(1))?.unwrap();
+        assert_eq!(tx.time, Some(Timestamp(1704067200)));
+        // the deposits 1 and 4 are older than a year
+        assert_eq!(ledger.finalize_expired()?, 2);
+        assert_eq!(ledger.finalize_expired()?, 0);
+        let states: Vec<_> = (1..=4)
+            .map(|tx| ledger.get_transaction(TxId(tx)).unwrap().unwrap().state)
+            .collect();
+        use crate::common::TxState::*;
+        assert_eq!(states, [Finalized, Finalized, Disputed, Finalized]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_policies() -> Result<(), ExecError> {
+        use crate::common::{ManualClock, Policy};
+        use std::{sync::Arc, time::Duration};
+        let day = Duration::from_secs(24 * 60 * 60);
+        let policy = Policy {
+            dispute_window: Some(120 * day),
+            finalize_after: Some(365 * day),
+            ..Default::default()
+        };
+        let now = "2024-06-01T00:00:00Z".parse().unwrap();
+        let clock = Arc::new(ManualClock::new(now));
+        let mut ledger = HashLedger::with_policy(policy).with_clock(clock.clone());
+        check_time_policies(&mut ledger, &clock)?;
+        let clock = Arc::new(ManualClock::new(now));
+        let ledger = crate::advanced::SledLedger::new_empty(None, policy).unwrap();
+        let mut ledger = ledger.with_clock(clock.clone());
+        check_time_policies(&mut ledger, &clock)
+    }
+}
@@ -0,0 +1,413 @@
+//! Notifications of ledger events matching configured rules
+//!
+//! The `Notifier` consumes the events of an `EventBus` channel in its own thread,
+//!   so slow sinks do not delay ledger writes, a bounded channel drops the notifier
+//!   falling behind. A notification is a JSON object
+//!   of the matched rule, the client and the event:
+//! ```text
+//! {"rule":"account-locked","client":1,"event":{"seq":7,"event":"account_locked",...}}
+//! ```
+use crate::{
+    common::{Account, Client},
+    events::{Event, EventKind},
+};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::{Read, Write},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+/// Condition of an event to notify about
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rule {
+    AccountLocked,
+    DisputeOpened,
+    /// Available funds of the account drop below the threshold
+    BalanceBelow(Decimal),
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rule::AccountLocked => write!(f, "account-locked"),
+            Rule::DisputeOpened => write!(f, "dispute-opened"),
+            Rule::BalanceBelow(threshold) => write!(f, "balance-below:{threshold}"),
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().split_once(':') {
+            None if s.trim() == "account-locked" => Ok(Rule::AccountLocked),
+            None if s.trim() == "dispute-opened" => Ok(Rule::DisputeOpened),
+            Some(("balance-below", threshold)) => threshold
+                .trim()
+                .parse()
+                .map(Rule::BalanceBelow)
+                .map_err(|_| format!("bad threshold `{threshold}`")),
+            _ => Err(format!(
+                "unknown rule `{s}`, expected account-locked, dispute-opened \
+                 or balance-below:AMOUNT"
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Notification {
+    pub rule: String,
+    pub client: Client,
+    pub event: Event,
+}
+
+/// Destination of notifications
+#[derive(Clone, Debug)]
+pub enum Sink {
+    /// POSTs the JSON to the `http://` url, retried after `backoff` doubled on every
+    ///   attempt, notifications failed all attempts are appended to the dead letter file
+    Http {
+        url: String,
+        retries: usize,
+        backoff: Duration,
+        dead_letter: Option<PathBuf>,
+    },
+    /// Runs the shell command with the JSON on its stdin
+    Command(String),
+    /// Prints the JSON line
+    Stdout,
+}
+
+impl Sink {
+    pub fn http(url: impl Into<String>) -> Self {
+        Sink::Http {
+            url: url.into(),
+            retries: 3,
+            backoff: Duration::from_millis(100),
+            dead_letter: None,
+        }
+    }
+
+    fn deliver(&self, json: &str) -> std::io::Result<()> {
+        match self {
+            Sink::Http {
+                url,
+                retries,
+                backoff,
+                dead_letter,
+            } => {
+                let mut delay = *backoff;
+                let mut result = post(url, json);
+                for _ in 0..*retries {
+                    if result.is_ok() {
+                        break;
+                    }
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                    result = post(url, json);
+                }
+                match (result, dead_letter) {
+                    (Err(_), Some(path)) => {
+                        let mut f = std::fs::OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(path)?;
+                        writeln!(f, "{json}")
+                    }
+                    (result, _) => result,
+                }
+            }
+            Sink::Command(cmd) => {
+                let mut child = std::process::Command::new("sh")
+                    .args(["-c", cmd])
+                    .stdin(std::process::Stdio::piped())
+                    .spawn()?;
+                let written = child.stdin.take().unwrap().write_all(json.as_bytes());
+                let status = child.wait()?;
+                written?;
+                match status.success() {
+                    true => Ok(()),
+                    false => Err(std::io::Error::other(format!("`{cmd}` failed: {status}"))),
+                }
+            }
+            Sink::Stdout => writeln!(std::io::stdout().lock(), "{json}"),
+        }
+    }
+}
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Minimal HTTP/1.1 POST, fails unless the status is 2xx
+fn post(url: &str, json: &str) -> std::io::Result<()> {
+    let bad_url =
+        || std::io::Error::other(format!("bad url `{url}`, expected http://HOST:PORT/PATH"));
+    let rest = url.strip_prefix("http://").ok_or_else(bad_url)?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let addr = std::net::ToSocketAddrs::to_socket_addrs(host)?
+        .next()
+        .ok_or_else(bad_url)?;
+    let mut stream = std::net::TcpStream::connect_timeout(&addr, HTTP_TIMEOUT)?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{json}",
+        json.len()
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    match response.split(' ').nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        status => Err(std::io::Error::other(format!(
+            "{url} replied {}",
+            status.unwrap_or("nothing")
+        ))),
+    }
+}
+
+/// Matches events against the rules and delivers notifications to the sinks
+pub struct Notifier {
+    rules: Vec<Rule>,
+    sinks: Vec<Sink>,
+    /// Clients whose available funds are below the threshold of the rule
+    below: HashMap<(usize, Client), bool>,
+}
+
+impl Notifier {
+    pub fn new(rules: Vec<Rule>, sinks: Vec<Sink>) -> Self {
+        Self {
+            rules,
+            sinks,
+            below: HashMap::new(),
+        }
+    }
+
+    /// Notifications of the event, the balance rule fires once the funds drop below
+    pub fn notifications(&mut self, event: &Event) -> Vec<Notification> {
+        let (client, account): (Client, Option<&Account>) = match &event.kind {
+            EventKind::DepositApplied {
+                client, account, ..
+            }
+            | EventKind::WithdrawalApplied {
+                client, account, ..
+            }
+            | EventKind::DisputeOpened {
+                client, account, ..
+            }
+            | EventKind::DisputeResolved {
+                client, account, ..
+            }
+            | EventKind::Chargeback {
+                client, account, ..
+            }
+            | EventKind::AccountLocked { client, account }
+            | EventKind::AccountUpdated { client, account } => (*client, Some(account)),
+            EventKind::TransactionUpdated { transaction, .. }
+            | EventKind::TransactionRemoved { transaction, .. } => (transaction.client, None),
+            EventKind::AccountRemoved { client, .. } => (*client, None),
+        };
+        let mut notifications = Vec::new();
+        for (i, rule) in self.rules.iter().enumerate() {
+            let matched = match (rule, &event.kind, account) {
+                (Rule::AccountLocked, EventKind::AccountLocked { .. }, _) => true,
+                (Rule::DisputeOpened, EventKind::DisputeOpened { .. }, _) => true,
+                (Rule::BalanceBelow(threshold), _, Some(account)) => {
+                    let below = account.available < *threshold;
+                    let was_below = self.below.insert((i, client), below);
+                    below && was_below != Some(true)
+                }
+                _ => false,
+            };
+            if matched {
+                notifications.push(Notification {
+                    rule: rule.to_string(),
+                    client,
+                    event: event.clone(),
+                });
+            }
+        }
+        notifications
+    }
+
+    /// Delivers the notification to every sink, returns errors of the failed deliveries
+    pub fn notify(&self, notification: &Notification) -> Vec<std::io::Error> {
+        let json = serde_json::to_string(notification).unwrap();
+        self.sinks
+            .iter()
+            .filter_map(|sink| sink.deliver(&json).err())
+            .collect()
+    }
+
+    /// Notifies about the received events until the channel is disconnected,
+    ///   `failed` is called for every failed delivery
+    pub fn spawn(
+        mut self,
+        events: crossbeam_channel::Receiver<Event>,
+        failed: impl Fn(&Notification, std::io::Error) + Send + 'static,
+    ) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            for event in events {
+                for notification in self.notifications(&event) {
+                    for e in self.notify(&notification) {
+                        failed(&notification, e);
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        basic::HashLedger,
+        common::{Ledger, TxError, TxId},
+        events::{EventBus, EventLedger},
+    };
+    use rust_decimal_macros::dec;
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    /// HTTP receiver replying the statuses in turn, sends the received bodies
+    fn stub_receiver(statuses: Vec<u16>) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (body_s, body_r) = mpsc::channel();
+        std::thread::spawn(move || {
+            for (stream, status) in listener.incoming().zip(statuses) {
+                let mut rd = BufReader::new(stream.unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    rd.read_line(&mut line).unwrap();
+                    match line.trim().to_lowercase().strip_prefix("content-length:") {
+                        Some(n) => length = n.trim().parse().unwrap(),
+                        None if line.trim().is_empty() => break,
+                        None => (),
+                    }
+                }
+                let mut body = vec![0; length];
+                rd.read_exact(&mut body).unwrap();
+                write!(
+                    rd.get_mut(),
+                    "HTTP/1.1 {status} Stub\r\nContent-Length: 0\r\n\r\n"
+                )
+                .unwrap();
+                body_s.send(String::from_utf8(body).unwrap()).unwrap();
+            }
+        });
+        (url, body_r)
+    }
+
+    fn run(rules: &str, sinks: Vec<Sink>) -> Result<(), TxError> {
+        let bus = EventBus::new();
+        let rules = rules.split(',').map(|r| r.parse().unwrap()).collect();
+        let notifier = Notifier::new(rules, sinks).spawn(bus.bounded_channel(), |_, _| ());
+        let mut ledger = EventLedger::new(HashLedger::new(), bus);
+        ledger.deposit(Client(1), TxId(1), dec!(10))?;
+        ledger.withdrawal(Client(1), TxId(2), dec!(6))?;
+        ledger.withdrawal(Client(1), TxId(3), dec!(1))?;
+        ledger.deposit(Client(2), TxId(4), dec!(1))?;
+        ledger.dispute(Client(2), TxId(4))?;
+        ledger.chargeback(Client(2), TxId(4))?;
+        drop(ledger);
+        notifier.join().unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_http_retries() -> Result<(), TxError> {
+        let (url, bodies) = stub_receiver(vec![500, 503, 200, 200, 200, 200]);
+        let sink = Sink::Http {
+            url,
+            retries: 2,
+            backoff: Duration::from_millis(1),
+            dead_letter: None,
+        };
+        run("balance-below:5,dispute-opened,account-locked", vec![sink])?;
+        let notified: Vec<Notification> = bodies
+            .try_iter()
+            .map(|b| serde_json::from_str(&b).unwrap())
+            .collect();
+        let notified: Vec<_> = notified
+            .iter()
+            .map(|n| (n.rule.as_str(), n.client.0, n.event.seq))
+            .collect();
+        // the first notification is retried twice, the balance crosses the threshold once
+        assert_eq!(
+            notified,
+            [
+                ("balance-below:5", 1, 2),
+                ("balance-below:5", 1, 2),
+                ("balance-below:5", 1, 2),
+                ("balance-below:5", 2, 4),
+                ("dispute-opened", 2, 5),
+                ("account-locked", 2, 7),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dead_letter_and_command() -> Result<(), TxError> {
+        let dir = std::env::temp_dir().join(format!("toybank-notify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (dead_letter, hooked) = (dir.join("dead.jsonl"), dir.join("hooked.jsonl"));
+        let (url, bodies) = stub_receiver(vec![500; 4]);
+        let sinks = vec![
+            Sink::Http {
+                url,
+                retries: 1,
+                backoff: Duration::from_millis(1),
+                dead_letter: Some(dead_letter.clone()),
+            },
+            Sink::Command(format!(
+                "cat >> {}; echo >> {}",
+                hooked.display(),
+                hooked.display()
+            )),
+        ];
+        run("account-locked,dispute-opened", sinks)?;
+        assert_eq!(bodies.try_iter().count(), 4);
+        let read = |path| -> Vec<Notification> {
+            std::fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .map(|l| serde_json::from_str(l).unwrap())
+                .collect()
+        };
+        let dead = read(&dead_letter);
+        assert_eq!(dead.len(), 2);
+        assert_eq!(dead, read(&hooked));
+        assert_eq!(dead[1].rule, "account-locked");
+        std::fs::remove_dir_all(&dir).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_rules() {
+        assert_eq!(
+            "balance-below:1.5".parse(),
+            Ok(Rule::BalanceBelow(dec!(1.5)))
+        );
+        assert_eq!(
+            Rule::BalanceBelow(dec!(1.5)).to_string(),
+            "balance-below:1.5"
+        );
+        assert!("balance-above:1".parse::<Rule>().is_err());
+    }
+}
@@ -0,0 +1,409 @@
+use crate::{
+    dialect::{Columns, Dialect},
+    libcsv::{ExecError, TxRequest},
+    source::{Position, SourceItem},
+};
+use crossbeam_channel::{bounded, Receiver, Sender};
+use csv::StringRecord;
+use std::{
+    io::Read,
+    thread::{self, JoinHandle, Scope},
+};
+
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Options of the pipelined csv parsing
+#[derive(Clone, Copy, Debug)]
+pub struct ParallelOptions {
+    /// Count of parsing threads
+    pub parsers: usize,
+    /// Bytes read at once, a chunk holds whole records so it can be larger
+    pub chunk_size: usize,
+}
+
+impl Default for ParallelOptions {
+    fn default() -> Self {
+        Self {
+            parsers: thread::available_parallelism().map_or(1, |n| n.get()),
+            chunk_size: CHUNK_SIZE,
+        }
+    }
+}
+
+/// Record parsed by a parsing thread, the record index is relative to its chunk
+pub(crate) type Parsed = Result<(Position, Result<TxRequest, String>), ExecError>;
+
+/// Whole records of the input following its header
+pub(crate) struct Chunk {
+    data: Vec<u8>,
+    first_line: u64,
+    columns: Columns,
+}
+
+struct Job {
+    chunk: Chunk,
+    done: Sender<(Vec<Parsed>, u64)>,
+}
+
+/// Splits the input appended to its buffer into chunks of whole records
+pub(crate) struct Chunker {
+    dialect: Dialect,
+    builder: csv::ReaderBuilder,
+    columns: Option<Columns>,
+    buf: Vec<u8>,
+    line: u64,
+}
+
+impl Chunker {
+    pub(crate) fn new(dialect: &Dialect) -> Result<Self, ExecError> {
+        Ok(Self {
+            dialect: dialect.clone(),
+            builder: chunk_reader(dialect)?,
+            columns: match dialect.has_headers {
+                true => None,
+                false => Some(dialect.resolve(None)?),
+            },
+            buf: Vec::new(),
+            line: 1,
+        })
+    }
+
+    /// The buffer to append the input to
+    pub(crate) fn buf(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+
+    /// Takes the whole records of the buffer, all of it at the end of the input,
+    ///   the header is resolved from the first record
+    pub(crate) fn take(&mut self, eof: bool) -> Result<Option<Chunk>, ExecError> {
+        let end = match eof {
+            true if self.buf.is_empty() => return Ok(None),
+            true => {
+                // the terminating newline makes the line of the last record countable
+                if !self.buf.ends_with(b"\n") {
+                    self.buf.push(b'\n');
+                }
+                self.buf.len()
+            }
+            false => match last_record_end(&self.dialect, &self.buf) {
+                Some(end) => end,
+                // the record is longer than the buffer
+                None => return Ok(None),
+            },
+        };
+        let rest = self.buf.split_off(end);
+        let mut data = std::mem::replace(&mut self.buf, rest);
+        let mut first_line = self.line;
+        self.line += data.iter().filter(|b| **b == b'\n').count() as u64;
+        if self.columns.is_none() {
+            let mut rdr = self.builder.from_reader(&data[..]);
+            let mut header = StringRecord::new();
+            if rdr.read_record(&mut header)? {
+                self.columns = Some(self.dialect.resolve(Some(&header))?);
+                first_line += rdr.position().line() - 1;
+                data.drain(..rdr.position().byte() as usize);
+            } else {
+                data.clear();
+            }
+        }
+        Ok(match (self.columns, data.is_empty()) {
+            (Some(columns), false) => Some(Chunk {
+                data,
+                first_line,
+                columns,
+            }),
+            _ => None,
+        })
+    }
+}
+
+/// Transaction requests read from csv with the pipeline of threads
+///
+/// The reader thread splits the input into chunks at record boundaries,
+///   the chunks are parsed by the pool of threads in parallel,
+///   and their records are yielded in the input order with the same positions
+///   `CsvSource` gives them, so the order of requests of every client is intact.
+/// Records must be terminated by `\n` (or `\r\n`).
+pub struct ParallelCsvSource {
+    order: Receiver<Receiver<(Vec<Parsed>, u64)>>,
+    items: std::vec::IntoIter<Parsed>,
+    base: u64,
+    next_base: u64,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl ParallelCsvSource {
+    /// The source with its own threads, they are joined when the source is dropped
+    pub fn new<R: Read + Send + 'static>(
+        dialect: &Dialect,
+        rd: R,
+        opts: &ParallelOptions,
+    ) -> Result<Self, ExecError> {
+        let mut threads = Vec::new();
+        let mut src = Self::start(dialect, rd, opts, |f| threads.push(thread::spawn(f)))?;
+        src.threads = threads;
+        Ok(src)
+    }
+
+    /// The source with threads of the scope, for readers borrowing their input
+    pub fn scoped<'scope, R: Read + Send + 'scope>(
+        scope: &'scope Scope<'scope, '_>,
+        dialect: &Dialect,
+        rd: R,
+        opts: &ParallelOptions,
+    ) -> Result<Self, ExecError> {
+        Self::start(dialect, rd, opts, |f| {
+            scope.spawn(f);
+        })
+    }
+
+    fn start<'a, R: Read + Send + 'a>(
+        dialect: &Dialect,
+        rd: R,
+        opts: &ParallelOptions,
+        mut spawn: impl FnMut(Box<dyn FnOnce() + Send + 'a>),
+    ) -> Result<Self, ExecError> {
+        let parsers = opts.parsers.max(1);
+        let (job_s, job_r) = bounded::<Job>(parsers);
+        let (order_s, order_r) = bounded(2 * parsers);
+        for _ in 0..parsers {
+            let (dialect, builder, job_r) =
+                (dialect.clone(), chunk_reader(dialect)?, job_r.clone());
+            spawn(Box::new(move || {
+                for job in job_r {
+                    let _ = job.done.send(parse_chunk(&dialect, &builder, &job.chunk));
+                }
+            }));
+        }
+        let chunker = Chunker::new(dialect)?;
+        let chunk_size = opts.chunk_size.max(1);
+        spawn(Box::new(move || {
+            if let Err(e) = split_chunks(chunker, rd, chunk_size, &job_s, &order_s) {
+                let (done, r) = bounded(1);
+                let _ = done.send((vec![Err(e)], 0));
+                let _ = order_s.send(r);
+            }
+        }));
+        Ok(Self {
+            order: order_r,
+            items: Vec::new().into_iter(),
+            base: 0,
+            next_base: 0,
+            threads: Vec::new(),
+        })
+    }
+}
+
+impl Drop for ParallelCsvSource {
+    fn drop(&mut self) {
+        // the pending chunks are dropped, so the threads stop at their next send
+        drop(std::mem::replace(
+            &mut self.order,
+            crossbeam_channel::never(),
+        ));
+        for t in self.threads.drain(..) {
+            let _ = t.join();
+        }
+    }
+}
+
+impl Iterator for ParallelCsvSource {
+    type Item = SourceItem;
+    fn next(&mut self) -> Option<SourceItem> {
+        loop {
+            if let Some(parsed) = self.items.next() {
+                return Some(source_item(parsed, self.base));
+            }
+            let (items, records) = match self.order.recv().ok()?.recv() {
+                Ok(parsed) => parsed,
+                Err(_) => (
+                    vec![Err(ExecError::StringError("csv parser failed".into()))],
+                    0,
+                ),
+            };
+            self.items = items.into_iter();
+            self.base = self.next_base;
+            self.next_base += records;
+        }
+    }
+}
+
+/// Chunks are read without headers, the header is resolved once by the chunker
+pub(crate) fn chunk_reader(dialect: &Dialect) -> Result<csv::ReaderBuilder, ExecError> {
+    let mut builder = dialect.reader_builder()?;
+    builder.has_headers(false);
+    Ok(builder)
+}
+
+/// Reads the input and sends its chunks to parsers, the chunk results in the input order
+fn split_chunks(
+    mut chunker: Chunker,
+    mut rd: impl Read,
+    chunk_size: usize,
+    jobs: &Sender<Job>,
+    order: &Sender<Receiver<(Vec<Parsed>, u64)>>,
+) -> Result<(), ExecError> {
+    loop {
+        let eof = (&mut rd)
+            .take(chunk_size as u64)
+            .read_to_end(chunker.buf())?
+            < chunk_size;
+        if let Some(chunk) = chunker.take(eof)? {
+            let (done, r) = bounded(1);
+            if order.send(r).is_err() {
+                return Ok(()); // the source is dropped
+            }
+            if jobs.send(Job { chunk, done }).is_err() {
+                return Ok(());
+            }
+        }
+        if eof {
+            return Ok(());
+        }
+    }
+}
+
+/// Parses the chunk, returns its records and the count of them
+pub(crate) fn parse_chunk(
+    dialect: &Dialect,
+    builder: &csv::ReaderBuilder,
+    chunk: &Chunk,
+) -> (Vec<Parsed>, u64) {
+    let mut rdr = builder.from_reader(&chunk.data[..]);
+    let (mut record, mut parsed, mut count) = (StringRecord::new(), Vec::new(), 0);
+    loop {
+        match rdr.read_record(&mut record) {
+            Ok(false) => return (parsed, count),
+            Err(e) => parsed.push(Err(e.into())),
+            Ok(true) => {
+                // the same counting of lines as `CsvSource` does
+                let newlines = record
+                    .iter()
+                    .map(|f| f.matches('\n').count() as u64)
+                    .sum::<u64>();
+                let pos = Position {
+                    line: chunk.first_line + rdr.position().line() - 2 - newlines,
+                    record: count,
+                };
+                count += 1;
+                parsed.push(Ok((pos, dialect.request(&chunk.columns, &record))));
+            }
+        }
+    }
+}
+
+/// The item of the record parsed in the chunk following `base` records
+pub(crate) fn source_item(parsed: Parsed, base: u64) -> SourceItem {
+    parsed.and_then(|(mut pos, r)| {
+        pos.record += base;
+        r.map(|r| (pos, r))
+            .map_err(|e| ExecError::StringError(format!("{pos}: {e}")))
+    })
+}
+
+/// Finds the end of the last whole record in the buffer starting with a record,
+///   newlines in quoted fields and in comments do not end records
+fn last_record_end(dialect: &Dialect, buf: &[u8]) -> Option<usize> {
+    let quote = dialect.quoting.then_some(dialect.quote as u8);
+    let escape = dialect.escape.map(|c| c as u8);
+    let comment = dialect.comment.map(|c| c as u8);
+    let (mut quoted, mut escaped, mut in_comment, mut line_start) = (false, false, false, true);
+    let mut end = None;
+    for (i, b) in buf.iter().copied().enumerate() {
+        let starts = std::mem::replace(&mut line_start, false);
+        if in_comment {
+            in_comment = b != b'\n';
+            line_start = !in_comment;
+        } else if escaped {
+            escaped = false;
+        } else if starts && Some(b) == comment {
+            in_comment = true;
+        } else if quoted && Some(b) == escape {
+            escaped = true;
+        } else if Some(b) == quote {
+            quoted = !quoted;
+        } else if b == b'\n' && !quoted {
+            line_start = true;
+        }
+        if line_start {
+            end = Some(i + 1);
+        }
+    }
+    end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::CsvSource;
+
+    fn collect(src: impl Iterator<Item = SourceItem>) -> Vec<String> {
+        src.map(|item| format!("{item:?}")).collect()
+    }
+
+    #[test]
+    fn test_parallel_source_order() -> Result<(), ExecError> {
+        for chunk_size in [1, 7, 64, CHUNK_SIZE] {
+            let opts = ParallelOptions {
+                parsers: 3,
+                chunk_size,
+            };
+            let src = ParallelCsvSource::new(
+                &Default::default(),
+                crate::basic::TRANSACTIONS.as_bytes(),
+                &opts,
+            )?;
+            let expected = CsvSource::new(std::io::Cursor::new(crate::basic::TRANSACTIONS));
+            assert_eq!(collect(src), collect(expected), "chunk size {chunk_size}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_source_dropped_early() -> Result<(), ExecError> {
+        let opts = ParallelOptions {
+            parsers: 2,
+            chunk_size: 7,
+        };
+        let mut src = ParallelCsvSource::new(
+            &Default::default(),
+            crate::basic::TRANSACTIONS.as_bytes(),
+            &opts,
+        )?;
+        src.next().unwrap()?;
+        // the threads are stopped and joined
+        drop(src);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_source_quoted_records() -> Result<(), ExecError> {
+        let input = "type,client,tx,amount\n\
+            # a \"comment\n\
+            deposit,\"1\",1,1.0\n\
+            \"withdrawal\n\",1,2,0.5\n\
+            bad,1,3,1\n\
+            deposit,2,4,2.0";
+        let dialect = Dialect::default();
+        for chunk_size in [1, 5, 16, 1024] {
+            let opts = ParallelOptions {
+                parsers: 2,
+                chunk_size,
+            };
+            let src = thread::scope(|s| {
+                ParallelCsvSource::scoped(s, &dialect, input.as_bytes(), &opts).map(collect)
+            })?;
+            let expected = collect(CsvSource::new(std::io::Cursor::new(input)));
+            assert_eq!(src, expected, "chunk size {chunk_size}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_boundaries() {
+        let dialect = Dialect::default();
+        assert_eq!(last_record_end(&dialect, b"a,b\nc,\"d\ne"), Some(4));
+        assert_eq!(last_record_end(&dialect, b"#\"\na,\"\"\n"), Some(8));
+        assert_eq!(last_record_end(&dialect, b"a,\"b\n"), None);
+    }
+}
@@ -0,0 +1,469 @@
+//! Interactive session over a ledger
+//!
+//! Commands use the syntax of the cucumber steps:
+//! ```text
+//! tx 1 deposit 1.0 to 2
+//! tx 2 withdrawal 0.5 from 2
+//! dispute 1 for 2
+//! resolve 1 for 2
+//! chargeback 1 for 2
+//! account 2 has total 1.0 available 1.0 held 0
+//! account 2 is locked
+//! ```
+//! and queries `account <client>`, `tx <tx>`, `accounts`, `transactions`,
+//!   `undo`, `history`, `help` and `quit`.
+use crate::{
+    common::{Account, Client, Ledger, Transaction, TxId, TxType},
+    libcsv::{
+        apply_request, dump_accounts_with, dump_transactions, DumpOptions, ExecError, Outcome,
+        TxRequest,
+    },
+};
+use regex::Regex;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::{collections::BTreeSet, sync::OnceLock};
+
+pub const HELP: &str = "\
+tx <tx> deposit <amount> to <client>
+tx <tx> withdrawal <amount> from <client>
+dispute <tx> for <client>
+resolve <tx> for <client>
+chargeback <tx> for <client>
+account <client> has total <total> available <available> held <held>
+account <client> is locked
+account <client>        show the account
+tx <tx>                 show the transaction
+accounts                list accounts
+transactions            list transactions
+undo                    revert the last applied operation
+history                 list commands of the session
+quit
+";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Request(TxRequest),
+    Account(Client),
+    Transaction(TxId),
+    /// Checks balances of the account: total, available, held
+    AccountHas(Client, Decimal, Decimal, Decimal),
+    AccountIsLocked(Client),
+    Accounts,
+    Transactions,
+    Undo,
+    History,
+    Help,
+    Quit,
+}
+
+struct Syntax {
+    deposit: Regex,
+    withdrawal: Regex,
+    other: Regex,
+    has: Regex,
+    locked: Regex,
+    show: Regex,
+}
+
+fn syntax() -> &'static Syntax {
+    static SYNTAX: OnceLock<Syntax> = OnceLock::new();
+    SYNTAX.get_or_init(|| Syntax {
+        deposit: Regex::new(r"^tx\s+(\d+)\s+deposit\s+(\d*\.?\d+)\s+to\s+(\d+)$").unwrap(),
+        withdrawal: Regex::new(r"^tx\s+(\d+)\s+withdrawal\s+(\d*\.?\d+)\s+from\s+(\d+)$").unwrap(),
+        other: Regex::new(r"^(dispute|resolve|chargeback)\s+(\d+)\s+for\s+(\d+)$").unwrap(),
+        has: Regex::new(
+            r"^account\s+(\d+)\s+has\s+total[=\s](\d*\.?\d+)\s+available[=\s](\d*\.?\d+)\s+held[=\s](\d*\.?\d+)$",
+        )
+        .unwrap(),
+        locked: Regex::new(r"^account\s+(\d+)\s+is\s+locked$").unwrap(),
+        show: Regex::new(r"^(account|tx)\s+(\d+)$").unwrap(),
+    })
+}
+
+/// Parses the command, `None` is the empty line or the `#` comment
+pub fn parse(line: &str) -> Result<Option<Command>, String> {
+    let line = line.trim();
+    let syntax = syntax();
+    let number = |s: &str| s.parse::<u32>().map_err(|e| format!("`{s}`: {e}"));
+    let client = |s: &str| {
+        s.parse::<u16>()
+            .map(Client)
+            .map_err(|e| format!("`{s}`: {e}"))
+    };
+    let amount = |s: &str| Decimal::from_str_exact(s).map_err(|e| format!("`{s}`: {e}"));
+    let request = |tx_type, tx_id: &str, amount: Option<Decimal>, c: &str| {
+        Ok::<_, String>(Command::Request(TxRequest {
+            tx_type,
+            client: client(c)?,
+            tx_id: TxId(number(tx_id)?),
+            amount,
+            key: None,
+            time: None,
+        }))
+    };
+    let command = match line {
+        "" => return Ok(None),
+        _ if line.starts_with('#') => return Ok(None),
+        "accounts" => Command::Accounts,
+        "transactions" => Command::Transactions,
+        "undo" => Command::Undo,
+        "history" => Command::History,
+        "help" => Command::Help,
+        "quit" | "exit" => Command::Quit,
+        _ => {
+            if let Some(c) = syntax.deposit.captures(line) {
+                request(TxType::Deposit, &c[1], Some(amount(&c[2])?), &c[3])?
+            } else if let Some(c) = syntax.withdrawal.captures(line) {
+                request(TxType::Withdrawal, &c[1], Some(amount(&c[2])?), &c[3])?
+            } else if let Some(c) = syntax.other.captures(line) {
+                let tx_type = match &c[1] {
+                    "dispute" => TxType::Dispute,
+                    "resolve" => TxType::Resolve,
+                    _ => TxType::Chargeback,
+                };
+                request(tx_type, &c[2], None, &c[3])?
+            } else if let Some(c) = syntax.has.captures(line) {
+                Command::AccountHas(
+                    client(&c[1])?,
+                    amount(&c[2])?,
+                    amount(&c[3])?,
+                    amount(&c[4])?,
+                )
+            } else if let Some(c) = syntax.locked.captures(line) {
+                Command::AccountIsLocked(client(&c[1])?)
+            } else if let Some(c) = syntax.show.captures(line) {
+                match &c[1] {
+                    "account" => Command::Account(client(&c[2])?),
+                    _ => Command::Transaction(TxId(number(&c[2])?)),
+                }
+            } else {
+                return Err(format!("unknown command `{line}`, try `help`"));
+            }
+        }
+    };
+    Ok(Some(command))
+}
+
+/// The state of the records before the applied operation
+#[derive(Clone, Debug)]
+struct Change {
+    line: String,
+    client: Client,
+    account: Option<Account>,
+    tx_id: TxId,
+    tx: Option<Transaction>,
+}
+
+/// Structured reply to the transaction request
+#[derive(Serialize)]
+struct Reply {
+    outcome: Outcome,
+    account: Option<Account>,
+    transaction: Option<Transaction>,
+}
+
+/// Session of commands over the ledger, keeps the history and the undo log
+pub struct Session<L> {
+    pub ledger: L,
+    undo: Vec<Change>,
+    history: Vec<String>,
+}
+
+impl<L: Ledger> Session<L> {
+    pub fn new(ledger: L) -> Self {
+        Self {
+            ledger,
+            undo: Vec::new(),
+            history: Vec::new(),
+        }
+    }
+
+    /// Parses and runs the command line, returns the output
+    pub fn execute(&mut self, line: &str) -> Result<String, ExecError> {
+        match parse(line).map_err(ExecError::StringError)? {
+            Some(command) => self.run(command, line),
+            None => Ok(String::new()),
+        }
+    }
+
+    pub fn run(&mut self, command: Command, line: &str) -> Result<String, ExecError> {
+        self.history.push(line.trim().to_string());
+        let not_found = |what: String| ExecError::StringError(format!("{what} not found"));
+        match command {
+            Command::Request(r) => {
+                let before = Change {
+                    line: line.trim().to_string(),
+                    client: r.client,
+                    account: self.ledger.get_account(r.client)?,
+                    tx_id: r.tx_id,
+                    tx: self.ledger.get_transaction(r.tx_id)?,
+                };
+                let outcome = Outcome::of(apply_request(&mut self.ledger, &r))?;
+                if outcome == Outcome::Applied {
+                    self.undo.push(before);
+                }
+                Ok(json(&Reply {
+                    outcome,
+                    account: self.ledger.get_account(r.client)?,
+                    transaction: self.ledger.get_transaction(r.tx_id)?,
+                }))
+            }
+            Command::Account(client) => {
+                let acc = self.ledger.get_account(client)?;
+                Ok(json(&acc.ok_or_else(|| {
+                    not_found(format!("account {}", client.0))
+                })?))
+            }
+            Command::Transaction(tx_id) => {
+                let tx = self.ledger.get_transaction(tx_id)?;
+                Ok(json(&tx.ok_or_else(|| {
+                    not_found(format!("transaction {}", tx_id.0))
+                })?))
+            }
+            Command::AccountHas(client, total, available, held) => {
+                let acc = self.ledger.get_account(client)?;
+                match acc {
+                    Some(a) if (a.total, a.available, a.held) == (total, available, held) => {
+                        Ok("ok".into())
+                    }
+                    acc => Err(ExecError::StringError(format!("account is {acc:?}"))),
+                }
+            }
+            Command::AccountIsLocked(client) => match self.ledger.get_account(client)? {
+                Some(a) if a.locked => Ok("ok".into()),
+                acc => Err(ExecError::StringError(format!("account is {acc:?}"))),
+            },
+            Command::Accounts => {
+                let mut out = Vec::new();
+                let opts = DumpOptions {
+                    sorted: true,
+                    ..Default::default()
+                };
+                dump_accounts_with(&mut out, &self.ledger, &opts)?;
+                Ok(String::from_utf8_lossy(&out).trim_end().to_string())
+            }
+            Command::Transactions => {
+                let mut out = Vec::new();
+                dump_transactions(&mut out, &self.ledger, true)?;
+                Ok(String::from_utf8_lossy(&out).trim_end().to_string())
+            }
+            Command::Undo => {
+                let change = self
+                    .undo
+                    .pop()
+                    .ok_or_else(|| ExecError::StringError("nothing to undo".into()))?;
+                match change.tx {
+                    Some(tx) => self.ledger.put_transaction(change.tx_id, tx)?,
+                    None => self.ledger.remove_transaction(change.tx_id)?,
+                }
+                match change.account {
+                    Some(acc) => self.ledger.put_account(change.client, acc)?,
+                    None => self.ledger.remove_account(change.client)?,
+                }
+                Ok(format!("undone `{}`", change.line))
+            }
+            Command::History => Ok(self
+                .history
+                .iter()
+                .enumerate()
+                .map(|(i, line)| format!("{:>4}  {line}", i + 1))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            Command::Help => Ok(HELP.trim_end().to_string()),
+            Command::Quit => Ok(String::new()),
+        }
+    }
+
+    /// Completes the word before `pos`: keywords, client ids or tx ids of the ledger,
+    ///   returns the start of the word and the candidates
+    pub fn complete(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let head = &line[..pos];
+        let start = head
+            .char_indices()
+            .rfind(|(_, c)| c.is_whitespace())
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        let prefix = &head[start..];
+        let words: Vec<_> = head[..start].split_whitespace().collect();
+        let keywords = |list: &[&str]| list.iter().map(|s| s.to_string()).collect();
+        let candidates: BTreeSet<String> = match words.as_slice() {
+            [] => keywords(&[
+                "tx",
+                "dispute",
+                "resolve",
+                "chargeback",
+                "account",
+                "accounts",
+                "transactions",
+                "undo",
+                "history",
+                "help",
+                "quit",
+            ]),
+            ["tx"] | ["dispute" | "resolve" | "chargeback"] => self.tx_ids(prefix),
+            ["account"] | [.., "to" | "from" | "for"] => self.clients(prefix),
+            ["tx", _] => keywords(&["deposit", "withdrawal"]),
+            ["tx", _, "deposit", _] => keywords(&["to"]),
+            ["tx", _, "withdrawal", _] => keywords(&["from"]),
+            ["dispute" | "resolve" | "chargeback", _] => keywords(&["for"]),
+            ["account", _] => keywords(&["has", "is"]),
+            _ => BTreeSet::new(),
+        };
+        let candidates = candidates
+            .into_iter()
+            .filter(|c| c.starts_with(prefix))
+            .take(MAX_CANDIDATES)
+            .collect();
+        (start, candidates)
+    }
+
+    fn clients(&self, prefix: &str) -> BTreeSet<String> {
+        self.ledger
+            .accounts()
+            .filter_map(|r| r.ok())
+            .map(|(c, _)| c.0.to_string())
+            .filter(|c| c.starts_with(prefix))
+            .take(MAX_CANDIDATES)
+            .collect()
+    }
+
+    fn tx_ids(&self, prefix: &str) -> BTreeSet<String> {
+        self.ledger
+            .transactions()
+            .filter_map(|r| r.ok())
+            .map(|(t, _)| t.0.to_string())
+            .filter(|t| t.starts_with(prefix))
+            .take(MAX_CANDIDATES)
+            .collect()
+    }
+}
+
+fn json(v: &impl Serialize) -> String {
+    serde_json::to_string(v).unwrap()
+}
+
+/// Completion of big ledgers lists only the first candidates
+pub const MAX_CANDIDATES: usize = 100;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        advanced::SledLedger,
+        audit::{verify, AuditLedger, AuditLog},
+        basic::HashLedger,
+        snapshot::Snapshot,
+    };
+    use rust_decimal_macros::dec;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("tx 1 deposit 1.5 to 2"),
+            Ok(Some(Command::Request(TxRequest {
+                tx_type: TxType::Deposit,
+                client: Client(2),
+                tx_id: TxId(1),
+                amount: Some(dec!(1.5)),
+                key: None,
+                time: None,
+            })))
+        );
+        assert!(matches!(
+            parse("  chargeback 3 for 4 "),
+            Ok(Some(Command::Request(TxRequest {
+                tx_type: TxType::Chargeback,
+                ..
+            })))
+        ));
+        assert_eq!(
+            parse("account 2 has total 1 available 0.5 held 0.5"),
+            Ok(Some(Command::AccountHas(
+                Client(2),
+                dec!(1),
+                dec!(0.5),
+                dec!(0.5)
+            )))
+        );
+        assert_eq!(parse("tx 7"), Ok(Some(Command::Transaction(TxId(7)))));
+        assert_eq!(parse("# comment"), Ok(None));
+        assert!(parse("account 70000").is_err());
+        assert!(parse("deposit 1 to 2").is_err());
+    }
+
+    fn check_undo(ledger: impl Ledger) -> Result<(), ExecError> {
+        let mut session = Session::new(ledger);
+        let empty = Snapshot::from_ledger(&session.ledger)?;
+        session.execute("tx 1 deposit 2.0 to 1")?;
+        session.execute("tx 2 deposit 1.0 to 1")?;
+        let deposited = Snapshot::from_ledger(&session.ledger)?;
+        let reply = session.execute("dispute 1 for 1")?;
+        assert!(reply.contains(r#""outcome":"Applied""#), "{reply}");
+        let reply = session.execute("tx 3 withdrawal 5 from 1")?;
+        assert!(reply.contains("Rejected"), "{reply}");
+        session.execute("chargeback 1 for 1")?;
+        session.execute("account 1 is locked")?;
+        session.execute("account 1 has total 1.0 available 1.0 held 0")?;
+
+        // the rejected withdrawal changed nothing and is not undone
+        session.execute("undo")?;
+        session.execute("undo")?;
+        assert_eq!(Snapshot::from_ledger(&session.ledger)?, deposited);
+        session.execute("undo")?;
+        session.execute("undo")?;
+        assert_eq!(Snapshot::from_ledger(&session.ledger)?, empty);
+        assert!(session.execute("undo").is_err());
+        assert!(session.execute("account 1").is_err());
+        assert_eq!(session.execute("history")?.lines().count(), 14);
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo() -> Result<(), ExecError> {
+        check_undo(HashLedger::new())?;
+        check_undo(SledLedger::new().unwrap())
+    }
+
+    #[test]
+    fn test_audited_undo() -> Result<(), ExecError> {
+        let path = std::env::temp_dir().join(format!("toybank-repl-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let log = Arc::new(Mutex::new(AuditLog::open(&path)?));
+        check_undo(AuditLedger::new(HashLedger::new(), log, "tester"))?;
+        // the undone writes are logged too, the chain ends with the empty ledger
+        let replay = verify(std::io::BufReader::new(std::fs::File::open(&path)?))?;
+        replay.check_ledger(&HashLedger::new())?;
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_complete() -> Result<(), ExecError> {
+        let mut session = Session::new(HashLedger::new());
+        for line in [
+            "tx 10 deposit 1 to 1",
+            "tx 11 deposit 1 to 12",
+            "tx 2 deposit 1 to 2",
+        ] {
+            session.execute(line)?;
+        }
+        let complete = |line: &str| session.complete(line, line.len());
+        assert_eq!(complete("di"), (0, vec!["dispute".to_string()]));
+        assert_eq!(complete("dispute 1"), (8, vec!["10".into(), "11".into()]));
+        assert_eq!(complete("dispute 10 f"), (11, vec!["for".into()]));
+        assert_eq!(
+            complete("dispute 10 for 1"),
+            (15, vec!["1".into(), "12".into()])
+        );
+        assert_eq!(complete("tx 3 w").1, vec!["withdrawal".to_string()]);
+        assert_eq!(complete("account ").1, vec!["1", "12", "2"]);
+        assert!(complete("quit ").1.is_empty());
+        // the ideographic space is three bytes long
+        assert_eq!(
+            complete("dispute\u{3000}1"),
+            (10, vec!["10".into(), "11".into()])
+        );
+        Ok(())
+    }
+}
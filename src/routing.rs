@@ -0,0 +1,178 @@
+use crate::{advanced::index_by_client, common::Client, libcsv::ExecError};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Selects the shard processing the client
+pub trait ShardRouter {
+    fn route(&self, client: Client, shards: usize) -> usize;
+}
+
+/// Plain functions like `index_by_client` are routers
+impl<F: Fn(Client, usize) -> usize> ShardRouter for F {
+    fn route(&self, client: Client, shards: usize) -> usize {
+        self(client, shards)
+    }
+}
+
+/// Built-in routers
+///
+/// Serializable, so the router used to shard a ledger can be stored with it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Router {
+    /// Multiplicative hash of `index_by_client`
+    #[default]
+    Hash,
+    /// Client id modulo count of shards
+    Modulo,
+    /// Jump consistent hash, only 1/n of clients move when a shard is added
+    Jump,
+    /// Rendezvous (highest random weight) hashing
+    Rendezvous,
+    /// Explicitly pinned clients, other clients are routed with the fallback router
+    Override {
+        pins: Vec<(Client, usize)>,
+        fallback: Box<Router>,
+    },
+}
+
+impl Router {
+    /// Pins the client to the shard, e.g. to isolate a hot client
+    pub fn pin(self, client: Client, shard: usize) -> Router {
+        match self {
+            Router::Override { mut pins, fallback } => {
+                pins.retain(|(c, _)| *c != client);
+                pins.push((client, shard));
+                Router::Override { pins, fallback }
+            }
+            router => Router::Override {
+                pins: vec![(client, shard)],
+                fallback: Box::new(router),
+            },
+        }
+    }
+
+    /// Checks the pinned shards exist among `shards` ones
+    pub fn validate(&self, shards: usize) -> Result<(), ExecError> {
+        match self {
+            Router::Override { pins, fallback } => {
+                if let Some((client, shard)) = pins.iter().find(|(_, s)| *s >= shards) {
+                    return Err(ExecError::StringError(format!(
+                        "client {} is pinned to worker {shard} of {shards}",
+                        client.0
+                    )));
+                }
+                fallback.validate(shards)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ShardRouter for Router {
+    fn route(&self, client: Client, shards: usize) -> usize {
+        match self {
+            Router::Hash => index_by_client(client, shards),
+            Router::Modulo => client.0 as usize % shards,
+            Router::Jump => jump_hash(mix(client.0 as u64), shards),
+            Router::Rendezvous => (0..shards)
+                .max_by_key(|shard| mix(((client.0 as u64) << 32) | *shard as u64))
+                .unwrap_or(0),
+            Router::Override { pins, fallback } => match pins.iter().find(|(c, _)| *c == client) {
+                Some((_, shard)) => shard % shards,
+                None => fallback.route(client, shards),
+            },
+        }
+    }
+}
+
+impl ShardRouter for &Router {
+    fn route(&self, client: Client, shards: usize) -> usize {
+        (*self).route(client, shards)
+    }
+}
+
+impl FromStr for Router {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "hash" => Ok(Router::Hash),
+            "modulo" => Ok(Router::Modulo),
+            "jump" => Ok(Router::Jump),
+            "rendezvous" => Ok(Router::Rendezvous),
+            _ => Err(format!("unknown router `{s}`")),
+        }
+    }
+}
+
+/// SplitMix64 finalizer, spreads sequential client ids
+pub(crate) fn mix(v: u64) -> u64 {
+    let mut z = v.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Jump consistent hash by Lamping and Veach
+fn jump_hash(mut key: u64, buckets: usize) -> usize {
+    let (mut b, mut j) = (-1i64, 0i64);
+    while j < buckets as i64 {
+        b = j;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    b.max(0) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moved(router: &Router, from: usize, to: usize) -> Vec<(usize, usize)> {
+        (0..1000u16)
+            .map(Client)
+            .map(|c| (router.route(c, from), router.route(c, to)))
+            .filter(|(a, b)| a != b)
+            .collect()
+    }
+
+    #[test]
+    fn test_consistent_routers() {
+        for router in [Router::Jump, Router::Rendezvous] {
+            let moved = moved(&router, 4, 5);
+            // clients move to the new shard only, about 1/5 of them
+            assert!(moved.iter().all(|(_, b)| *b == 4));
+            assert!(
+                (150..250).contains(&moved.len()),
+                "{router:?} {}",
+                moved.len()
+            );
+        }
+        assert!(moved(&Router::Modulo, 4, 5).len() > 500);
+    }
+
+    #[test]
+    fn test_balanced_routers() {
+        // the multiplicative hash is not in the list, it is skewed a lot
+        for router in [Router::Modulo, Router::Jump, Router::Rendezvous] {
+            let mut counts = [0; 4];
+            (0..4000u16).for_each(|c| counts[router.route(Client(c), 4)] += 1);
+            assert!(
+                counts.iter().all(|n| (800..1200).contains(n)),
+                "{router:?} {counts:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_override_router() {
+        let router = Router::Jump.pin(Client(7), 3).pin(Client(8), 3);
+        assert_eq!(router.route(Client(7), 4), 3);
+        assert_eq!(router.route(Client(8), 2), 1);
+        assert_eq!(router.route(Client(9), 4), Router::Jump.route(Client(9), 4));
+        assert!(router.validate(4).is_ok());
+        assert!(router.validate(3).is_err());
+        let json = serde_json::to_string(&router).unwrap();
+        assert_eq!(serde_json::from_str::<Router>(&json).unwrap(), router);
+    }
+}
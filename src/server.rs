@@ -0,0 +1,149 @@
+//! HTTP/JSON API over the `LedgerService`
+//!
+//! ```text
+//! POST /transactions                    {"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}
+//! GET  /accounts                        all accounts ordered by client
+//! GET  /accounts/<client>               the account
+//! GET  /accounts/<client>/transactions  transactions of the client ordered by id
+//! ```
+//! The `Idempotency-Key` header sets the `key` of the submitted transaction.
+//! Submitted transactions reply `{"outcome": "applied"}`, `ignored` or `rejected`
+//!   with the `reason`, rejected ones with status 422. Failures reply `{"error": ...}`,
+//!   deposits and withdrawals without the positive amount with status 400.
+use crate::{
+    common::{Account, Client, TxId},
+    libcsv::{AccountState, ExecError, Outcome, TxRequest},
+    service::LedgerService,
+    snapshot::state_name,
+};
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json,
+};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use serde_json::json;
+
+#[derive(Serialize)]
+struct OutcomeReply {
+    outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TransactionReply {
+    tx: TxId,
+    client: Client,
+    amount: Decimal,
+    state: &'static str,
+}
+
+/// Failure of the request replied as `{"error": ...}`
+struct Failure(StatusCode, String);
+
+impl From<ExecError> for Failure {
+    fn from(e: ExecError) -> Self {
+        Failure(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
+}
+
+impl IntoResponse for Failure {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+async fn submit(
+    State(service): State<LedgerService>,
+    headers: HeaderMap,
+    Json(mut r): Json<TxRequest>,
+) -> Result<Response, Failure> {
+    if let Some(key) = headers.get("idempotency-key") {
+        let key = key
+            .to_str()
+            .map_err(|_| Failure(StatusCode::BAD_REQUEST, "bad idempotency key".into()))?;
+        r.key = Some(key.to_string());
+    }
+    r.check_amount()
+        .map_err(|e| Failure(StatusCode::BAD_REQUEST, e))?;
+    let (status, outcome, reason) = match service.submit(r).await? {
+        Outcome::Applied => (StatusCode::OK, "applied", None),
+        Outcome::Ignored(e) => (StatusCode::OK, "ignored", Some(e)),
+        Outcome::Rejected(e) => (StatusCode::UNPROCESSABLE_ENTITY, "rejected", Some(e)),
+    };
+    Ok((status, Json(OutcomeReply { outcome, reason })).into_response())
+}
+
+fn account_state(client: Client, acc: Account) -> AccountState {
+    AccountState {
+        client,
+        available: acc.available,
+        held: acc.held,
+        total: acc.total,
+        locked: acc.locked,
+    }
+}
+
+async fn account(
+    State(service): State<LedgerService>,
+    Path(client): Path<u16>,
+) -> Result<Json<AccountState>, Failure> {
+    match service.account(Client(client)).await? {
+        Some(acc) => Ok(Json(account_state(Client(client), acc))),
+        None => Err(Failure(
+            StatusCode::NOT_FOUND,
+            format!("account {client} not found"),
+        )),
+    }
+}
+
+async fn client_transactions(
+    State(service): State<LedgerService>,
+    Path(client): Path<u16>,
+) -> Result<Json<Vec<TransactionReply>>, Failure> {
+    let txs = service.client_transactions(Client(client)).await?;
+    Ok(Json(
+        txs.into_iter()
+            .map(|(tx_id, tx)| TransactionReply {
+                tx: tx_id,
+                client: tx.client,
+                amount: tx.amount,
+                state: state_name(tx.state),
+            })
+            .collect(),
+    ))
+}
+
+async fn accounts(
+    State(service): State<LedgerService>,
+) -> Result<Json<Vec<AccountState>>, Failure> {
+    let accounts = service.accounts().await?;
+    Ok(Json(
+        accounts
+            .into_iter()
+            .map(|(client, acc)| account_state(client, acc))
+            .collect(),
+    ))
+}
+
+/// Routes of the API
+pub fn app(service: LedgerService) -> axum::Router {
+    axum::Router::new()
+        .route("/transactions", post(submit))
+        .route("/accounts", get(accounts))
+        .route("/accounts/:client", get(account))
+        .route("/accounts/:client/transactions", get(client_transactions))
+        .with_state(service)
+}
+
+/// Serves the API until the listener fails
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    service: LedgerService,
+) -> std::io::Result<()> {
+    axum::serve(listener, app(service)).await
+}
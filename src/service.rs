@@ -0,0 +1,404 @@
+//! Long-lived sharded ledger serving requests of concurrent callers
+use crate::{
+    advanced::MSG_QUEUE_LENGTH,
+    common::{Account, Client, Ledger, Transaction, TxError, TxId, TxType},
+    libcsv::{apply_request, ExecError, Outcome, TxRequest},
+    routing::{Router, ShardRouter},
+};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    future::Future,
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex, MutexGuard},
+};
+use tokio::sync::{mpsc, oneshot};
+
+type Job = (TxRequest, oneshot::Sender<Result<Outcome, TxError>>);
+
+fn stopped() -> ExecError {
+    ExecError::StringError("the ledger service is stopped".into())
+}
+
+fn poisoned() -> TxError {
+    TxError::StringError("the shard is poisoned by a panic".into())
+}
+
+const OWNER_STRIPES: usize = 64;
+
+/// Clients owning the values, the map is split into stripes locked separately
+struct Stripes<K>(Vec<Mutex<HashMap<K, Client>>>);
+
+impl<K: Hash + Eq> Stripes<K> {
+    fn new() -> Self {
+        Self((0..OWNER_STRIPES).map(|_| Default::default()).collect())
+    }
+
+    fn stripe(&self, value: &K) -> MutexGuard<'_, HashMap<K, Client>> {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let stripe = &self.0[hasher.finish() as usize % OWNER_STRIPES];
+        stripe.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Clients of the transaction ids and the idempotency keys of all shards
+///
+/// A shard knows only its own transactions and keys, so a deposit or withdrawal
+///   checks its id here and a keyed request checks its key,
+///   the stripes of both are locked while it is applied.
+struct Owners {
+    txs: Stripes<TxId>,
+    keys: Stripes<String>,
+}
+
+impl Owners {
+    fn new(ledgers: &[Arc<Mutex<dyn Ledger + Send>>]) -> Result<Self, ExecError> {
+        let owners = Self {
+            txs: Stripes::new(),
+            keys: Stripes::new(),
+        };
+        for ledger in ledgers {
+            let ledger = ledger.lock().map_err(|_| ExecError::TxError(poisoned()))?;
+            for pair in ledger.transactions() {
+                let (tx_id, tx) = pair?;
+                owners.txs.stripe(&tx_id).insert(tx_id, tx.client);
+            }
+            for pair in ledger.recorded() {
+                let (key, recorded) = pair?;
+                owners.keys.stripe(&key).insert(key, recorded.client);
+            }
+        }
+        Ok(owners)
+    }
+
+    /// Applies the request, a key used by another client is rejected
+    ///   as `apply_request` rejects it in a single ledger
+    fn apply(&self, ledger: &mut dyn Ledger, r: &TxRequest) -> Result<Outcome, TxError> {
+        let Some(key) = &r.key else {
+            return self.apply_tx(ledger, r);
+        };
+        let mut keys = self.keys.stripe(key);
+        match keys.get(key) {
+            Some(client) if *client != r.client => Ok(Outcome::Rejected(format!(
+                "idempotency key `{key}` is used by another request"
+            ))),
+            _ => {
+                let outcome = self.apply_tx(ledger, r)?;
+                keys.insert(key.clone(), r.client);
+                Ok(outcome)
+            }
+        }
+    }
+
+    /// Applies the request, a deposit or withdrawal reusing the transaction id
+    ///   of another client is ignored as the duplicate
+    fn apply_tx(&self, ledger: &mut dyn Ledger, r: &TxRequest) -> Result<Outcome, TxError> {
+        if !matches!(r.tx_type, TxType::Deposit | TxType::Withdrawal) {
+            return Outcome::of(apply_request(ledger, r));
+        }
+        let mut owners = self.txs.stripe(&r.tx_id);
+        match owners.get(&r.tx_id) {
+            Some(client) if *client != r.client => {
+                Ok(Outcome::Ignored("duplicated transaction".into()))
+            }
+            _ => {
+                let outcome = Outcome::of(apply_request(ledger, r))?;
+                if outcome == Outcome::Applied {
+                    owners.insert(r.tx_id, r.client);
+                }
+                Ok(outcome)
+            }
+        }
+    }
+}
+
+/// Sharded ledger applying submitted requests and answering queries
+///
+/// Like `async_sharded_execute`, every shard is served by a task receiving requests
+///   from a bounded channel and applying them in batches in the blocking pool,
+///   so the requests of a client are applied one by one in the submission order
+///   and a busy shard makes its submitters wait. Queries lock the shard owning the client.
+/// Transaction ids and idempotency keys are unique across all shards, as they are
+///   in a single ledger: the ids and the keys of all shards are kept by the service.
+/// A panic while applying a batch fails the requests of the shard since then.
+/// Must be created within the tokio runtime, the tasks stop when all clones are dropped.
+#[derive(Clone)]
+pub struct LedgerService {
+    ledgers: Arc<[Arc<Mutex<dyn Ledger + Send>>]>,
+    router: Arc<Router>,
+    shards: Vec<mpsc::Sender<Job>>,
+}
+
+impl LedgerService {
+    pub fn new(
+        ledgers: Vec<Arc<Mutex<dyn Ledger + Send>>>,
+        router: Router,
+    ) -> Result<Self, ExecError> {
+        let owners = Arc::new(Owners::new(&ledgers)?);
+        let shards = ledgers
+            .iter()
+            .map(|ledger| {
+                let (job_s, mut job_r) = mpsc::channel::<Job>(MSG_QUEUE_LENGTH);
+                let (ledger, owners) = (ledger.clone(), owners.clone());
+                tokio::spawn(async move {
+                    let mut batch = Vec::with_capacity(MSG_QUEUE_LENGTH);
+                    while job_r.recv_many(&mut batch, MSG_QUEUE_LENGTH).await > 0 {
+                        let (ledger, owners) = (ledger.clone(), owners.clone());
+                        let jobs = std::mem::take(&mut batch);
+                        // the replies of the panicked batch are dropped, its submitters fail
+                        let _ = tokio::task::spawn_blocking(move || {
+                            let mut l = match ledger.lock() {
+                                Ok(l) => l,
+                                Err(_) => {
+                                    for (_, reply) in jobs {
+                                        let _ = reply.send(Err(poisoned()));
+                                    }
+                                    return;
+                                }
+                            };
+                            for (r, reply) in jobs {
+                                let _ = reply.send(owners.apply(&mut *l, &r));
+                            }
+                        })
+                        .await;
+                    }
+                });
+                job_s
+            })
+            .collect();
+        Ok(Self {
+            ledgers: ledgers.into(),
+            router: Arc::new(router),
+            shards,
+        })
+    }
+
+    pub fn shards(&self) -> usize {
+        self.ledgers.len()
+    }
+
+    fn shard(&self, client: Client) -> usize {
+        self.router.route(client, self.ledgers.len())
+    }
+
+    /// Applies the request after the previously submitted requests of the client
+    pub async fn submit(&self, r: TxRequest) -> Result<Outcome, ExecError> {
+        self.enqueue(r).await?.await
+    }
+
+    /// Queues the request, waiting while the shard queue is full,
+    ///   the returned future resolves to its outcome once applied
+    pub async fn enqueue(
+        &self,
+        r: TxRequest,
+    ) -> Result<impl Future<Output = Result<Outcome, ExecError>> + Send + 'static, ExecError> {
+        let (reply_s, reply_r) = oneshot::channel();
+        self.shards[self.shard(r.client)]
+            .send((r, reply_s))
+            .await
+            .map_err(|_| stopped())?;
+        Ok(async move { Ok(reply_r.await.map_err(|_| stopped())??) })
+    }
+
+    /// Runs the query on the locked shard in the blocking pool
+    async fn query<T: Send + 'static>(
+        &self,
+        shard: usize,
+        f: impl FnOnce(&dyn Ledger) -> Result<T, ExecError> + Send + 'static,
+    ) -> Result<T, ExecError> {
+        let ledger = self.ledgers[shard].clone();
+        tokio::task::spawn_blocking(move || match ledger.lock() {
+            Ok(l) => f(&*l),
+            Err(_) => Err(poisoned().into()),
+        })
+        .await
+        .map_err(|e| ExecError::StringError(e.to_string()))?
+    }
+
+    pub async fn account(&self, client: Client) -> Result<Option<Account>, ExecError> {
+        self.query(self.shard(client), move |l| Ok(l.get_account(client)?))
+            .await
+    }
+
+    /// Transactions of the client ordered by id
+    pub async fn client_transactions(
+        &self,
+        client: Client,
+    ) -> Result<Vec<(TxId, Transaction)>, ExecError> {
+        self.query(self.shard(client), move |l| {
+            let mut txs = Vec::new();
+            for pair in l.transactions() {
+                match pair? {
+                    (tx_id, tx) if tx.client == client => txs.push((tx_id, tx)),
+                    _ => (),
+                }
+            }
+            txs.sort_by_key(|(tx_id, _)| tx_id.0);
+            Ok(txs)
+        })
+        .await
+    }
+
+    /// Accounts of all shards ordered by client
+    pub async fn accounts(&self) -> Result<Vec<(Client, Account)>, ExecError> {
+        let mut accounts = Vec::new();
+        for shard in 0..self.shards() {
+            let router = self.router.clone();
+            let shards = self.shards();
+            let owned = self
+                .query(shard, move |l| {
+                    let mut owned = Vec::new();
+                    for pair in l.accounts() {
+                        match pair? {
+                            (client, _) if router.route(client, shards) != shard => (),
+                            pair => owned.push(pair),
+                        }
+                    }
+                    Ok(owned)
+                })
+                .await?;
+            accounts.extend(owned);
+        }
+        accounts.sort_by_key(|(client, _)| client.0);
+        Ok(accounts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{advanced::SledLedger, basic::HashLedger, common::Recorded};
+    use rust_decimal_macros::dec;
+
+    fn deposit(client: u16, tx_id: u32) -> TxRequest {
+        TxRequest {
+            tx_type: TxType::Deposit,
+            client: Client(client),
+            tx_id: TxId(tx_id),
+            amount: Some(dec!(1)),
+            key: None,
+            time: None,
+        }
+    }
+
+    async fn check_concurrent_clients(service: LedgerService) -> Result<(), ExecError> {
+        let tasks: Vec<_> = (1..=8u16)
+            .map(|client| {
+                let service = service.clone();
+                tokio::spawn(async move {
+                    for i in 0..20 {
+                        let outcome = service
+                            .submit(deposit(client, client as u32 * 100 + i))
+                            .await?;
+                        assert_eq!(outcome, Outcome::Applied);
+                    }
+                    service.submit(deposit(client, client as u32 * 100)).await
+                })
+            })
+            .collect();
+        for task in tasks {
+            assert!(matches!(task.await.unwrap()?, Outcome::Ignored(_)));
+        }
+        let accounts = service.accounts().await?;
+        assert_eq!(accounts.len(), 8);
+        assert!(accounts.iter().all(|(_, a)| a.total == dec!(20)));
+        assert_eq!(service.client_transactions(Client(3)).await?.len(), 20);
+        assert_eq!(service.account(Client(9)).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_service() -> Result<(), ExecError> {
+        let sharding: Vec<_> = (0..3)
+            .map(|_| Arc::new(Mutex::new(HashLedger::new())) as Arc<Mutex<dyn Ledger + Send>>)
+            .collect();
+        check_concurrent_clients(LedgerService::new(sharding, Router::Jump)?).await?;
+        let sharding = SledLedger::new().unwrap().sharding(2);
+        check_concurrent_clients(LedgerService::new(sharding, Router::Hash)?).await
+    }
+
+    fn hash_shards(n: usize) -> Vec<Arc<Mutex<dyn Ledger + Send>>> {
+        (0..n)
+            .map(|_| Arc::new(Mutex::new(HashLedger::new())) as Arc<Mutex<dyn Ledger + Send>>)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_tx_ids_unique_across_shards() -> Result<(), ExecError> {
+        let sharding = hash_shards(2);
+        sharding[1]
+            .lock()
+            .unwrap()
+            .deposit(Client(1), TxId(7), dec!(1))?;
+        let service = LedgerService::new(sharding, Router::Modulo)?;
+        // clients 1 and 2 are served by different shards
+        assert_eq!(service.submit(deposit(2, 1)).await?, Outcome::Applied);
+        assert!(matches!(
+            service.submit(deposit(1, 1)).await?,
+            Outcome::Ignored(_)
+        ));
+        assert!(matches!(
+            service.submit(deposit(2, 7)).await?,
+            Outcome::Ignored(_)
+        ));
+        assert_eq!(service.account(Client(1)).await?.unwrap().total, dec!(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_keys_unique_across_shards() -> Result<(), ExecError> {
+        let keyed = |client, tx_id| TxRequest {
+            key: Some("k1".into()),
+            ..deposit(client, tx_id)
+        };
+        let sharding = hash_shards(2);
+        sharding[1].lock().unwrap().put_recorded(
+            "k0",
+            Recorded {
+                tx_type: TxType::Deposit,
+                client: Client(1),
+                tx_id: TxId(7),
+                amount: Some(dec!(1)),
+                outcome: Outcome::Applied,
+            },
+        )?;
+        let service = LedgerService::new(sharding, Router::Modulo)?;
+        // clients 1 and 2 are served by different shards
+        assert_eq!(service.submit(keyed(2, 1)).await?, Outcome::Applied);
+        assert_eq!(service.submit(keyed(2, 1)).await?, Outcome::Applied);
+        assert!(matches!(
+            service.submit(keyed(1, 2)).await?,
+            Outcome::Rejected(e) if e.contains("`k1` is used by another request")
+        ));
+        let reused = TxRequest {
+            key: Some("k0".into()),
+            ..deposit(2, 3)
+        };
+        assert!(matches!(
+            service.submit(reused).await?,
+            Outcome::Rejected(_)
+        ));
+        assert_eq!(service.account(Client(2)).await?.unwrap().total, dec!(1));
+        assert_eq!(service.account(Client(1)).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_poisoned_shard() -> Result<(), ExecError> {
+        let sharding = hash_shards(2);
+        let service = LedgerService::new(sharding.clone(), Router::Modulo)?;
+        let _ = std::thread::spawn(move || {
+            let _l = sharding[0].lock().unwrap();
+            panic!("the shard is poisoned");
+        })
+        .join();
+        // client 2 is served by the poisoned shard, client 1 by the other one
+        assert!(matches!(
+            service.submit(deposit(2, 1)).await,
+            Err(ExecError::TxError(TxError::StringError(e))) if e.contains("poisoned")
+        ));
+        assert!(service.account(Client(2)).await.is_err());
+        assert_eq!(service.submit(deposit(1, 2)).await?, Outcome::Applied);
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+use crate::{
+    common::{Account, Client, Ledger, Transaction, TxId, TxState},
+    libcsv::ExecError,
+};
+use rust_decimal::Decimal;
+use sha2::{Digest, Sha256};
+use std::{
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    str::FromStr,
+};
+
+pub const SNAPSHOT_FORMAT: &str = "toybank-snapshot";
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Full ledger state: accounts and transactions with their states
+///
+/// The portable text form is
+/// ```text
+/// toybank-snapshot,1
+/// account,<client>,<available>,<held>,<total>,<locked>
+/// transaction,<tx>,<client>,<amount>,<state>[,<time>]
+/// checksum,<sha256 of all preceding lines>, the last line
+/// ```
+/// Records are ordered by client and transaction id,
+///   so the same state always produces the same file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    pub accounts: Vec<(Client, Account)>,
+    pub transactions: Vec<(TxId, Transaction)>,
+}
+
+impl Snapshot {
+    pub fn from_ledger(ledger: &dyn Ledger) -> Result<Snapshot, ExecError> {
+        let mut snapshot = Snapshot {
+            accounts: ledger.accounts().collect::<Result<_, _>>()?,
+            transactions: ledger.transactions().collect::<Result<_, _>>()?,
+        };
+        snapshot.sort();
+        Ok(snapshot)
+    }
+
+    pub fn sort(&mut self) {
+        self.accounts.sort_by_key(|(client, _)| client.0);
+        self.transactions.sort_by_key(|(tx_id, _)| tx_id.0);
+    }
+
+    /// Puts all accounts and transactions into the ledger,
+    ///   existing records with the same keys are overwritten
+    pub fn restore(&self, ledger: &mut dyn Ledger) -> Result<(), ExecError> {
+        for (client, account) in &self.accounts {
+            ledger.put_account(*client, *account)?;
+        }
+        for (tx_id, tx) in &self.transactions {
+            ledger.put_transaction(*tx_id, *tx)?;
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, wr: impl Write) -> Result<(), ExecError> {
+        let mut wr = ChecksumWriter(wr, Sha256::new());
+        wr.line(format!("{SNAPSHOT_FORMAT},{SNAPSHOT_VERSION}"))?;
+        for (client, acc) in &self.accounts {
+            wr.line(format!(
+                "account,{},{},{},{},{}",
+                client.0, acc.available, acc.held, acc.total, acc.locked
+            ))?;
+        }
+        for (tx_id, tx) in &self.transactions {
+            let time = tx.time.map(|t| format!(",{t}")).unwrap_or_default();
+            wr.line(format!(
+                "transaction,{},{},{},{}{time}",
+                tx_id.0,
+                tx.client.0,
+                tx.amount,
+                state_name(tx.state)
+            ))?;
+        }
+        let checksum = hex(&wr.1.clone().finalize());
+        writeln!(wr.0, "checksum,{checksum}")?;
+        wr.0.flush()?;
+        Ok(())
+    }
+
+    /// Reads the whole snapshot and verifies its checksum
+    pub fn read(rd: impl std::io::Read) -> Result<Snapshot, ExecError> {
+        let mut hasher = Sha256::new();
+        let mut snapshot = Snapshot::default();
+        let mut lines = BufReader::new(rd).lines();
+        let header = lines
+            .next()
+            .ok_or_else(|| ExecError::StringError("snapshot is empty".into()))??;
+        match header.split_once(',') {
+            Some((SNAPSHOT_FORMAT, v)) if v == SNAPSHOT_VERSION.to_string() => (),
+            _ => {
+                return Err(ExecError::StringError(format!(
+                    "unsupported snapshot header `{header}`"
+                )))
+            }
+        }
+        hasher.update(format!("{header}\n"));
+        let mut checksum = None;
+        for (n, line) in lines.by_ref().enumerate() {
+            let line = line?;
+            let fields: Vec<&str> = line.split(',').collect();
+            let bad = || ExecError::StringError(format!("bad snapshot record at line {}", n + 2));
+            match fields.as_slice() {
+                ["account", client, available, held, total, locked] => {
+                    snapshot.accounts.push((
+                        Client(parse(client).ok_or_else(bad)?),
+                        Account {
+                            available: parse(available).ok_or_else(bad)?,
+                            held: parse(held).ok_or_else(bad)?,
+                            total: parse(total).ok_or_else(bad)?,
+                            locked: parse(locked).ok_or_else(bad)?,
+                        },
+                    ));
+                }
+                ["transaction", tx_id, client, amount, state, time @ ..] if time.len() <= 1 => {
+                    snapshot.transactions.push((
+                        TxId(parse(tx_id).ok_or_else(bad)?),
+                        Transaction {
+                            client: Client(parse(client).ok_or_else(bad)?),
+                            amount: parse::<Decimal>(amount).ok_or_else(bad)?,
+                            state: parse_state(state).ok_or_else(bad)?,
+                            time: match time {
+                                [time] => Some(parse(time).ok_or_else(bad)?),
+                                _ => None,
+                            },
+                        },
+                    ));
+                }
+                ["checksum", sum] => {
+                    checksum = Some(sum.to_string());
+                    break;
+                }
+                _ => return Err(bad()),
+            }
+            hasher.update(format!("{line}\n"));
+        }
+        let Some(checksum) = checksum else {
+            return Err(ExecError::StringError("snapshot has no checksum".into()));
+        };
+        if hex(&hasher.finalize()) != checksum {
+            return Err(ExecError::StringError(
+                "snapshot checksum does not match".into(),
+            ));
+        }
+        // the checksum does not cover appended lines
+        if lines.next().is_some() {
+            return Err(ExecError::StringError(
+                "snapshot has lines after the checksum".into(),
+            ));
+        }
+        Ok(snapshot)
+    }
+}
+
+pub fn export_ledger(wr: impl Write, ledger: &dyn Ledger) -> Result<(), ExecError> {
+    Snapshot::from_ledger(ledger)?.write(wr)
+}
+
+pub fn export_ledger_file(path: impl AsRef<Path>, ledger: &dyn Ledger) -> Result<(), ExecError> {
+    export_ledger(std::fs::File::create(path)?, ledger)
+}
+
+pub fn import_ledger(rd: impl std::io::Read, ledger: &mut dyn Ledger) -> Result<(), ExecError> {
+    Snapshot::read(rd)?.restore(ledger)
+}
+
+pub fn import_ledger_file(
+    path: impl AsRef<Path>,
+    ledger: &mut dyn Ledger,
+) -> Result<(), ExecError> {
+    import_ledger(std::fs::File::open(path)?, ledger)
+}
+
+pub fn state_name(state: TxState) -> &'static str {
+    match state {
+        TxState::Committed => "committed",
+        TxState::Disputed => "disputed",
+        TxState::Finalized => "finalized",
+        TxState::Cancelled => "cancelled",
+    }
+}
+
+pub fn parse_state(s: &str) -> Option<TxState> {
+    match s {
+        "committed" => Some(TxState::Committed),
+        "disputed" => Some(TxState::Disputed),
+        "finalized" => Some(TxState::Finalized),
+        "cancelled" => Some(TxState::Cancelled),
+        _ => None,
+    }
+}
+
+fn parse<T: FromStr>(s: &str) -> Option<T> {
+    s.parse().ok()
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+struct ChecksumWriter<W: Write>(W, Sha256);
+
+impl<W: Write> ChecksumWriter<W> {
+    fn line(&mut self, line: String) -> std::io::Result<()> {
+        let line = line + "\n";
+        self.1.update(&line);
+        self.0.write_all(line.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{advanced::SledLedger, basic::HashLedger, libcsv::execute_csv};
+
+    #[test]
+    fn test_snapshot_roundtrip() -> Result<(), ExecError> {
+        let mut ledger = HashLedger::new();
+        execute_csv(
+            std::io::Cursor::new(crate::basic::TRANSACTIONS.as_bytes()),
+            &mut ledger,
+        )?;
+        let mut exported = Vec::new();
+        export_ledger(&mut exported, &ledger)?;
+
+        let mut sled = SledLedger::new().unwrap();
+        import_ledger(std::io::Cursor::new(&exported), &mut sled)?;
+        crate::libcsv::validate_accounts(
+            std::io::Cursor::new(crate::basic::ACCOUNTS.as_bytes()),
+            &sled,
+        )?;
+        let mut reexported = Vec::new();
+        export_ledger(&mut reexported, &sled)?;
+        assert_eq!(exported, reexported);
+        assert_eq!(
+            sled.get_transaction(TxId(2))?.map(|tx| tx.state),
+            Some(TxState::Cancelled)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_checksum() -> Result<(), ExecError> {
+        let mut ledger = HashLedger::new();
+        ledger.deposit(Client(1), TxId(1), Decimal::ONE)?;
+        let mut exported = Vec::new();
+        export_ledger(&mut exported, &ledger)?;
+        let tampered = String::from_utf8(exported.clone())
+            .unwrap()
+            .replace(",1,1,1,", ",1,1,2,");
+        assert!(matches!(
+            Snapshot::read(std::io::Cursor::new(tampered)),
+            Err(ExecError::StringError(e)) if e.contains("checksum")
+        ));
+        let mut appended = exported.clone();
+        appended.extend_from_slice(b"account,2,5,0,5,false\n");
+        assert!(matches!(
+            Snapshot::read(std::io::Cursor::new(appended)),
+            Err(ExecError::StringError(e)) if e.contains("after the checksum")
+        ));
+        Snapshot::read(std::io::Cursor::new(exported))?;
+        Ok(())
+    }
+}
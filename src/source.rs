@@ -0,0 +1,252 @@
+use crate::{
+    dialect::{Columns, Dialect},
+    libcsv::{ExecError, TxRequest},
+};
+use csv::StringRecord;
+use std::io::Read;
+
+/// Position of a transaction request in its input
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Position {
+    /// 1-based line number, 0 when the input has no lines
+    pub line: u64,
+    /// 0-based index of the record in the input
+    pub record: u64,
+}
+
+impl std::fmt::Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, record {}", self.line, self.record)
+    }
+}
+
+pub type SourceItem = Result<(Position, TxRequest), ExecError>;
+
+/// A stream of transaction requests with their positions
+///
+/// Any iterator over `SourceItem` is a source, so generated or decoded
+///   requests are executed the same way as the csv ones.
+pub trait TxSource: Iterator<Item = SourceItem> {}
+impl<T: Iterator<Item = SourceItem>> TxSource for T {}
+
+/// Transaction requests read from csv
+pub struct CsvSource<R: Read> {
+    rdr: csv::Reader<std::io::Chain<R, &'static [u8]>>,
+    dialect: Dialect,
+    columns: Option<Columns>,
+    record: StringRecord,
+    count: u64,
+}
+
+/// The reader used for all csv inputs:
+///   `,` delimited, `#` comments, flexible and trimmed records
+pub fn csv_reader_builder() -> csv::ReaderBuilder {
+    Dialect::default().reader_builder().unwrap()
+}
+
+impl<R: Read> CsvSource<R> {
+    pub fn new(rd: R) -> Self {
+        Self::with_dialect(&Default::default(), rd).unwrap()
+    }
+    pub fn with_dialect(dialect: &Dialect, rd: R) -> Result<Self, ExecError> {
+        // the terminating newline makes the line of the last record countable
+        Ok(Self {
+            rdr: dialect.reader_builder()?.from_reader(rd.chain(&b"\n"[..])),
+            dialect: dialect.clone(),
+            columns: None,
+            record: StringRecord::new(),
+            count: 0,
+        })
+    }
+}
+
+impl<R: Read> Iterator for CsvSource<R> {
+    type Item = SourceItem;
+    fn next(&mut self) -> Option<SourceItem> {
+        let columns = match self.columns {
+            Some(columns) => columns,
+            None => {
+                let resolved = match self.dialect.has_headers {
+                    // the empty input has no header and no requests
+                    true => match self.rdr.headers() {
+                        Ok(h) if h.is_empty() => return None,
+                        Ok(h) => self.dialect.resolve(Some(h)),
+                        Err(e) => Err(e.into()),
+                    },
+                    false => self.dialect.resolve(None),
+                };
+                match resolved {
+                    Ok(columns) => *self.columns.insert(columns),
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+        };
+        match self.rdr.read_record(&mut self.record) {
+            Ok(false) => None,
+            Err(e) => Some(Err(e.into())),
+            Ok(true) => {
+                // the record position does not count skipped comments and empty lines,
+                //   so the line is counted back from the end of the record
+                let newlines = self
+                    .record
+                    .iter()
+                    .map(|f| f.matches('\n').count() as u64)
+                    .sum::<u64>();
+                let pos = Position {
+                    line: self.rdr.position().line() - 1 - newlines,
+                    record: self.count,
+                };
+                self.count += 1;
+                Some(
+                    self.dialect
+                        .request(&columns, &self.record)
+                        .map(|r| (pos, r))
+                        .map_err(|e| ExecError::StringError(format!("{pos}: {e}"))),
+                )
+            }
+        }
+    }
+}
+
+/// Parser of separate csv lines, for inputs delivered line by line
+///
+/// Comments and empty lines are skipped, the first line is the header
+///   when the dialect has headers.
+pub struct LineParser {
+    dialect: Dialect,
+    builder: csv::ReaderBuilder,
+    columns: Option<Columns>,
+    count: u64,
+}
+
+impl LineParser {
+    pub fn new(dialect: &Dialect) -> Result<Self, ExecError> {
+        let mut builder = dialect.reader_builder()?;
+        builder.has_headers(false).buffer_capacity(256);
+        Ok(Self {
+            dialect: dialect.clone(),
+            builder,
+            columns: match dialect.has_headers {
+                true => None,
+                false => Some(dialect.resolve(None)?),
+            },
+            count: 0,
+        })
+    }
+
+    /// Parses the line, returns `None` for header, comment or empty line
+    pub fn parse(&mut self, line: &str, line_no: u64) -> Option<SourceItem> {
+        let mut record = StringRecord::new();
+        // a comment is recognized only when it is terminated by newline
+        let line = line.as_bytes().chain(&b"\n"[..]);
+        match self.builder.from_reader(line).read_record(&mut record) {
+            Ok(false) => return None,
+            Err(e) => return Some(Err(e.into())),
+            Ok(true) => (),
+        }
+        let columns = match self.columns {
+            Some(columns) => columns,
+            None => {
+                return match self.dialect.resolve(Some(&record)) {
+                    Ok(columns) => {
+                        self.columns = Some(columns);
+                        None
+                    }
+                    Err(e) => Some(Err(e)),
+                }
+            }
+        };
+        let pos = Position {
+            line: line_no,
+            record: self.count,
+        };
+        self.count += 1;
+        Some(
+            self.dialect
+                .request(&columns, &record)
+                .map(|r| (pos, r))
+                .map_err(|e| ExecError::StringError(format!("{pos}: {e}"))),
+        )
+    }
+}
+
+/// Source over already built requests, positions are their indices
+pub fn requests_source(requests: impl IntoIterator<Item = TxRequest>) -> impl TxSource {
+    requests.into_iter().enumerate().map(|(i, r)| {
+        Ok((
+            Position {
+                line: 0,
+                record: i as u64,
+            },
+            r,
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        advanced::{index_by_client, sharded_execute_source},
+        basic::HashLedger,
+        common::{Client, Ledger, TxId, TxType},
+        libcsv::execute_source,
+    };
+    use rust_decimal_macros::dec;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_csv_source_positions() -> Result<(), ExecError> {
+        let src = CsvSource::new(std::io::Cursor::new(crate::basic::TRANSACTIONS.as_bytes()));
+        let positions: Vec<_> = src.map(|r| r.map(|(p, _)| p)).collect::<Result<_, _>>()?;
+        assert_eq!(positions.len(), 14);
+        assert_eq!(positions[0], Position { line: 3, record: 0 });
+        assert_eq!(positions[1], Position { line: 5, record: 1 });
+        assert_eq!(
+            positions[13],
+            Position {
+                line: 29,
+                record: 13
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_csv_source() {
+        assert!(CsvSource::new(std::io::Cursor::new("")).next().is_none());
+        assert!(
+            CsvSource::new(std::io::Cursor::new("type,client,tx,amount\n"))
+                .next()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_requests_source() -> Result<(), ExecError> {
+        let requests = || {
+            requests_source((1..=10u16).map(|c| TxRequest {
+                tx_type: TxType::Deposit,
+                client: Client(c),
+                tx_id: TxId(c as u32),
+                amount: Some(dec!(1.5)),
+                key: None,
+                time: None,
+            }))
+        };
+        let mut ledger = HashLedger::new();
+        execute_source(requests(), &mut ledger)?;
+        let sharding: Vec<_> = (0..3)
+            .map(|_| Arc::new(Mutex::new(HashLedger::new())) as Arc<Mutex<dyn Ledger + Send>>)
+            .collect();
+        sharded_execute_source(requests(), &sharding, index_by_client)?;
+        for c in (1..=10).map(Client) {
+            let shard = sharding[index_by_client(c, 3)].lock().unwrap();
+            assert_eq!(
+                shard.get_account(c)?.map(|a| a.total),
+                ledger.get_account(c)?.map(|a| a.total)
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+use crate::{
+    common::{Ledger, TxState},
+    libcsv::{checked_amount, ExecError},
+};
+use rust_decimal::Decimal;
+use std::fmt;
+
+/// Summary of the ledger state
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LedgerStats {
+    pub accounts: usize,
+    pub locked: usize,
+    pub committed: usize,
+    pub disputed: usize,
+    pub finalized: usize,
+    pub cancelled: usize,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+}
+
+impl LedgerStats {
+    pub fn of(ledger: &dyn Ledger) -> Result<LedgerStats, ExecError> {
+        let mut stats = LedgerStats::default();
+        for pair in ledger.accounts() {
+            let (_, acc) = pair?;
+            stats.accounts += 1;
+            stats.locked += acc.locked as usize;
+            stats.available = checked_amount(stats.available.checked_add(acc.available))?;
+            stats.held = checked_amount(stats.held.checked_add(acc.held))?;
+            stats.total = checked_amount(stats.total.checked_add(acc.total))?;
+        }
+        for pair in ledger.transactions() {
+            let (_, tx) = pair?;
+            *match tx.state {
+                TxState::Committed => &mut stats.committed,
+                TxState::Disputed => &mut stats.disputed,
+                TxState::Finalized => &mut stats.finalized,
+                TxState::Cancelled => &mut stats.cancelled,
+            } += 1;
+        }
+        Ok(stats)
+    }
+    pub fn transactions(&self) -> usize {
+        self.committed + self.disputed + self.finalized + self.cancelled
+    }
+}
+
+impl fmt::Display for LedgerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "accounts      {}", self.accounts)?;
+        writeln!(f, "  locked      {}", self.locked)?;
+        writeln!(f, "transactions  {}", self.transactions())?;
+        writeln!(f, "  committed   {}", self.committed)?;
+        writeln!(f, "  disputed    {}", self.disputed)?;
+        writeln!(f, "  finalized   {}", self.finalized)?;
+        writeln!(f, "  cancelled   {}", self.cancelled)?;
+        writeln!(f, "available     {}", self.available)?;
+        writeln!(f, "held          {}", self.held)?;
+        writeln!(f, "total         {}", self.total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        basic::{HashLedger, TRANSACTIONS},
+        common::{Client, TxId},
+        libcsv::execute_csv,
+    };
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_ledger_stats() -> Result<(), ExecError> {
+        let mut ledger = HashLedger::new();
+        execute_csv(std::io::Cursor::new(TRANSACTIONS), &mut ledger)?;
+        let stats = LedgerStats::of(&ledger)?;
+        assert_eq!(stats.total, stats.available + stats.held);
+        assert_eq!(stats.transactions(), ledger.transactions().count());
+        assert_eq!(stats.accounts, ledger.accounts().count());
+        assert!(stats.to_string().starts_with("accounts"));
+        assert_eq!(LedgerStats::of(&HashLedger::new())?.total, dec!(0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sums_overflow() -> Result<(), ExecError> {
+        let mut ledger = HashLedger::new();
+        ledger.deposit(Client(1), TxId(1), Decimal::MAX)?;
+        ledger.deposit(Client(2), TxId(2), Decimal::MAX)?;
+        let err = LedgerStats::of(&ledger).unwrap_err();
+        assert_eq!(err.to_string(), "amount overflow");
+        Ok(())
+    }
+}
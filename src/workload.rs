@@ -0,0 +1,277 @@
+use crate::{
+    common::{Client, TxId, TxType},
+    libcsv::{ExecError, TxRequest},
+    routing::mix,
+    source::{requests_source, TxSource},
+};
+use rust_decimal::Decimal;
+use std::{collections::HashMap, io::Write};
+
+/// Knobs of the synthetic workload
+#[derive(Clone, Debug)]
+pub struct WorkloadOptions {
+    pub clients: u16,
+    pub rows: usize,
+    /// Share of rows disputing an earlier deposit,
+    ///   the same share of rows closes open disputes
+    pub dispute_rate: f64,
+    /// Share of closed disputes ending with chargeback, others are resolved
+    pub chargeback_rate: f64,
+    /// Share of rows repeating an earlier deposit with the same tx id
+    pub duplicate_rate: f64,
+    pub withdrawal_rate: f64,
+    /// Zipf exponent of clients activity, 0 is uniform, 1 and more is a few hot clients
+    pub skew: f64,
+    pub seed: u64,
+}
+
+impl Default for WorkloadOptions {
+    fn default() -> Self {
+        Self {
+            clients: 1000,
+            rows: 100_000,
+            dispute_rate: 0.01,
+            chargeback_rate: 0.2,
+            duplicate_rate: 0.001,
+            withdrawal_rate: 0.2,
+            skew: 0.0,
+            seed: 1,
+        }
+    }
+}
+
+/// Deposits of a client kept as candidates for disputes and duplicates
+const RECENT_DEPOSITS: usize = 8;
+
+/// Generator of the realistic stream of transaction requests
+///
+/// The same options always produce the same stream.
+pub struct Workload {
+    opts: WorkloadOptions,
+    rng: Rng,
+    /// Cumulative weights of clients
+    weights: Vec<f64>,
+    deposits: HashMap<Client, Vec<(TxId, Decimal)>>,
+    disputes: Vec<(Client, TxId)>,
+    next_tx: u32,
+    generated: usize,
+}
+
+impl Workload {
+    pub fn new(opts: WorkloadOptions) -> Self {
+        let weights = (1..=opts.clients.max(1))
+            .scan(0.0, |sum, k| {
+                *sum += 1.0 / (k as f64).powf(opts.skew);
+                Some(*sum)
+            })
+            .collect();
+        Self {
+            rng: Rng(opts.seed),
+            opts,
+            weights,
+            deposits: Default::default(),
+            disputes: Default::default(),
+            next_tx: 1,
+            generated: 0,
+        }
+    }
+
+    pub fn source(self) -> impl TxSource {
+        requests_source(self)
+    }
+
+    /// Writes the whole workload as csv `execute_csv` reads
+    pub fn write_csv(self, wr: impl Write) -> Result<(), ExecError> {
+        let mut wr = std::io::BufWriter::new(wr);
+        writeln!(wr, "type,client,tx,amount")?;
+        for r in self {
+            let tx_type = match r.tx_type {
+                TxType::Deposit => "deposit",
+                TxType::Withdrawal => "withdrawal",
+                TxType::Dispute => "dispute",
+                TxType::Resolve => "resolve",
+                TxType::Chargeback => "chargeback",
+            };
+            let amount = r.amount.map(|a| a.to_string()).unwrap_or_default();
+            writeln!(wr, "{tx_type},{},{},{amount}", r.client.0, r.tx_id.0)?;
+        }
+        wr.flush()?;
+        Ok(())
+    }
+
+    pub fn to_csv(self) -> Vec<u8> {
+        let mut csv = Vec::new();
+        self.write_csv(&mut csv).unwrap();
+        csv
+    }
+
+    fn client(&mut self) -> Client {
+        let total = self.weights.last().copied().unwrap_or(1.0);
+        let x = self.rng.next_f64() * total;
+        Client(
+            self.weights
+                .partition_point(|w| *w < x)
+                .min(self.weights.len() - 1) as u16
+                + 1,
+        )
+    }
+
+    fn request(tx_type: TxType, client: Client, tx_id: TxId, amount: Option<Decimal>) -> TxRequest {
+        TxRequest {
+            tx_type,
+            client,
+            tx_id,
+            amount,
+            key: None,
+            time: None,
+        }
+    }
+
+    fn recent(&mut self, client: Client) -> Option<usize> {
+        let n = self.deposits.get(&client).map_or(0, |d| d.len());
+        (n > 0).then(|| self.rng.below(n as u64) as usize)
+    }
+}
+
+impl Iterator for Workload {
+    type Item = TxRequest;
+    fn next(&mut self) -> Option<TxRequest> {
+        if self.generated == self.opts.rows {
+            return None;
+        }
+        self.generated += 1;
+        let client = self.client();
+        let x = self.rng.next_f64();
+        let (duplicate, dispute) = (self.opts.duplicate_rate, self.opts.dispute_rate);
+        if x < duplicate {
+            if let Some(i) = self.recent(client) {
+                let (tx_id, amount) = self.deposits[&client][i];
+                return Some(Self::request(TxType::Deposit, client, tx_id, Some(amount)));
+            }
+        } else if x < duplicate + dispute {
+            if let Some(i) = self.recent(client) {
+                let (tx_id, _) = self.deposits.get_mut(&client).unwrap().swap_remove(i);
+                self.disputes.push((client, tx_id));
+                return Some(Self::request(TxType::Dispute, client, tx_id, None));
+            }
+        } else if x < duplicate + 2.0 * dispute && !self.disputes.is_empty() {
+            let i = self.rng.below(self.disputes.len() as u64) as usize;
+            let (client, tx_id) = self.disputes.swap_remove(i);
+            let tx_type = match self.rng.next_f64() < self.opts.chargeback_rate {
+                true => TxType::Chargeback,
+                false => TxType::Resolve,
+            };
+            return Some(Self::request(tx_type, client, tx_id, None));
+        }
+        let tx_id = TxId(self.next_tx);
+        self.next_tx += 1;
+        let amount = Decimal::new(self.rng.below(10_000_000) as i64 + 1, 4);
+        match self.rng.next_f64() < self.opts.withdrawal_rate {
+            true => Some(Self::request(
+                TxType::Withdrawal,
+                client,
+                tx_id,
+                Some(amount),
+            )),
+            false => {
+                let recent = self.deposits.entry(client).or_default();
+                if recent.len() == RECENT_DEPOSITS {
+                    recent.remove(0);
+                }
+                recent.push((tx_id, amount));
+                Some(Self::request(TxType::Deposit, client, tx_id, Some(amount)))
+            }
+        }
+    }
+}
+
+/// SplitMix64 generator, the workload must not depend on the platform
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        mix(self.0)
+    }
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        advanced::{index_by_client, sharded_execute_csv, sharded_validate_accounts},
+        basic::HashLedger,
+        common::Ledger,
+        libcsv::{dump_accounts, execute_csv},
+    };
+    use std::sync::{Arc, Mutex};
+
+    fn count(opts: &WorkloadOptions, tx_type: TxType) -> usize {
+        Workload::new(opts.clone())
+            .filter(|r| r.tx_type == tx_type)
+            .count()
+    }
+
+    #[test]
+    fn test_workload_rates() {
+        let opts = WorkloadOptions {
+            rows: 20_000,
+            dispute_rate: 0.05,
+            chargeback_rate: 0.5,
+            ..Default::default()
+        };
+        assert_eq!(Workload::new(opts.clone()).count(), 20_000);
+        let disputes = count(&opts, TxType::Dispute);
+        assert!((800..1200).contains(&disputes), "{disputes}");
+        let closed = count(&opts, TxType::Chargeback) + count(&opts, TxType::Resolve);
+        assert!(closed <= disputes && closed > disputes * 3 / 4);
+        let withdrawals = count(&opts, TxType::Withdrawal);
+        assert!((3400..4200).contains(&withdrawals), "{withdrawals}");
+    }
+
+    #[test]
+    fn test_workload_skew() {
+        let top = |skew| {
+            Workload::new(WorkloadOptions {
+                rows: 10_000,
+                skew,
+                ..Default::default()
+            })
+            .filter(|r| r.client.0 <= 10)
+            .count()
+        };
+        assert!(top(0.0) < 300);
+        assert!(top(1.2) > 5000);
+    }
+
+    #[test]
+    fn test_workload_execution() -> Result<(), ExecError> {
+        let opts = WorkloadOptions {
+            clients: 50,
+            rows: 5000,
+            dispute_rate: 0.05,
+            duplicate_rate: 0.01,
+            skew: 1.0,
+            ..Default::default()
+        };
+        let csv = Workload::new(opts.clone()).to_csv();
+        assert_eq!(csv, Workload::new(opts).to_csv());
+        let mut ledger = HashLedger::new();
+        execute_csv(std::io::Cursor::new(&csv), &mut ledger)?;
+        assert!(ledger.accounts().any(|a| a.unwrap().1.locked));
+        let mut accounts = Vec::new();
+        dump_accounts(&mut accounts, &ledger)?;
+
+        let sharding: Vec<_> = (0..4)
+            .map(|_| Arc::new(Mutex::new(HashLedger::new())) as Arc<Mutex<dyn Ledger + Send>>)
+            .collect();
+        sharded_execute_csv(std::io::Cursor::new(&csv), &sharding, index_by_client)?;
+        sharded_validate_accounts(std::io::Cursor::new(accounts), &sharding, index_by_client)
+    }
+}
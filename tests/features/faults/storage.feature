@@ -0,0 +1,71 @@
+Feature: Storage Failures
+  Rule: failed storage calls fail the transaction with the IO error
+
+    Scenario: a failed write changes nothing and can be retried
+      Given new ledger
+        And put_account fails after 1 call 1 time
+      When tx 1 deposit 1.0 to 1
+        And tx 2 deposit 2.0 to 1 failed
+        And tx 2 deposit 2.0 to 1
+      Then account 1 has total 3.0 available 3.0 held 0
+
+    Scenario: a failed read fails the transaction
+      Given new ledger
+        And get_transaction fails after 2 calls
+      When tx 1 deposit 2.0 to 1
+        And dispute 1 for 1
+        And resolve 1 for 1 failed
+      Then account 1 has total 2.0 available 0 held 2.0
+
+    Scenario: a partial write stores the transaction without the account
+      Given new ledger
+        And put_account writes partially after 1 call 1 time
+      When tx 1 deposit 1.0 to 1
+        And tx 2 deposit 2.0 to 1 failed
+        And tx 2 deposit 2.0 to 1 ignored
+      Then account 1 has total 1.0 available 1.0 held 0
+
+    Scenario: slow storage changes nothing but the time
+      Given new ledger
+        And storage is delayed by 2 ms
+      When tx 1 deposit 1.0 to 1
+        And tx 2 withdrawal 0.5 from 1
+      Then account 1 has total 0.5 available 0.5 held 0
+
+  Rule: executors stop on the first storage failure
+
+    Scenario: execute csv
+      Given new ledger
+        And put_account fails after 2 calls
+      When execute csv fails
+      """
+      type,       client, tx, amount
+      deposit,    1,      1,  1.0
+      deposit,    2,      2,  2.0
+      deposit,    1,      3,  1.0
+      deposit,    3,      4,  1.0
+      """
+      Then validate accounts
+      """
+      client,     available,  held, total,  locked
+      1,          1.0,        0,    1.0,    false
+      2,          2.0,        0,    2.0,    false
+      """
+
+    Scenario: sharded execute csv
+      Given new ledger
+        And put_transaction fails after 2 calls
+      When execute csv sharded fails
+      """
+      type,       client, tx, amount
+      deposit,    1,      1,  1.0
+      deposit,    2,      2,  2.0
+      deposit,    1,      3,  1.0
+      deposit,    3,      4,  1.0
+      """
+      Then validate accounts
+      """
+      client,     available,  held, total,  locked
+      1,          1.0,        0,    1.0,    false
+      2,          2.0,        0,    2.0,    false
+      """
@@ -6,10 +6,20 @@ use cucumber::{
 };
 use futures::{self, FutureExt as _};
 use rust_decimal::Decimal;
-use std::{default::Default, fmt::Debug, marker::PhantomData};
-use toybank::common::{Ledger, Policy, TxError};
+use std::{
+    default::Default,
+    fmt::Debug,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use toybank::{
+    common::{Ledger, Policy, TxError},
+    faults::{Fault, FaultRule, FaultyLedger},
+    libcsv::ExecError,
+};
 
-pub type Dyna = Box<dyn Ledger>;
+pub type Dyna = Box<dyn Ledger + Send>;
 
 #[allow(clippy::all)]
 pub trait Factory {
@@ -27,6 +37,12 @@ pub trait CustomTest {
     fn dyna(&mut self) -> &mut dyn Ledger {
         panic!("uninitialized")
     }
+    fn take(&mut self) -> Dyna {
+        panic!("uninitialized")
+    }
+    fn put(&mut self, _ledger: Dyna) {
+        panic!("uninitialized")
+    }
 }
 
 #[derive(Default)]
@@ -38,6 +54,8 @@ impl<F: Factory> CustomTest for CustomTestImpl<F> {
     }
 
     fn open_ledger(&mut self, leger: String) {
+        self.0 = None;
+        wait_unlocked(&leger);
         self.0 = Some(F::open(leger, self.1))
     }
 
@@ -47,6 +65,27 @@ impl<F: Factory> CustomTest for CustomTestImpl<F> {
         }
         panic!("ledger is not selected")
     }
+
+    fn take(&mut self) -> Dyna {
+        self.0.take().expect("ledger is not selected")
+    }
+
+    fn put(&mut self, ledger: Dyna) {
+        self.0 = Some(ledger)
+    }
+}
+
+/// Waits for the background threads of a dropped file ledger to release its lock.
+fn wait_unlocked(leger: &str) {
+    let Ok(db) = std::fs::File::open(std::path::Path::new(leger).join("db")) else {
+        return;
+    };
+    for _ in 0..100 {
+        if db.try_lock().is_ok() {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
 }
 
 #[derive(cucumber::World)]
@@ -90,7 +129,13 @@ fn err(status: Result<(), TxError>, j: String) -> Result<(), String> {
                 Err(format!("ignored: {e}"))
             }
         }
-        Err(TxError::IOError(e)) => Err(format!("IoError: {e}")),
+        Err(TxError::IOError(e)) => {
+            if j == "failed" {
+                Ok(())
+            } else {
+                Err(format!("IoError: {e}"))
+            }
+        }
         Err(TxError::StringError(e)) => Err(e),
         Err(TxError::Empty) => Err("empty".into()),
     }
@@ -109,33 +154,37 @@ fn open_leger(w: &mut Test, name: String) {
     w.0.open_ledger(name.trim().into())
 }
 
-#[when(regex = r"tx\s+(\d+)\s+deposit\s+(\d*\.?\d+)\s+to\s+(\d+)(\s+rejected|\s+ignored)?")]
+#[when(
+    regex = r"tx\s+(\d+)\s+deposit\s+(\d*\.?\d+)\s+to\s+(\d+)(\s+rejected|\s+ignored|\s+failed)?"
+)]
 fn deposit(w: &mut Test, tx: u32, a: String, c: u32, j: String) {
     let amount = Decimal::from_str_exact(a.as_str()).unwrap();
     let status = w.0.dyna().deposit(c.into(), tx.into(), amount);
     assert_eq!(err(status, j), Ok(()))
 }
 
-#[when(regex = r"tx\s+(\d+)\s+withdrawal\s+(\d*\.?\d+)\s+from\s+(\d+)(\s+rejected|\s+ignored)?")]
+#[when(
+    regex = r"tx\s+(\d+)\s+withdrawal\s+(\d*\.?\d+)\s+from\s+(\d+)(\s+rejected|\s+ignored|\s+failed)?"
+)]
 fn withdrawal(w: &mut Test, tx: u32, a: String, c: u32, j: String) {
     let amount = Decimal::from_str_exact(a.as_str()).unwrap();
     let status = w.0.dyna().withdrawal(c.into(), tx.into(), amount);
     assert_eq!(err(status, j), Ok(()))
 }
 
-#[when(regex = r"dispute\s+(\d+)\s+for\s+(\d+)(\s+rejected|\s+ignored)?")]
+#[when(regex = r"dispute\s+(\d+)\s+for\s+(\d+)(\s+rejected|\s+ignored|\s+failed)?")]
 fn dispute(w: &mut Test, tx: u32, c: u32, j: String) {
     let status = w.0.dyna().dispute(c.into(), tx.into());
     assert_eq!(err(status, j), Ok(()))
 }
 
-#[when(regex = r"resolve\s+(\d+)\s+for\s+(\d+)(\s+rejected|\s+ignored)?")]
+#[when(regex = r"resolve\s+(\d+)\s+for\s+(\d+)(\s+rejected|\s+ignored|\s+failed)?")]
 fn resolve(w: &mut Test, tx: u32, c: u32, j: String) {
     let status = w.0.dyna().resolve(c.into(), tx.into());
     assert_eq!(err(status, j), Ok(()))
 }
 
-#[when(regex = r"chargeback\s+(\d+)\s+for\s+(\d+)(\s+rejected|\s+ignored)?")]
+#[when(regex = r"chargeback\s+(\d+)\s+for\s+(\d+)(\s+rejected|\s+ignored|\s+failed)?")]
 fn chargeback(w: &mut Test, tx: u32, c: u32, j: String) {
     let status = w.0.dyna().chargeback(c.into(), tx.into());
     assert_eq!(err(status, j), Ok(()))
@@ -162,11 +211,59 @@ fn account_is_locked(w: &mut Test, c: u32) {
     assert!(acc.unwrap().locked);
 }
 
-#[when("execute csv")]
-fn execute_csv(w: &mut Test, step: &Step) {
+#[given(
+    regex = r"^(\w+)\s+(fails|writes partially|is delayed by \d+ ms)(\s+after\s+\d+\s+calls?)?(\s+\d+\s+times?)?$"
+)]
+fn storage_fault(w: &mut Test, op: String, fault: String, after: String, times: String) {
+    let number = |s: &str| {
+        let digits: String = s.chars().filter(char::is_ascii_digit).collect();
+        digits.parse::<u64>().ok()
+    };
+    let op = match op.as_str() {
+        "storage" => None,
+        op => Some(op.parse().unwrap()),
+    };
+    let fault = match fault.as_str() {
+        "fails" => Fault::Error,
+        "writes partially" => Fault::PartialWrite,
+        delay => Fault::Latency(Duration::from_millis(number(delay).unwrap())),
+    };
+    let rule = FaultRule {
+        after: number(&after).unwrap_or(0) as usize,
+        times: number(&times).map(|t| t as usize),
+        ..FaultRule::new(op, fault)
+    };
+    let ledger = w.0.take();
+    w.0.put(Box::new(FaultyLedger::new(ledger, vec![rule])))
+}
+
+#[when(regex = r"^execute csv(\s+sharded)?(\s+fails)?$")]
+fn execute_csv(w: &mut Test, step: &Step, sharded: String, fails: String) {
     let x = step.docstring.clone().unwrap();
-    if let Err(e) = toybank::libcsv::execute_csv(std::io::Cursor::new(x.as_bytes()), w.0.dyna()) {
-        panic!("error occured: {e}")
+    let rd = std::io::Cursor::new(x.as_bytes());
+    let res = match sharded.is_empty() {
+        true => toybank::libcsv::execute_csv(rd, w.0.dyna()),
+        false => {
+            // the single shard keeps the ledger of the scenario
+            let ledger = Arc::new(Mutex::new(w.0.take()));
+            let sharding = vec![ledger.clone() as Arc<Mutex<dyn Ledger + Send>>];
+            let res = toybank::advanced::sharded_execute_csv(
+                rd,
+                &sharding,
+                toybank::advanced::index_by_client,
+            );
+            drop(sharding);
+            let ledger = Arc::try_unwrap(ledger).ok().unwrap();
+            w.0.put(ledger.into_inner().unwrap());
+            res
+        }
+    };
+    match (res, fails.is_empty()) {
+        (Ok(()), true) => (),
+        (Err(ExecError::IOError(_)), false) => (),
+        (Err(ExecError::TxError(TxError::IOError(_))), false) => (),
+        (Ok(()), false) => panic!("succeeded but must fail"),
+        (Err(e), _) => panic!("error occured: {e}"),
     }
 }
 
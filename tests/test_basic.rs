@@ -16,3 +16,8 @@ impl suite::Factory for TheFactory {
 fn test() {
     suite::succeeded_with::<TheFactory>("tests/features/basic");
 }
+
+#[test]
+fn test_faults() {
+    suite::succeeded_with::<TheFactory>("tests/features/faults");
+}
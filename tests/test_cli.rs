@@ -0,0 +1,89 @@
+use std::process::Command;
+
+fn execute(args: &[&str]) -> (bool, String) {
+    let out = Command::new(env!("CARGO_BIN_EXE_execute"))
+        .args(args)
+        .output()
+        .unwrap();
+    (out.status.success(), String::from_utf8(out.stdout).unwrap())
+}
+
+/// Lines of the dump, accounts are dumped in the hashtable order
+fn sorted(out: &str) -> Vec<&str> {
+    let mut lines: Vec<_> = out.lines().collect();
+    lines[1..].sort();
+    lines
+}
+
+#[test]
+fn test_bare_input_file() {
+    let (ok, out) = execute(&["tests/test_tx_1.csv"]);
+    assert!(ok);
+    assert_eq!(
+        sorted(&out),
+        [
+            "client,available,held,total,locked",
+            "1,1.5,0,1.5,true",
+            "2,2,0,2,false",
+            "3,3,0,3,false"
+        ]
+    );
+    let (ok, run) = execute(&["run", "tests/test_tx_1.csv"]);
+    assert!(ok);
+    assert_eq!(sorted(&run), sorted(&out));
+    let (ok, sharded) = execute(&["-p", "2", "tests/test_tx_1.csv"]);
+    assert!(ok);
+    assert_eq!(sorted(&sharded), sorted(&out));
+}
+
+#[test]
+fn test_input_file_or_command_required() {
+    assert!(!execute(&[]).0);
+    assert!(!execute(&["tests/test_tx_1.csv", "stats"]).0);
+}
+
+#[test]
+fn test_options_before_command() {
+    // the command is not taken for the input file
+    let (ok, out) = execute(&["-p", "2", "run", "tests/test_tx_1.csv"]);
+    assert!(ok);
+    assert_eq!(sorted(&out)[1], "1,1.5,0,1.5,true");
+    let (ok, out) = execute(&["--ledger", "inmem", "stats"]);
+    assert!(ok, "{out}");
+}
+
+#[test]
+fn test_drop_with_writing_commands_only() {
+    let (ok, out) = execute(&["--ledger", "inmem", "--drop", "run", "tests/test_tx_1.csv"]);
+    assert!(ok, "{out}");
+    for command in [
+        "stats",
+        "dump-accounts",
+        "dump-transactions",
+        "repair",
+        "finalize",
+    ] {
+        assert!(
+            !execute(&["--ledger", "inmem", "--drop", command]).0,
+            "{command}"
+        );
+    }
+}
+
+#[test]
+fn test_audited_import() {
+    let dir = std::env::temp_dir().join(format!("toybank-cli-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+    let (snapshot, log, ledger) = (path("snapshot"), path("audit.jsonl"), path("ledger"));
+    let (ok, _) = execute(&["run", "tests/test_tx_1.csv", "--export", &snapshot]);
+    assert!(ok);
+    let import = ["--ledger", &ledger, "--audit", &log, "import", &snapshot];
+    assert!(execute(&import).0);
+    let (ok, out) = execute(&["--ledger", &ledger, "verify-audit", &log]);
+    assert!(ok);
+    // 3 accounts and 5 transactions are imported
+    assert!(out.starts_with("8 entries"), "{out}");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
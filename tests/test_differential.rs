@@ -0,0 +1,267 @@
+//! Randomized differential tests of ledger backends and executors
+//!
+//! The same generated stream of requests is applied to a reference model,
+//! to every `Ledger` implementation and to the sharded executor,
+//! all of them must agree on every row outcome and on the final state.
+//! proptest shrinks a failing stream to the minimal one and prints it as csv.
+use proptest::prelude::*;
+use rust_decimal::Decimal;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use toybank::{
+    advanced::SledLedger,
+    basic::HashLedger,
+    common::{Account, Client, Ledger, Transaction, TxId, TxState, TxType},
+    differential::{colliding_clients, diff, diff_except, run_serial, run_sharded, to_csv, Run},
+    libcsv::{Outcome, TxRequest},
+    routing::Router,
+};
+
+/// Transaction ids are mostly `client * SLOTS + slot`, so most ids of a client do not
+///   collide with ids of other clients, the rest are any ids of the same range
+const SLOTS: u32 = 6;
+
+fn request() -> impl Strategy<Value = TxRequest> {
+    let tx_type = prop_oneof![
+        4 => Just(TxType::Deposit),
+        2 => Just(TxType::Withdrawal),
+        2 => Just(TxType::Dispute),
+        1 => Just(TxType::Resolve),
+        1 => Just(TxType::Chargeback),
+    ];
+    // disputes sometimes refer to transactions of other clients
+    let owner = prop_oneof![8 => Just(None), 1 => (1..=4u16).prop_map(Some)];
+    let any_id = prop_oneof![30 => Just(None), 1 => (SLOTS..5 * SLOTS).prop_map(Some)];
+    (tx_type, 1..=4u16, owner, 0..SLOTS, any_id, 1..=50i64).prop_map(
+        |(tx_type, client, owner, slot, any_id, amount)| {
+            let owner = match tx_type {
+                TxType::Deposit | TxType::Withdrawal => client,
+                _ => owner.unwrap_or(client),
+            };
+            TxRequest {
+                tx_type,
+                client: Client(client),
+                tx_id: TxId(any_id.unwrap_or(owner as u32 * SLOTS + slot)),
+                amount: match tx_type {
+                    TxType::Deposit | TxType::Withdrawal => Some(Decimal::new(amount, 1)),
+                    _ => None,
+                },
+                key: None,
+                time: None,
+            }
+        },
+    )
+}
+
+/// Straightforward reference model of the ledger rules
+#[derive(Default)]
+struct Model {
+    accounts: HashMap<Client, Account>,
+    transactions: HashMap<TxId, Transaction>,
+}
+
+enum Kind {
+    Applied,
+    Rejected,
+    Ignored,
+}
+
+impl Model {
+    fn apply(&mut self, r: &TxRequest) -> Kind {
+        let tx = self.transactions.get(&r.tx_id).copied();
+        let acc = self.accounts.get(&r.client).copied();
+        match r.tx_type {
+            TxType::Deposit | TxType::Withdrawal => {
+                let amount = r.amount.unwrap();
+                let deposit = r.tx_type == TxType::Deposit;
+                match acc {
+                    None if !deposit => return Kind::Rejected,
+                    Some(acc) if deposit && tx.is_none() && acc.locked => return Kind::Rejected,
+                    Some(acc) if !deposit && acc.locked => return Kind::Rejected,
+                    _ if tx.is_some() => return Kind::Ignored,
+                    Some(acc) if !deposit && acc.available < amount => return Kind::Rejected,
+                    _ => (),
+                }
+                let (amount, state) = match deposit {
+                    true => (amount, TxState::Committed),
+                    false => (-amount, TxState::Finalized),
+                };
+                let acc = self.accounts.entry(r.client).or_default();
+                acc.available += amount;
+                acc.total += amount;
+                self.transactions.insert(
+                    r.tx_id,
+                    Transaction {
+                        client: r.client,
+                        amount: r.amount.unwrap(),
+                        state,
+                        time: r.time,
+                    },
+                );
+                Kind::Applied
+            }
+            _ => {
+                let required = match r.tx_type {
+                    TxType::Dispute => TxState::Committed,
+                    _ => TxState::Disputed,
+                };
+                let (tx, acc) = match (tx, acc) {
+                    (Some(tx), Some(acc)) if tx.client == r.client => (tx, acc),
+                    _ => return Kind::Rejected,
+                };
+                if tx.state != required {
+                    return match (required, tx.state) {
+                        (TxState::Committed, TxState::Disputed) => Kind::Ignored,
+                        _ => Kind::Rejected,
+                    };
+                }
+                if acc.locked || (required == TxState::Committed && tx.amount > acc.available) {
+                    return Kind::Rejected;
+                }
+                let acc = self.accounts.get_mut(&r.client).unwrap();
+                let state = match r.tx_type {
+                    TxType::Dispute => {
+                        acc.available -= tx.amount;
+                        acc.held += tx.amount;
+                        TxState::Disputed
+                    }
+                    TxType::Resolve => {
+                        acc.available += tx.amount;
+                        acc.held -= tx.amount;
+                        TxState::Finalized
+                    }
+                    _ => {
+                        acc.total -= tx.amount;
+                        acc.held -= tx.amount;
+                        acc.locked = true;
+                        TxState::Cancelled
+                    }
+                };
+                self.transactions.get_mut(&r.tx_id).unwrap().state = state;
+                Kind::Applied
+            }
+        }
+    }
+
+    fn check(requests: &[TxRequest], run: &Run) -> Result<(), String> {
+        let mut model = Model::default();
+        for (i, (r, outcome)) in requests.iter().zip(&run.outcomes).enumerate() {
+            match (model.apply(r), outcome) {
+                (Kind::Applied, Outcome::Applied)
+                | (Kind::Rejected, Outcome::Rejected(_))
+                | (Kind::Ignored, Outcome::Ignored(_)) => (),
+                _ => return Err(format!("row {i} {r:?}: model disagrees with {outcome:?}")),
+            }
+        }
+        let mut accounts: Vec<_> = model.accounts.into_iter().collect();
+        accounts.sort_by_key(|(c, _)| c.0);
+        match accounts == run.accounts {
+            true => Ok(()),
+            false => Err(format!("model accounts {accounts:?}")),
+        }
+    }
+}
+
+fn hash_shards(n: usize) -> Vec<Arc<Mutex<dyn Ledger + Send>>> {
+    (0..n)
+        .map(|_| Arc::new(Mutex::new(HashLedger::new())) as Arc<Mutex<dyn Ledger + Send>>)
+        .collect()
+}
+
+fn check_all(requests: &[TxRequest]) -> Result<(), TestCaseError> {
+    let fail = |what: &str, e: String| {
+        TestCaseError::fail(format!("{what}: {e}\nreproduction:\n{}", to_csv(requests)))
+    };
+    let reference =
+        run_serial(&mut HashLedger::new(), requests).map_err(|e| fail("hash", e.to_string()))?;
+    Model::check(requests, &reference).map_err(|e| fail("model", e))?;
+
+    let mut sled = SledLedger::new().unwrap();
+    let run = run_serial(&mut sled, requests).map_err(|e| fail("sled", e.to_string()))?;
+    if let Some(e) = diff(requests, &reference, &run) {
+        return Err(fail("sled", e));
+    }
+
+    // the only exception: transaction ids are unique within a shard,
+    //   so the clients sharing an id with another client may differ
+    let colliding = colliding_clients(requests);
+    for router in [Router::Hash, Router::Jump] {
+        let run = run_sharded(&hash_shards(3), &router, requests)
+            .map_err(|e| fail("sharded hash", e.to_string()))?;
+        if let Some(e) = diff_except(requests, &reference, &run, &colliding) {
+            return Err(fail(&format!("sharded hash {router:?}"), e));
+        }
+    }
+    let sharding = SledLedger::new().unwrap().sharding(2);
+    let run = run_sharded(&sharding, &Router::Modulo, requests)
+        .map_err(|e| fail("sharded sled", e.to_string()))?;
+    if let Some(e) = diff_except(requests, &reference, &run, &colliding) {
+        return Err(fail("sharded sled", e));
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn test_backends_agree(requests in prop::collection::vec(request(), 0..60)) {
+        check_all(&requests)?;
+    }
+}
+
+/// The exception of the sharded execution is real: a transaction id reused
+///   by the client of another shard is applied there, while the serial execution ignores it
+#[test]
+fn test_sharded_colliding_ids() -> Result<(), TestCaseError> {
+    let deposit = |client, tx_id| TxRequest {
+        tx_type: TxType::Deposit,
+        client: Client(client),
+        tx_id: TxId(tx_id),
+        amount: Some(Decimal::ONE),
+        key: None,
+        time: None,
+    };
+    let requests = [deposit(1, 1), deposit(2, 1), deposit(3, 2)];
+    let reference = run_serial(&mut HashLedger::new(), &requests).unwrap();
+    assert!(matches!(reference.outcomes[1], Outcome::Ignored(_)));
+    let router = Router::Modulo;
+    let run = run_sharded(&hash_shards(2), &router, &requests).unwrap();
+    assert_eq!(run.outcomes[1], Outcome::Applied);
+    let colliding = colliding_clients(&requests);
+    assert_eq!(colliding.len(), 2);
+    assert!(diff(&requests, &reference, &run).is_some());
+    assert_eq!(diff_except(&requests, &reference, &run, &colliding), None);
+    check_all(&requests)
+}
+
+#[test]
+fn test_shrinking_reports_minimal_case() {
+    use proptest::test_runner::{Config, RngAlgorithm, TestError, TestRng, TestRunner};
+    // a deliberately wrong expectation: no account is ever locked
+    let config = Config {
+        cases: 256,
+        failure_persistence: None,
+        ..Config::default()
+    };
+    let rng = TestRng::deterministic_rng(RngAlgorithm::ChaCha);
+    let mut runner = TestRunner::new_with_rng(config, rng);
+    let result = runner.run(&prop::collection::vec(request(), 0..60), |requests| {
+        let run = run_serial(&mut HashLedger::new(), &requests).unwrap();
+        prop_assert!(run.accounts.iter().all(|(_, a)| !a.locked));
+        Ok(())
+    });
+    match result {
+        Err(TestError::Fail(_, minimal)) => {
+            // deposit, dispute and chargeback of the same transaction, maybe with a leftover
+            let types: Vec<_> = minimal.iter().map(|r| r.tx_type).collect();
+            assert!(minimal.len() <= 4, "{}", to_csv(&minimal));
+            for tx_type in [TxType::Deposit, TxType::Dispute, TxType::Chargeback] {
+                assert!(types.contains(&tx_type), "{}", to_csv(&minimal));
+            }
+        }
+        other => panic!("expected the failure, got {other:?}"),
+    }
+}
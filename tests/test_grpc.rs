@@ -0,0 +1,184 @@
+//! The gRPC API served on loopback by both ledgers
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tonic::{transport::Channel, Code};
+use toybank::{
+    advanced::SledLedger,
+    basic::HashLedger,
+    common::Ledger,
+    grpc::{
+        proto::{
+            bank_client::BankClient, AccountQuery, ListAccountsRequest, Outcome, Transaction,
+            TxRequest, TxState, TxType,
+        },
+        serve,
+    },
+    routing::Router,
+    service::LedgerService,
+};
+
+async fn start(
+    sharding: Vec<Arc<Mutex<dyn Ledger + Send>>>,
+    router: Router,
+) -> BankClient<Channel> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve(
+        listener,
+        LedgerService::new(sharding, router).unwrap(),
+    ));
+    BankClient::connect(format!("http://{addr}")).await.unwrap()
+}
+
+fn request(tx_type: TxType, client: u32, tx: u32, amount: Option<&str>) -> TxRequest {
+    TxRequest {
+        r#type: tx_type.into(),
+        client,
+        tx,
+        amount: amount.map(Into::into),
+        key: None,
+        time: None,
+    }
+}
+
+async fn check_api(mut bank: BankClient<Channel>) {
+    let reply = bank
+        .submit(request(TxType::Deposit, 1, 1, Some("2.5")))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(
+        (reply.outcome(), reply.reason.as_str()),
+        (Outcome::Applied, "")
+    );
+    let reply = bank
+        .submit(request(TxType::Withdrawal, 1, 2, Some("10")))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(reply.outcome(), Outcome::Rejected);
+    assert!(!reply.reason.is_empty());
+    let status = bank
+        .submit(request(TxType::Withdrawal, 1, 3, None))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    let status = bank
+        .submit(request(TxType::Unspecified, 1, 3, None))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+    // amounts are not positive or have more digits than a decimal holds
+    for amount in ["-100", "0", "1.00000000000000000000000000001"] {
+        for tx_type in [TxType::Deposit, TxType::Withdrawal] {
+            let status = bank
+                .submit(request(tx_type, 1, 3, Some(amount)))
+                .await
+                .unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument, "{amount}");
+        }
+    }
+
+    // bulk of several clients, the duplicate and the failed withdrawal are reported
+    let mut bulk = vec![
+        request(TxType::Deposit, 1, 2, Some("1.0")),
+        request(TxType::Deposit, 1, 2, Some("1.0")),
+        request(TxType::Dispute, 1, 1, None),
+        request(TxType::Withdrawal, 2, 4, Some("1")),
+    ];
+    bulk.extend((10..30).flat_map(|client| {
+        (0..50).map(move |i| request(TxType::Deposit, client, client * 100 + i, Some("1")))
+    }));
+    let reply = bank
+        .bulk_submit(futures::stream::iter(bulk))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!((reply.applied, reply.ignored, reply.rejected), (1002, 1, 1));
+    let failures: Vec<_> = reply
+        .failures
+        .iter()
+        .map(|f| (f.index, f.outcome()))
+        .collect();
+    assert_eq!(failures, [(1, Outcome::Ignored), (3, Outcome::Rejected)]);
+
+    let account = bank
+        .get_account(AccountQuery { client: 1 })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(
+        (
+            account.available.as_str(),
+            account.held.as_str(),
+            account.total.as_str()
+        ),
+        ("1.0", "2.5", "3.5")
+    );
+    assert!(!account.locked);
+    let status = bank
+        .get_account(AccountQuery { client: 2 })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::NotFound);
+    let status = bank
+        .get_account(AccountQuery { client: 70000 })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), Code::InvalidArgument);
+
+    let mut txs = bank
+        .client_transactions(AccountQuery { client: 1 })
+        .await
+        .unwrap()
+        .into_inner();
+    let mut all = Vec::new();
+    while let Some(tx) = txs.message().await.unwrap() {
+        all.push(tx);
+    }
+    assert_eq!(
+        all,
+        [
+            Transaction {
+                tx: 1,
+                client: 1,
+                amount: "2.5".into(),
+                state: TxState::Disputed.into(),
+                time: None
+            },
+            Transaction {
+                tx: 2,
+                client: 1,
+                amount: "1.0".into(),
+                state: TxState::Committed.into(),
+                time: None
+            },
+        ]
+    );
+
+    let mut accounts = bank
+        .list_accounts(ListAccountsRequest {})
+        .await
+        .unwrap()
+        .into_inner();
+    let mut clients = Vec::new();
+    while let Some(account) = accounts.message().await.unwrap() {
+        assert!(account.client == 1 || account.total == "50");
+        clients.push(account.client);
+    }
+    assert_eq!(clients, [1].into_iter().chain(10..30).collect::<Vec<_>>());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_hash_ledger_grpc() {
+    let sharding = (0..3)
+        .map(|_| Arc::new(Mutex::new(HashLedger::new())) as Arc<Mutex<dyn Ledger + Send>>)
+        .collect();
+    check_api(start(sharding, Router::Jump).await).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_sled_ledger_grpc() {
+    let sharding = SledLedger::new().unwrap().sharding(3);
+    check_api(start(sharding, Router::Hash).await).await;
+}
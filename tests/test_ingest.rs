@@ -0,0 +1,152 @@
+//! The csv line protocol served on localhost TCP and Unix sockets
+use std::sync::{Arc, Mutex};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use toybank::{
+    advanced::SledLedger,
+    basic::HashLedger,
+    common::{Client, Ledger},
+    ingest::{ingest, serve_tcp, MAX_LINE_LENGTH},
+    routing::Router,
+    service::LedgerService,
+};
+
+const INPUT: &str = "type, client, tx, amount
+deposit, 1, 1, 2.5
+# comment
+
+deposit, 1, 2, 1.0
+deposit, 1, 2, 1.0
+withdrawal, 1, 3, 10
+dispute, 1, 1,
+withdrawal, 2, 4,
+deposit, x, 5, 1
+";
+
+const ACKS: &str = "2,applied,
+5,applied,
+6,ignored,duplicated transaction
+";
+
+/// Sends the input while reading acknowledgements, returns them
+async fn exchange<S>(stream: S, input: String) -> String
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut rd, mut wr) = tokio::io::split(stream);
+    let sending = tokio::spawn(async move {
+        wr.write_all(input.as_bytes()).await.unwrap();
+        wr.shutdown().await.unwrap();
+    });
+    let mut acks = String::new();
+    rd.read_to_string(&mut acks).await.unwrap();
+    sending.await.unwrap();
+    acks
+}
+
+async fn check_ingest<S>(connect: impl Fn() -> S, service: LedgerService)
+where
+    S: std::future::Future + Send + 'static,
+    S::Output: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let acks = exchange(connect().await, INPUT.into()).await;
+    let acks: Vec<_> = acks.lines().collect();
+    assert_eq!(acks.len(), 7);
+    assert_eq!(acks[..3], ACKS.lines().collect::<Vec<_>>());
+    assert_eq!(acks[3], "7,rejected,insufficient funds");
+    assert_eq!(acks[4], "8,applied,");
+    assert_eq!(acks[5], "9,error,withdrawal has no amount");
+    assert!(acks[6].starts_with("10,error,"));
+    let account = service.account(Client(1)).await.unwrap().unwrap();
+    assert_eq!(account.held.to_string(), "2.5");
+
+    // many requests of concurrent connections, acknowledged in order of input
+    let tasks: Vec<_> = (10..20u32)
+        .map(|client| {
+            let stream = connect();
+            tokio::spawn(async move {
+                let input: String = std::iter::once("type,client,tx,amount\n".to_string())
+                    .chain(
+                        (0..1000).map(|i| format!("deposit,{client},{},1\n", client * 10000 + i)),
+                    )
+                    .collect();
+                exchange(stream.await, input).await
+            })
+        })
+        .collect();
+    for task in tasks {
+        let acks = task.await.unwrap();
+        let expected: String = (2..=1001).map(|n| format!("{n},applied,\n")).collect();
+        assert_eq!(acks, expected);
+    }
+    for client in 10..20 {
+        let account = service.account(Client(client)).await.unwrap().unwrap();
+        assert_eq!(account.total.to_string(), "1000");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_tcp_ingest() {
+    let sharding = (0..3)
+        .map(|_| Arc::new(Mutex::new(HashLedger::new())) as Arc<Mutex<dyn Ledger + Send>>)
+        .collect();
+    let service = LedgerService::new(sharding, Router::Jump).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve_tcp(
+        listener,
+        service.clone(),
+        Default::default(),
+        |_| (),
+    ));
+    check_ingest(
+        || async move { TcpStream::connect(addr).await.unwrap() },
+        service,
+    )
+    .await;
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_unix_ingest() {
+    use tokio::net::{UnixListener, UnixStream};
+    let path = std::env::temp_dir().join(format!("toybank-ingest-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let service = LedgerService::new(SledLedger::new().unwrap().sharding(2), Router::Hash).unwrap();
+    let listener = UnixListener::bind(&path).unwrap();
+    tokio::spawn(toybank::ingest::serve_unix(
+        listener,
+        service.clone(),
+        Default::default(),
+        |_| (),
+    ));
+    let connect = || {
+        let path = path.clone();
+        async move { UnixStream::connect(path).await.unwrap() }
+    };
+    check_ingest(connect, service).await;
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn test_line_too_long() {
+    let sharding = vec![Arc::new(Mutex::new(HashLedger::new())) as Arc<Mutex<dyn Ledger + Send>>];
+    let service = LedgerService::new(sharding, Router::Hash).unwrap();
+    let (mut peer, stream) = tokio::io::duplex(4 * MAX_LINE_LENGTH);
+    let input = format!(
+        "type,client,tx,amount\ndeposit,1,1,1\ndeposit,1,2,{}\ndeposit,1,3,1\n",
+        "1".repeat(MAX_LINE_LENGTH)
+    );
+    peer.write_all(input.as_bytes()).await.unwrap();
+    peer.shutdown().await.unwrap();
+    let failed = ingest(stream, &service, &Default::default()).await;
+    assert!(failed.unwrap_err().to_string().contains("longer than"));
+    let mut acks = String::new();
+    peer.read_to_string(&mut acks).await.unwrap();
+    let acks: Vec<_> = acks.lines().collect();
+    assert_eq!(acks.len(), 2);
+    assert_eq!(acks[0], "2,applied,");
+    assert!(acks[1].starts_with("3,error,line 3 is longer than"));
+}
@@ -0,0 +1,74 @@
+//! Property tests of the `Ledger` state machine invariants
+//!
+//! Random sequences of operations are applied to ledgers,
+//! the state is checked after every operation and a violation
+//! prints the (shrunk) trace of operations with their outcomes.
+use proptest::prelude::*;
+use rust_decimal::Decimal;
+use toybank::{
+    advanced::SledLedger,
+    basic::HashLedger,
+    common::{Client, Ledger, Policy, TxId, TxType},
+    invariants::{check_snapshot, check_step},
+    libcsv::{apply_request, Outcome, TxRequest},
+    snapshot::Snapshot,
+};
+
+fn operation() -> impl Strategy<Value = TxRequest> {
+    let tx_type = prop_oneof![
+        Just(TxType::Deposit),
+        Just(TxType::Withdrawal),
+        Just(TxType::Dispute),
+        Just(TxType::Resolve),
+        Just(TxType::Chargeback),
+    ];
+    (tx_type, 1..=3u16, 1..=8u32, 1..=100i64).prop_map(|(tx_type, client, tx_id, amount)| {
+        TxRequest {
+            tx_type,
+            client: Client(client),
+            tx_id: TxId(tx_id),
+            amount: Some(Decimal::new(amount, 2)),
+            key: None,
+            time: None,
+        }
+    })
+}
+
+/// Applies the operations checking invariants after each of them
+fn check_trace(ledger: &mut dyn Ledger, ops: &[TxRequest]) -> Result<(), TestCaseError> {
+    let mut trace = String::new();
+    let mut before = Snapshot::from_ledger(ledger).unwrap();
+    for r in ops {
+        let outcome = Outcome::of(apply_request(ledger, r)).unwrap();
+        trace += &format!(
+            "{:?} client {} {:?} {:?}: {outcome}\n",
+            r.tx_type, r.client.0, r.tx_id, r.amount
+        );
+        let after = Snapshot::from_ledger(ledger).unwrap();
+        let checked = check_step(&before, &after, r).and_then(|_| check_snapshot(&after));
+        prop_assert!(checked.is_ok(), "{}\ntrace:\n{trace}", checked.unwrap_err());
+        before = after;
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(128))]
+
+    #[test]
+    fn test_hash_ledger_invariants(
+        ops in prop::collection::vec(operation(), 0..80),
+        negative in any::<bool>(),
+    ) {
+        let policy = Policy {
+            allow_negative_balance_for_dispute: negative,
+            ..Default::default()
+        };
+        check_trace(&mut HashLedger::with_policy(policy), &ops)?;
+    }
+
+    #[test]
+    fn test_sled_ledger_invariants(ops in prop::collection::vec(operation(), 0..40)) {
+        check_trace(&mut SledLedger::new().unwrap(), &ops)?;
+    }
+}
@@ -0,0 +1,167 @@
+//! The HTTP API served on localhost by both ledgers
+use serde_json::{json, Value};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use toybank::{
+    advanced::SledLedger, basic::HashLedger, common::Ledger, routing::Router, server::serve,
+    service::LedgerService,
+};
+
+async fn start(sharding: Vec<Arc<Mutex<dyn Ledger + Send>>>, router: Router) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve(
+        listener,
+        LedgerService::new(sharding, router).unwrap(),
+    ));
+    addr
+}
+
+async fn request(addr: SocketAddr, method: &str, path: &str, body: Option<Value>) -> (u16, Value) {
+    request_with(addr, method, path, "", body).await
+}
+
+/// Minimal HTTP/1.1 client, one request per connection, `headers` are `\r\n` terminated
+async fn request_with(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    headers: &str,
+    body: Option<Value>,
+) -> (u16, Value) {
+    let body = body.map(|b| b.to_string()).unwrap_or_default();
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let head = format!(
+        "{method} {path} HTTP/1.1\r\nHost: {addr}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n{headers}\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await.unwrap();
+    stream.write_all(body.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let status = response[9..12].parse().unwrap();
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    (status, serde_json::from_str(body).unwrap_or(Value::Null))
+}
+
+async fn submit(
+    addr: SocketAddr,
+    tx_type: &str,
+    client: u16,
+    tx: u32,
+    amount: &str,
+) -> (u16, Value) {
+    let mut body = json!({"type": tx_type, "client": client, "tx": tx});
+    if !amount.is_empty() {
+        body["amount"] = json!(amount);
+    }
+    request(addr, "POST", "/transactions", Some(body)).await
+}
+
+async fn check_api(addr: SocketAddr) {
+    assert_eq!(
+        submit(addr, "deposit", 1, 1, "2.5").await,
+        (200, json!({"outcome": "applied"}))
+    );
+    assert_eq!(submit(addr, "deposit", 1, 2, "1.0").await.0, 200);
+    let (status, reply) = submit(addr, "deposit", 1, 2, "1.0").await;
+    assert_eq!((status, &reply["outcome"]), (200, &json!("ignored")));
+    let (status, reply) = submit(addr, "withdrawal", 1, 3, "10").await;
+    assert_eq!((status, &reply["outcome"]), (422, &json!("rejected")));
+    assert!(reply["reason"].is_string());
+    assert_eq!(submit(addr, "dispute", 1, 1, "").await.0, 200);
+    assert_eq!(submit(addr, "withdrawal", 2, 4, "").await.0, 400);
+    for amount in ["-100", "0"] {
+        let (status, reply) = submit(addr, "deposit", 2, 4, amount).await;
+        assert_eq!(status, 400);
+        assert!(reply["error"].as_str().unwrap().contains("not positive"));
+        assert_eq!(submit(addr, "withdrawal", 1, 4, amount).await.0, 400);
+    }
+
+    // retries with the same idempotency key get the recorded outcome
+    let keyed = |key: &'static str, tx_type: &'static str| async move {
+        let headers = format!("Idempotency-Key: {key}\r\n");
+        let body = json!({"type": tx_type, "client": 40, "tx": 40});
+        request_with(addr, "POST", "/transactions", &headers, Some(body)).await
+    };
+    for _ in 0..2 {
+        let (status, reply) = keyed("k1", "dispute").await;
+        assert_eq!((status, &reply["outcome"]), (422, &json!("rejected")));
+    }
+    assert_eq!(submit(addr, "deposit", 40, 40, "5").await.0, 200);
+    assert_eq!(keyed("k1", "dispute").await.0, 422);
+    for _ in 0..2 {
+        assert_eq!(
+            keyed("k2", "dispute").await,
+            (200, json!({"outcome": "applied"}))
+        );
+    }
+    let (status, reply) = keyed("k2", "resolve").await;
+    assert_eq!((status, &reply["outcome"]), (422, &json!("rejected")));
+    let body = json!({"type": "resolve", "client": 40, "tx": 40, "key": "k3"});
+    for _ in 0..2 {
+        assert_eq!(
+            request(addr, "POST", "/transactions", Some(body.clone()))
+                .await
+                .0,
+            200
+        );
+    }
+
+    let (status, account) = request(addr, "GET", "/accounts/1", None).await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        account,
+        json!({"client": 1, "available": "1.0", "held": "2.5", "total": "3.5", "locked": false})
+    );
+    assert_eq!(request(addr, "GET", "/accounts/2", None).await.0, 404);
+    let (_, txs) = request(addr, "GET", "/accounts/1/transactions", None).await;
+    assert_eq!(
+        txs,
+        json!([
+            {"tx": 1, "client": 1, "amount": "2.5", "state": "disputed"},
+            {"tx": 2, "client": 1, "amount": "1.0", "state": "committed"},
+        ])
+    );
+
+    // concurrent submissions of many clients
+    let tasks: Vec<_> = (10..30u16)
+        .map(|client| {
+            tokio::spawn(async move {
+                for i in 0..5 {
+                    let tx = client as u32 * 10 + i;
+                    assert_eq!(submit(addr, "deposit", client, tx, "1").await.0, 200);
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    let (_, accounts) = request(addr, "GET", "/accounts", None).await;
+    let accounts = accounts.as_array().unwrap();
+    assert_eq!(accounts.len(), 22);
+    assert_eq!(accounts[0]["client"], json!(1));
+    assert!(accounts[1..].iter().all(|a| a["total"] == json!("5")));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_hash_ledger_server() {
+    let sharding = (0..3)
+        .map(|_| Arc::new(Mutex::new(HashLedger::new())) as Arc<Mutex<dyn Ledger + Send>>)
+        .collect();
+    check_api(start(sharding, Router::Jump).await).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_sled_ledger_server() {
+    let sharding = SledLedger::new().unwrap().sharding(3);
+    check_api(start(sharding, Router::Hash).await).await;
+}
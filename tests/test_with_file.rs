@@ -17,3 +17,8 @@ fn test() {
     suite::succeeded_with::<TheFactory>("tests/features/basic");
     suite::succeeded_with::<TheFactory>("tests/features/advanced");
 }
+
+#[test]
+fn test_faults() {
+    suite::succeeded_with::<TheFactory>("tests/features/faults");
+}